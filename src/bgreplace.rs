@@ -0,0 +1,398 @@
+use std::i32;
+use std::sync::Mutex;
+
+use crate::caps;
+use crate::cata;
+use crate::device;
+use crate::frame;
+use crate::models;
+use crate::overlay;
+use crate::registry;
+use crate::salientobject;
+
+use glib::subclass;
+use glib::{GEnum, StaticType, ToValue};
+use gst;
+use gst_video;
+
+use tch;
+use tch::Tensor;
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, GEnum)]
+#[repr(u32)]
+#[genum(type_name = "SimboticBgOutput")]
+pub enum Output {
+    #[genum(name = "Composite: RGB input over the background", nick = "composite")]
+    Composite = 0,
+    #[genum(name = "Alpha: RGBA input with the matte in alpha", nick = "alpha")]
+    Alpha = 1,
+}
+
+lazy_static! {
+    static ref CAPS_IN: Mutex<gst::Caps> = Mutex::new(gst::Caps::new_simple(
+        "video/x-raw",
+        &[
+            (
+                "format",
+                &gst::List::new(&[&gst_video::VideoFormat::Rgb.to_str()]),
+            ),
+            ("width", &gst::IntRange::<i32>::new(1, i32::MAX)),
+            ("height", &gst::IntRange::<i32>::new(1, i32::MAX)),
+            (
+                "framerate",
+                &gst::FractionRange::new(gst::Fraction::new(0, 1), gst::Fraction::new(i32::MAX, 1),),
+            ),
+        ],
+    ));
+    static ref CAPS_OUT: Mutex<gst::Caps> = Mutex::new(gst::Caps::new_simple(
+        "video/x-raw",
+        &[
+            (
+                "format",
+                &gst::List::new(&[
+                    &gst_video::VideoFormat::Rgb.to_str(),
+                    &gst_video::VideoFormat::Rgba.to_str()
+                ]),
+            ),
+            ("width", &gst::IntRange::<i32>::new(1, i32::MAX)),
+            ("height", &gst::IntRange::<i32>::new(1, i32::MAX)),
+            (
+                "framerate",
+                &gst::FractionRange::new(gst::Fraction::new(0, 1), gst::Fraction::new(i32::MAX, 1),),
+            ),
+        ],
+    ));
+}
+
+// Softens the edges of a Tensor[[1, 1, H, W], Float] matte with a box blur of `radius` pixels
+fn feather(matte: &Tensor, radius: i64) -> Tensor {
+    if radius <= 0 {
+        return matte.shallow_clone();
+    }
+    let size = 2 * radius + 1;
+    matte.avg_pool2d(
+        &[size, size],
+        &[1, 1],
+        &[radius, radius],
+        false,
+        false,
+        None::<i64>,
+    )
+}
+
+// Metadata for the properties
+static PROPERTIES: [subclass::Property; 6] = [
+    subclass::Property("device", |name| device::param_spec(name)),
+    subclass::Property("model-path", |name| models::path_param_spec(name)),
+    subclass::Property("output", |name| {
+        glib::ParamSpec::enum_(
+            name,
+            "Output",
+            "Composite over the background, or the input with the matte in alpha",
+            Output::static_type(),
+            Output::Composite as i32,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_READY,
+        )
+    }),
+    subclass::Property("feather", |name| {
+        glib::ParamSpec::uint(
+            name,
+            "Feather",
+            "Radius in pixels the matte edges are softened by",
+            0,
+            u32::MAX,
+            4,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+    subclass::Property("normalize", |name| {
+        glib::ParamSpec::enum_(
+            name,
+            "Normalize",
+            "How the model output is mapped to 0..1",
+            salientobject::Normalize::static_type(),
+            salientobject::DEFAULT_NORMALIZE as i32,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+    subclass::Property("temporal-alpha", |name| {
+        glib::ParamSpec::double(
+            name,
+            "Temporal alpha",
+            "Weight of the newest frame when smoothing the matte, 1 disables smoothing",
+            0.0,
+            1.0,
+            1.0,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+];
+
+pub struct BgReplace {
+    video_info: gst_video::VideoInfo,
+    background_info: gst_video::VideoInfo,
+    device: tch::Device,
+    model_path: Option<String>,
+    model: Option<models::Model>,
+    output: Output,
+    feather: u32,
+    normalize: salientobject::Normalize,
+    temporal_alpha: f64,
+    smoothed: Option<Tensor>, // Tensor[[1, H, W], Float] at network resolution
+    imagenet: (Tensor, Tensor), // Mean and std on the device
+}
+
+impl registry::Registry for BgReplace {
+    const NAME: &'static str = "bgreplace";
+    const DEBUG_CATEGORY: &'static str = "bgreplace";
+    register_typedata!();
+
+    fn properties() -> &'static [glib::subclass::Property<'static>] {
+        &PROPERTIES
+    }
+}
+
+impl std::default::Default for BgReplace {
+    fn default() -> Self {
+        let mut caps: gst::Caps = CAPS_IN.lock().unwrap().clone();
+        caps.fixate();
+        BgReplace {
+            video_info: gst_video::VideoInfo::from_caps(&caps).unwrap(),
+            background_info: gst_video::VideoInfo::from_caps(&caps).unwrap(),
+            device: device::default(),
+            model_path: None,
+            model: None,
+            output: Output::Composite,
+            feather: 4,
+            normalize: salientobject::DEFAULT_NORMALIZE,
+            temporal_alpha: 1.0,
+            smoothed: None,
+            imagenet: salientobject::imagenet(tch::Device::Cpu),
+        }
+    }
+}
+
+impl caps::CapsDef for BgReplace {
+    fn caps_def() -> (Vec<caps::PadCaps>, Vec<caps::PadCaps>) {
+        let in_caps = caps::PadCaps {
+            name: "rgb",
+            caps: CAPS_IN.lock().unwrap().clone(),
+        };
+        let background_caps = caps::PadCaps {
+            name: "background",
+            caps: CAPS_IN.lock().unwrap().clone(),
+        };
+        let out_caps = caps::PadCaps {
+            name: "composite",
+            caps: CAPS_OUT.lock().unwrap().clone(),
+        };
+        (vec![in_caps, background_caps], vec![out_caps])
+    }
+
+    // The latest background frame is reused for every input frame, and the background pad
+    // can stay unlinked in alpha mode. A non-live background is paced by PTS against the input.
+    fn sticky_sinks() -> &'static [&'static str] {
+        &["background"]
+    }
+}
+
+impl BgReplace {
+    // RGB or RGBA frames at the input resolution
+    fn output_info(&self) -> Option<gst_video::VideoInfo> {
+        let format = match self.output {
+            Output::Composite => gst_video::VideoFormat::Rgb,
+            Output::Alpha => gst_video::VideoFormat::Rgba,
+        };
+        gst_video::VideoInfo::builder(format, self.video_info.width(), self.video_info.height())
+            .fps(self.video_info.fps())
+            .par(self.video_info.par())
+            .build()
+            .ok()
+    }
+
+    // Tensor[[H, W], Float] feathered matte in 0..1 of a Tensor[[H, W, 3], Uint8] frame,
+    // smoothed over frames at network resolution
+    fn matte(&mut self, rgb: &Tensor) -> Result<Tensor, cata::ProcessError> {
+        let size = rgb.size();
+        let img = salientobject::normalize(&rgb.permute(&[2, 0, 1]), &self.imagenet)?
+            .unsqueeze(0)
+            .upsample_bilinear2d(
+                &[salientobject::HEIGHT as i64, salientobject::WIDTH as i64],
+                false,
+                None::<f64>,
+                None::<f64>,
+            );
+        let saliency = salientobject::saliency(self.model.as_ref().unwrap(), img)?;
+        let scaled = salientobject::scale(&saliency, self.normalize);
+        let smoothed = match &self.smoothed {
+            Some(previous) if previous.size() == scaled.size() => {
+                previous + (&scaled - previous) * self.temporal_alpha
+            }
+            _ => scaled,
+        };
+        self.smoothed = Some(smoothed.shallow_clone());
+        let matte = smoothed.unsqueeze(0).upsample_bilinear2d(
+            &[size[0], size[1]],
+            false,
+            None::<f64>,
+            None::<f64>,
+        );
+        Ok(feather(&matte, self.feather as i64)
+            .clamp(0.0, 1.0)
+            .view((size[0], size[1])))
+    }
+
+    // Tensor[[H, W, 3], Uint8] background scaled to the input resolution,
+    // black until the first background frame arrives
+    fn background(&self, inbuf: &gst::Buffer) -> Result<Tensor, cata::ProcessError> {
+        let (height, width) = (
+            self.video_info.height() as i64,
+            self.video_info.width() as i64,
+        );
+        if inbuf.get_size() == 0 {
+            return Ok(Tensor::zeros(
+                &[height, width, 3],
+                (tch::Kind::Uint8, self.device),
+            ));
+        }
+        let bg_frame = frame::map_readable(inbuf.as_ref(), &self.background_info)?;
+        let bg = frame::to_tensor(&bg_frame)
+            .f_to_device(self.device)?
+            .permute(&[2, 0, 1])
+            .unsqueeze(0)
+            .to_kind(tch::Kind::Float);
+        let bg = if bg.size()[2..] == [height, width] {
+            bg
+        } else {
+            bg.upsample_bilinear2d(&[height, width], false, None::<f64>, None::<f64>)
+        };
+        Ok(bg
+            .squeeze_dim(0)
+            .permute(&[1, 2, 0])
+            .round()
+            .clamp(0.0, 255.0)
+            .to_kind(tch::Kind::Uint8))
+    }
+}
+
+impl cata::Process for BgReplace {
+    fn process(
+        &mut self,
+        inbuf: &Vec<gst::Buffer>,
+        outbuf: &mut Vec<gst::Buffer>,
+    ) -> Result<(), cata::ProcessError> {
+        let in_ref = inbuf[0].as_ref();
+        let rgb = {
+            let in_frame = frame::map_readable(in_ref, &self.video_info)?;
+            frame::to_tensor(&in_frame).f_to_device(self.device)?
+        };
+        let matte = self.matte(&rgb)?;
+
+        let composite = match self.output {
+            Output::Composite => {
+                let background = self.background(&inbuf[1])?;
+                overlay::blend(&background, &rgb, &matte, 1.0)
+            }
+            Output::Alpha => {
+                let alpha = (matte * 255.0).round().to_kind(tch::Kind::Uint8);
+                Tensor::f_cat(&[rgb, alpha.unsqueeze(-1)], 2)?
+            }
+        };
+
+        let info = self
+            .output_info()
+            .ok_or_else(|| cata::ProcessError::Shape("No frame for the composite".to_string()))?;
+        outbuf[0] = gst::Buffer::with_size(info.size())
+            .map_err(|_| cata::ProcessError::Mapping("Failed to allocate buffer".to_string()))?;
+        let out_ref = outbuf[0].get_mut().unwrap();
+        out_ref.set_pts(in_ref.get_pts());
+        out_ref.set_dts(in_ref.get_dts());
+        out_ref.set_offset(in_ref.get_offset());
+        out_ref.set_duration(in_ref.get_duration());
+        let mut out_frame = frame::map_writable(out_ref, &info)?;
+        frame::copy_to_frame(&composite, &mut out_frame)?;
+
+        Ok(())
+    }
+
+    fn set_property(&mut self, property: &subclass::Property, value: &glib::Value) {
+        match property {
+            subclass::Property("device", ..) => {
+                self.device = device::from_value(value, self.device);
+            }
+            subclass::Property("model-path", ..) => {
+                self.model_path = value.get().expect("model path");
+            }
+            subclass::Property("output", ..) => {
+                self.output = value.get_some().expect("output");
+            }
+            subclass::Property("feather", ..) => {
+                self.feather = value.get_some().expect("feather");
+            }
+            subclass::Property("normalize", ..) => {
+                self.normalize = value.get_some().expect("normalize");
+            }
+            subclass::Property("temporal-alpha", ..) => {
+                self.temporal_alpha = value.get_some().expect("temporal alpha");
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    fn get_property(&self, property: &subclass::Property) -> glib::Value {
+        match property {
+            subclass::Property("device", ..) => device::to_value(self.device),
+            subclass::Property("model-path", ..) => self.model_path.to_value(),
+            subclass::Property("output", ..) => self.output.to_value(),
+            subclass::Property("feather", ..) => self.feather.to_value(),
+            subclass::Property("normalize", ..) => self.normalize.to_value(),
+            subclass::Property("temporal-alpha", ..) => self.temporal_alpha.to_value(),
+            _ => unimplemented!(),
+        }
+    }
+
+    fn set_caps(&mut self, sink: usize, caps: &gst::Caps) -> bool {
+        match gst_video::VideoInfo::from_caps(caps) {
+            Ok(video_info) if sink == 0 => {
+                self.video_info = video_info;
+                true
+            }
+            Ok(video_info) => {
+                self.background_info = video_info;
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    // The composite matches the input resolution
+    fn src_caps(&self, _src: usize) -> Option<gst::Caps> {
+        self.output_info()?.to_caps().ok()
+    }
+
+    fn prepare(&mut self) -> Result<(), gst::ErrorMessage> {
+        let model_path = models::resolve(&self.model_path, "salientobject/model.pt")?;
+        self.model = Some(models::load(&model_path, self.device)?);
+        self.smoothed = None;
+        self.imagenet = salientobject::imagenet(self.device);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn feathers_matte_edges() {
+        let matte = Tensor::of_slice(&[0f32, 0., 1., 1.]).view((1, 1, 1, 4));
+        let feathered = Vec::<f32>::from(feather(&matte, 1).flatten(0, -1));
+        let expected = [0., 1. / 3., 2. / 3., 1.];
+        assert!(feathered
+            .iter()
+            .zip(expected.iter())
+            .all(|(a, e)| (a - e).abs() < 1e-6));
+        assert!(feather(&matte, 0).equal(&matte));
+    }
+}
@@ -7,4 +7,22 @@ pub struct PadCaps {
 
 pub trait CapsDef {
     fn caps_def() -> (Vec<PadCaps>, Vec<PadCaps>);
+
+    // Sink pads that may stay unlinked. Their latest buffer joins every set of zipped buffers,
+    // an empty buffer until the first one arrives, and they don't hold back EOS.
+    fn sticky_sinks() -> &'static [&'static str] {
+        &[]
+    }
+}
+
+// Raw float32 tensors in row-major order
+pub const TENSOR_CAPS: &str = "application/x-simbotic-tensor";
+
+pub fn tensor_caps(shape: &[i64]) -> gst::Caps {
+    let shape = shape
+        .iter()
+        .map(|dim| dim.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    gst::Caps::new_simple(TENSOR_CAPS, &[("dtype", &"float32"), ("shape", &shape)])
 }
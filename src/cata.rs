@@ -7,20 +7,100 @@ use gst::prelude::*;
 use gst::subclass::prelude::*;
 
 use std::collections::{HashMap, HashSet};
-use std::sync::Mutex;
+use std::fmt;
+use std::panic;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::caps::CapsDef;
 use crate::registry::Registry;
-use crate::zipper::Zipper;
+use crate::worker::{self, Item, Leaky};
+use crate::zipper::{self, ZipPolicy, Zipper};
+
+// Failure while processing a frame
+#[derive(Debug)]
+pub enum ProcessError {
+    // Model inference or tensor operation failed, e.g. CUDA out of memory
+    Model(tch::TchError),
+    // Model output or tensors don't have the expected shape
+    Shape(String),
+    // Buffer couldn't be mapped as a video frame
+    Mapping(String),
+    // Models or other resources aren't loaded, prepare didn't run or failed
+    NotPrepared(String),
+}
+
+impl fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProcessError::Model(err) => write!(f, "Model runtime error: {}", err),
+            ProcessError::Shape(msg) => write!(f, "Unexpected shape: {}", msg),
+            ProcessError::Mapping(msg) => write!(f, "Buffer mapping failed: {}", msg),
+            ProcessError::NotPrepared(msg) => write!(f, "Not prepared: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ProcessError {}
+
+impl From<tch::TchError> for ProcessError {
+    fn from(err: tch::TchError) -> Self {
+        ProcessError::Model(err)
+    }
+}
 
 pub trait Process {
     fn process(
         &mut self,
         inbuf: &Vec<gst::Buffer>,
         outbuf: &mut Vec<gst::Buffer>,
-    ) -> Result<(), std::io::Error>;
+    ) -> Result<(), ProcessError>;
 
     fn set_property(&mut self, property: &subclass::Property, value: &glib::Value);
+
+    // Current value of `property`, as reported by `g_object_get`
+    fn get_property(&self, property: &subclass::Property) -> glib::Value;
+
+    // Load models and other resources, called on the NULL to READY transition
+    fn prepare(&mut self) -> Result<(), gst::ErrorMessage> {
+        Ok(())
+    }
+
+    // Processes several zipped buffer sets at once, filling one output set per input set.
+    // Processors able to run a batched forward pass override it, others go frame by frame.
+    fn process_batch(
+        &mut self,
+        inbufs: &[Vec<gst::Buffer>],
+        outbufs: &mut Vec<Vec<gst::Buffer>>,
+    ) -> Result<(), ProcessError> {
+        for (inbuf, outbuf) in inbufs.iter().zip(outbufs.iter_mut()) {
+            self.process(inbuf, outbuf)?;
+        }
+        Ok(())
+    }
+
+    // Caps received on sink pad `_sink`, returns false if they can't be handled
+    fn set_caps(&mut self, _sink: usize, _caps: &gst::Caps) -> bool {
+        true
+    }
+
+    // Caps to announce on src pad `_src`, None to use the fixated pad template
+    fn src_caps(&self, _src: usize) -> Option<gst::Caps> {
+        None
+    }
+
+    // Whether src pad `_src` has a peer, updated before every batch so outputs nobody
+    // consumes can be skipped, leaving an empty buffer
+    fn src_linked(&mut self, _src: usize, _linked: bool) {}
+
+    // Element messages to post on the bus, drained after every processed batch
+    fn messages(&mut self) -> Vec<gst::Structure> {
+        Vec::new()
+    }
+
+    // One of the action signals listed by `Registry::actions` was emitted
+    fn action(&mut self, _name: &str) {}
 }
 
 struct State<T>
@@ -28,6 +108,14 @@ where
     T: 'static + Send + Default + Process + CapsDef,
 {
     processor: T,
+    // Consecutive batches that failed to process
+    errors: u32,
+    max_errors: u32,
+    // Zipped buffer sets waiting for a batched forward pass
+    batch: Vec<Vec<gst::Buffer>>,
+    batch_started: Option<Instant>,
+    batch_size: u32,
+    batch_timeout: u64,
 }
 
 impl<T> Default for State<T>
@@ -37,19 +125,202 @@ where
     fn default() -> State<T> {
         State::<T> {
             processor: Default::default(),
+            errors: 0,
+            max_errors: 0,
+            batch: Vec::new(),
+            batch_started: None,
+            batch_size: 1,
+            batch_timeout: 0,
         }
     }
 }
 
 type PadMap = HashMap<gst::Pad, PadInfo>;
 
-#[derive(Debug)]
+// Results of a processed batch, pushed once the state is unlocked
+struct Output {
+    outbufs: Vec<Vec<gst::Buffer>>,
+    messages: Vec<gst::Structure>,
+    started: Option<Instant>,
+}
+
+// Flow of a buffer pushed on every src pad: any error wins, NotLinked only once no pad is linked
+fn combine_flows(
+    flows: &[Result<gst::FlowSuccess, gst::FlowError>],
+) -> Result<gst::FlowSuccess, gst::FlowError> {
+    if let Some(err) = flows
+        .iter()
+        .filter_map(|flow| flow.err())
+        .find(|err| *err != gst::FlowError::NotLinked)
+    {
+        return Err(err);
+    }
+    if !flows.is_empty()
+        && flows
+            .iter()
+            .all(|flow| *flow == Err(gst::FlowError::NotLinked))
+    {
+        return Err(gst::FlowError::NotLinked);
+    }
+    Ok(gst::FlowSuccess::Ok)
+}
+
+// Pads in declaration order
+fn ordered(pads: &PadMap) -> Vec<gst::Pad> {
+    let mut pads: Vec<(gst::Pad, usize)> = pads
+        .iter()
+        .map(|(pad, info)| (pad.clone(), info.idx))
+        .collect();
+    pads.sort_by_key(|(_, idx)| *idx);
+    pads.into_iter().map(|(pad, _)| pad).collect()
+}
+
+// Properties handled by Cata itself, installed after the processor's own
+static CATA_PROPERTIES: [subclass::Property; 12] = [
+    subclass::Property("zip-policy", |name| {
+        glib::ParamSpec::enum_(
+            name,
+            "Zip policy",
+            "How buffers arriving on several sink pads are matched",
+            ZipPolicy::static_type(),
+            ZipPolicy::Latest as i32,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+    subclass::Property("zip-tolerance", |name| {
+        glib::ParamSpec::uint64(
+            name,
+            "Zip tolerance",
+            "Maximum PTS difference in nanoseconds between matched buffers",
+            0,
+            u64::MAX,
+            zipper::DEFAULT_TOLERANCE,
+            glib::ParamFlags::READWRITE
+                | gst::PARAM_FLAG_CONTROLLABLE
+                | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+    subclass::Property("max-queue", |name| {
+        glib::ParamSpec::uint(
+            name,
+            "Max queue",
+            "Maximum buffers queued per sink pad while waiting for a match",
+            1,
+            u32::MAX,
+            zipper::DEFAULT_MAX_QUEUE,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+    subclass::Property("dropped", |name| {
+        glib::ParamSpec::uint64(
+            name,
+            "Dropped",
+            "Buffers dropped while matching sink pads",
+            0,
+            u64::MAX,
+            0,
+            glib::ParamFlags::READABLE,
+        )
+    }),
+    subclass::Property("max-errors", |name| {
+        glib::ParamSpec::uint(
+            name,
+            "Max errors",
+            "Consecutive frames allowed to fail processing, and be dropped, before erroring out",
+            0,
+            u32::MAX,
+            0,
+            glib::ParamFlags::READWRITE
+                | gst::PARAM_FLAG_CONTROLLABLE
+                | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+    subclass::Property("batch-size", |name| {
+        glib::ParamSpec::uint(
+            name,
+            "Batch size",
+            "Frames processed together in one forward pass",
+            1,
+            u32::MAX,
+            1,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_READY,
+        )
+    }),
+    subclass::Property("batch-timeout", |name| {
+        glib::ParamSpec::uint64(
+            name,
+            "Batch timeout",
+            "Nanoseconds after which a partial batch is processed, 0 to wait for a full batch",
+            0,
+            u64::MAX,
+            0,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_READY,
+        )
+    }),
+    subclass::Property("async", |name| {
+        glib::ParamSpec::boolean(
+            name,
+            "Async",
+            "Run inference on a dedicated worker thread fed through a bounded queue",
+            false,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_READY,
+        )
+    }),
+    subclass::Property("queue-size", |name| {
+        glib::ParamSpec::uint(
+            name,
+            "Queue size",
+            "Frames waiting for the async worker before the leaky policy applies",
+            1,
+            u32::MAX,
+            worker::DEFAULT_QUEUE_SIZE,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+    subclass::Property("leaky", |name| {
+        glib::ParamSpec::enum_(
+            name,
+            "Leaky",
+            "Which frames to drop when the async queue is full",
+            Leaky::static_type(),
+            Leaky::Downstream as i32,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+    subclass::Property("queue-dropped", |name| {
+        glib::ParamSpec::uint64(
+            name,
+            "Queue dropped",
+            "Frames dropped because the async queue was full",
+            0,
+            u64::MAX,
+            0,
+            glib::ParamFlags::READABLE,
+        )
+    }),
+    subclass::Property("processing-latency", |name| {
+        glib::ParamSpec::uint64(
+            name,
+            "Processing latency",
+            "Nanoseconds from receiving the last processed frames to pushing their results",
+            0,
+            u64::MAX,
+            0,
+            glib::ParamFlags::READABLE,
+        )
+    }),
+];
+
+#[derive(Debug, Clone)]
 struct PadInfo {
     name: &'static str,
     idx: usize,
     caps: gst::Caps,
 }
 
+// Lock order: zipper, order, eos, worker, state. The pad maps are only held on their own,
+// never while holding another lock, and the state only nests the queue, deadline and turns.
+// Results are pushed downstream after releasing the state, paced sticky pads wait on the zipper.
 pub struct Cata<T>
 where
     T: 'static + Send + Default + Process + CapsDef,
@@ -58,6 +329,19 @@ where
     src_pads: Mutex<PadMap>,
     sink_pads: Mutex<PadMap>,
     zipper: Mutex<Zipper>,
+    // Signaled when paced sticky pads may push again
+    paced: Condvar,
+    // Held from zipping until the matches are queued or processed
+    order: Mutex<()>,
+    eos: Mutex<HashSet<usize>>,
+    async_mode: Mutex<bool>,
+    queue: worker::Queue,
+    worker: Mutex<Option<thread::JoinHandle<()>>>,
+    deadline: worker::Deadline,
+    flusher: Mutex<Option<thread::JoinHandle<()>>>,
+    // Keeps the results of batches in processing order while they are pushed
+    turns: worker::Turns,
+    latency: Mutex<u64>,
     state: Mutex<State<T>>,
 }
 
@@ -66,37 +350,203 @@ where
     T: 'static + Send + Default + Process + CapsDef + Registry,
     Self: ElementImpl,
 {
+    // Processor properties followed by the ones handled by Cata
+    fn properties() -> Vec<subclass::Property<'static>> {
+        T::properties()
+            .iter()
+            .chain(CATA_PROPERTIES.iter())
+            .map(|prop| subclass::Property(prop.0, prop.1))
+            .collect()
+    }
+
+    fn src_caps(&self, pad: &gst::Pad) -> gst::Caps {
+        let info = self.src_pads.lock().unwrap().get(pad).unwrap().clone();
+        let state = self.state.lock().unwrap();
+        T::src_caps(&state.processor, info.idx).unwrap_or_else(|| {
+            let mut caps = info.caps.clone();
+            caps.fixate();
+            caps
+        })
+    }
+
+    fn set_caps(&self, pad: &gst::Pad, element: &gst::Element, caps: &gst::Caps) -> bool {
+        let idx = self.sink_pads.lock().unwrap().get(pad).unwrap().idx;
+        if self.zipper.lock().unwrap().is_sticky(idx) {
+            // Non-live sources would race ahead of the other inputs, they are paced by PTS
+            let mut query = gst::query::Latency::new();
+            let live = pad.peer_query(&mut query) && query.get_result().0;
+            gst_debug!(self.cat, obj: pad, "Sticky input is live: {}", live);
+            self.zipper.lock().unwrap().set_paced(idx, !live);
+        }
+        let src_pads = self.ordered_src_pads();
+        // Frames batched so far were negotiated with the previous caps
+        let state = self.state.lock().unwrap();
+        match self.flush_batch(element, &src_pads, state) {
+            Err(gst::FlowError::Error) => return false,
+            res => {
+                gst_debug!(self.cat, obj: pad, "Flushing batch before new caps {:?}", res);
+            }
+        }
+        {
+            let mut state = self.state.lock().unwrap();
+            if !T::set_caps(&mut state.processor, idx, caps) {
+                gst_warning!(self.cat, obj: pad, "Caps not accepted {:?}", caps);
+                return false;
+            }
+        }
+
+        // Announce the caps derived from the new input on every src pad
+        for src_pad in src_pads {
+            // Caps must follow stream-start, they are announced again with the primary input's caps
+            if src_pad
+                .get_sticky_event(gst::EventType::StreamStart, 0)
+                .is_none()
+            {
+                continue;
+            }
+            let src_caps = self.src_caps(&src_pad);
+            gst_debug!(self.cat, obj: &src_pad, "Setting caps {:?}", src_caps);
+            src_pad.push_event(gst::event::Caps::new(&src_caps));
+        }
+        true
+    }
+
+    // Index of a sink pad, None for src pads
+    fn sink_idx(&self, pad: &gst::Pad) -> Option<usize> {
+        self.sink_pads.lock().unwrap().get(pad).map(|info| info.idx)
+    }
+
+    // Flow of paced pads, which stop waiting for zips unless it's Ok
+    fn set_pacing(&self, pacing: Result<(), gst::FlowError>) {
+        self.zipper.lock().unwrap().pacing = pacing;
+        self.paced.notify_all();
+    }
+
+    // Snapshot of the src pads, taken before locking the state
+    fn ordered_src_pads(&self) -> Vec<gst::Pad> {
+        ordered(&self.src_pads.lock().unwrap())
+    }
+
+    fn push_to_srcs(&self, event: &gst::Event) -> bool {
+        let src_pads: Vec<gst::Pad> = self.src_pads.lock().unwrap().keys().cloned().collect();
+        src_pads.iter().fold(true, |ret, src_pad| {
+            src_pad.push_event(event.clone()) && ret
+        })
+    }
+
+    // Stream events of the first sink pad drive every src pad, the other inputs are consumed
+    fn forward(&self, pad: &gst::Pad, event: &gst::Event) -> bool {
+        match self.sink_idx(pad) {
+            Some(0) => self.push_to_srcs(event),
+            _ => true,
+        }
+    }
+
+    // Every output is its own stream, with an id derived from the upstream one
+    fn forward_stream_start(
+        &self,
+        pad: &gst::Pad,
+        element: &gst::Element,
+        event: &gst::event::StreamStart,
+    ) -> bool {
+        if self.sink_idx(pad) != Some(0) {
+            return true;
+        }
+        let src_pads: Vec<(gst::Pad, &'static str)> = self
+            .src_pads
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(pad, info)| (pad.clone(), info.name))
+            .collect();
+        let mut ret = true;
+        for (src_pad, name) in src_pads {
+            let stream_id = src_pad.create_stream_id(element, Some(name));
+            let mut builder =
+                gst::event::StreamStart::builder(&stream_id).flags(event.get_stream_flags());
+            if let Some(group_id) = event.get_group_id() {
+                builder = builder.group_id(group_id);
+            }
+            ret = src_pad.push_event(builder.build()) && ret;
+        }
+        ret
+    }
+
+    // EOS goes downstream once every sink pad but the sticky ones is done
+    fn eos(&self, pad: &gst::Pad, element: &gst::Element) -> bool {
+        let num_sink_pads = self.sink_pads.lock().unwrap().len();
+        let idx = self.sink_idx(pad).unwrap();
+        let all_eos = {
+            let zipper = self.zipper.lock().unwrap();
+            let mut eos = self.eos.lock().unwrap();
+            eos.insert(idx);
+            (0..num_sink_pads).all(|idx| zipper.is_sticky(idx) || eos.contains(&idx))
+        };
+        if !all_eos {
+            gst_debug!(self.cat, obj: pad, "Waiting for EOS on other sink pads");
+            return true;
+        }
+        // No zip releases paced pads anymore
+        self.set_pacing(Err(gst::FlowError::Eos));
+        // Partial batches are processed before EOS goes downstream
+        let src_pads = self.ordered_src_pads();
+        let state = self.state.lock().unwrap();
+        if let Err(err) = self.flush_batch(element, &src_pads, state) {
+            gst_debug!(self.cat, obj: pad, "Flushing batch on EOS failed {:?}", err);
+        }
+        let _ = self.stop(element);
+        self.push_to_srcs(&gst::event::Eos::new())
+    }
+
     fn cata_event(&self, pad: &gst::Pad, element: &gst::Element, event: gst::Event) -> bool {
+        // With the async worker, serialized events stay in order with the queued buffers
+        let queued = self.sink_idx(pad).is_some()
+            && event.is_serialized()
+            && event.get_type() != gst::EventType::FlushStop
+            && self.worker.lock().unwrap().is_some();
+        if queued {
+            gst_log!(self.cat, obj: pad, "Queueing event {:?}", event);
+            return self.queue.push(Item::Event(pad.clone(), event)).is_ok();
+        }
+        self.handle_event(pad, element, event)
+    }
+
+    fn handle_event(&self, pad: &gst::Pad, element: &gst::Element, event: gst::Event) -> bool {
         use gst::EventView;
 
         gst_log!(self.cat, obj: pad, "Handling event {:?}", event);
 
         let ret = match event.view() {
             EventView::FlushStart(..) => {
+                self.queue.set_flushing(true);
+                self.set_pacing(Err(gst::FlowError::Flushing));
                 let _ = self.stop(element);
-                true
+                self.forward(pad, &event)
             }
             EventView::FlushStop(..) => {
+                self.queue.set_flushing(false);
+                // Queued buffers would never match the ones arriving after a flush
+                self.zipper.lock().unwrap().clear();
+                self.set_pacing(Ok(()));
+                self.eos.lock().unwrap().clear();
+                self.state.lock().unwrap().batch.clear();
                 let (res, state, pending) = element.get_state(0.into());
                 if res == Ok(gst::StateChangeSuccess::Success) && state == gst::State::Playing
                     || res == Ok(gst::StateChangeSuccess::Async) && pending == gst::State::Playing
                 {
                     let _ = self.start(element);
                 }
-                true
+                self.forward(pad, &event)
             }
             EventView::Reconfigure(..) => true,
             EventView::Latency(..) => true,
-            EventView::StreamStart(..) => true,
-            EventView::Caps(..) => true,
-            EventView::Tag(..) => true,
-            EventView::Segment(..) => true,
+            EventView::StreamStart(ref e) => self.forward_stream_start(pad, element, e),
+            EventView::Caps(ref e) => self.set_caps(pad, element, &e.get_caps_owned()),
+            EventView::Tag(..) => self.forward(pad, &event),
+            EventView::Segment(..) => self.forward(pad, &event),
             EventView::Qos(..) => true,
-            EventView::StreamGroupDone(..) => true,
-            EventView::Eos(..) => {
-                let _ = self.stop(element);
-                true
-            }
+            EventView::StreamGroupDone(..) => self.forward(pad, &event),
+            EventView::Eos(..) => self.eos(pad, element),
             _ => false,
         };
 
@@ -129,21 +579,32 @@ where
                 true
             }
             QueryView::AcceptCaps(ref mut q) => {
-                // TODO: validate what we are agreeing to
-                q.set_result(true);
+                let pads = match pad.get_direction() {
+                    gst::PadDirection::Sink => self.sink_pads.lock().unwrap(),
+                    gst::PadDirection::Src => self.src_pads.lock().unwrap(),
+                    _ => panic!("Querying pad with unknown direction"),
+                };
+                let accepted = q.get_caps().can_intersect(&pads.get(pad).unwrap().caps);
+                q.set_result(accepted);
                 true
             }
             QueryView::Caps(ref mut q) => {
                 // Agree on caps
-                let pads = match pad.get_direction() {
-                    gst::PadDirection::Sink => self.sink_pads.lock().unwrap(),
-                    gst::PadDirection::Src => self.src_pads.lock().unwrap(),
+                let caps = match pad.get_direction() {
+                    gst::PadDirection::Sink => self
+                        .sink_pads
+                        .lock()
+                        .unwrap()
+                        .get(pad)
+                        .unwrap()
+                        .caps
+                        .clone(),
+                    gst::PadDirection::Src => self.src_caps(pad),
                     _ => panic!("Querying pad with unknown direction"),
                 };
-                let caps = &pads.get(pad).unwrap().caps;
                 let caps = q
                     .get_filter()
-                    .map(|f| f.intersect_with_mode(caps, gst::CapsIntersectMode::First))
+                    .map(|f| f.intersect_with_mode(&caps, gst::CapsIntersectMode::First))
                     .unwrap_or_else(|| caps.clone());
                 q.set_result(&caps);
                 true
@@ -170,6 +631,8 @@ where
 
     fn prepare(&self, element: &gst::Element) -> Result<(), gst::ErrorMessage> {
         gst_debug!(self.cat, obj: element, "Preparing");
+        let mut state = self.state.lock().unwrap();
+        T::prepare(&mut state.processor)?;
         gst_debug!(self.cat, obj: element, "Prepared");
         Ok(())
     }
@@ -202,61 +665,305 @@ where
         gst_trace!(self.cat, obj: pad, "Handling buffer {:?}", inbuf);
 
         // Push buffer to zipper
-        let zipper = &mut self.zipper.lock().unwrap();
-        {
+        let (info, num_sink_pads) = {
             let sink_pads = self.sink_pads.lock().unwrap();
-            let info = sink_pads.get(pad).unwrap();
+            (sink_pads.get(pad).unwrap().clone(), sink_pads.len())
+        };
+        let (zips, _order) = {
+            let mut zipper = self.zipper.lock().unwrap();
+            // Paced pads wait for the zips to catch up with their queued buffers
+            while zipper.is_backlogged(info.idx) {
+                zipper.pacing?;
+                zipper = self.paced.wait(zipper).unwrap();
+            }
             zipper.push(inbuf, info.idx);
             gst_trace!(self.cat, obj: pad, "Pushed buffer to zipper {:?}", &info);
+
+            // A single buffer can complete several matches, e.g. a slow pad releasing a backlog
+            let mut zips = Vec::new();
+            while let Some(buffers) = zipper.try_zip() {
+                zips.push(buffers);
+            }
+            if !zips.is_empty() {
+                self.paced.notify_all();
+            }
+            // The zipper is released before a full queue blocks, as the worker locks it on EOS,
+            // while the order lock keeps matches of different sink pads in zip order
+            (zips, self.order.lock().unwrap())
         };
 
-        // Check if zipper can zip, process and push to srcs
-        if let Some(buffers) = zipper.try_zip() {
+        for buffers in zips {
             gst_trace!(
                 self.cat,
                 obj: pad,
                 "Check if zipper can zip, process and push to srcs {:?}",
                 buffers
             );
-            let num_sink_pads = self.sink_pads.lock().unwrap().len();
-            let num_src_pads = self.src_pads.lock().unwrap().len();
-
-            // Prepare buffer inputs and outputs
-            let mut outbufs: Vec<gst::Buffer> = Vec::new();
-            outbufs.resize_with(num_src_pads, || gst::Buffer::new());
             assert_eq!(
                 num_sink_pads,
                 buffers.len(),
                 "Num of sink pads don't match IN buffers"
             );
+
+            // The async worker takes over, upstream only waits when the queue doesn't leak
+            if self.worker.lock().unwrap().is_some() {
+                self.queue.push(Item::Buffers(buffers, Instant::now()))?;
+            } else {
+                self.handle_buffers(element, buffers, Instant::now())?;
+            }
+        }
+
+        Ok(gst::FlowSuccess::Ok)
+    }
+
+    // Batches zipped buffers, processing the batch once full or timed out
+    fn handle_buffers(
+        &self,
+        element: &gst::Element,
+        buffers: Vec<gst::Buffer>,
+        received: Instant,
+    ) -> Result<gst::FlowSuccess, gst::FlowError> {
+        let src_pads = self.ordered_src_pads();
+        let mut state = self.state.lock().unwrap();
+        if state.batch.is_empty() {
+            state.batch_started = Some(received);
+            // The flusher processes the partial batch if no frame completes it in time
+            if state.batch_timeout > 0 && state.batch_size > 1 {
+                self.deadline
+                    .set(Some(received + Duration::from_nanos(state.batch_timeout)));
+            }
+        }
+        state.batch.push(buffers);
+
+        let timed_out = state.batch_timeout > 0
+            && state.batch_started.map_or(false, |started| {
+                started.elapsed() >= Duration::from_nanos(state.batch_timeout)
+            });
+        if state.batch.len() >= state.batch_size as usize || timed_out {
+            return self.flush_batch(element, &src_pads, state);
+        }
+        Ok(gst::FlowSuccess::Ok)
+    }
+
+    // Runs on the worker thread until the queue shuts down
+    fn worker_loop(&self, element: &gst::Element) {
+        gst_debug!(self.cat, obj: element, "Worker started");
+        while let Some(item) = self.queue.pop() {
+            match item {
+                Item::Buffers(buffers, received) => {
+                    if let Err(err) = self.handle_buffers(element, buffers, received) {
+                        self.queue.set_error(err);
+                    }
+                }
+                Item::Event(pad, event) => {
+                    self.handle_event(&pad, element, event);
+                }
+            }
+        }
+        gst_debug!(self.cat, obj: element, "Worker stopped");
+    }
+
+    // Runs on the flusher thread, processing partial batches once batch-timeout passes
+    fn flusher_loop(&self, element: &gst::Element) {
+        let src_pads = self.ordered_src_pads();
+        while self.deadline.wait() {
+            let state = self.state.lock().unwrap();
+            let timeout = Duration::from_nanos(state.batch_timeout);
+            // The batch may have been processed, and a new one started, since the deadline passed
+            let timed_out = state
+                .batch_started
+                .map_or(false, |started| started.elapsed() >= timeout);
+            if !timed_out {
+                continue;
+            }
+            gst_trace!(self.cat, obj: element, "Batch timed out");
+            if let Err(err) = self.flush_batch(element, &src_pads, state) {
+                gst_debug!(self.cat, obj: element, "Flushing timed out batch failed {:?}", err);
+                self.queue.set_error(err);
+            }
+        }
+    }
+
+    fn start_flusher(&self, element: &gst::Element) {
+        {
+            let state = self.state.lock().unwrap();
+            if state.batch_timeout == 0 || state.batch_size <= 1 {
+                return;
+            }
+        }
+        self.deadline.start();
+        let element = element.clone();
+        let handle = thread::spawn(move || {
+            let cata = Self::from_instance(&element);
+            cata.flusher_loop(&element);
+        });
+        *self.flusher.lock().unwrap() = Some(handle);
+    }
+
+    fn stop_flusher(&self) {
+        let handle = self.flusher.lock().unwrap().take();
+        if let Some(handle) = handle {
+            self.deadline.shutdown();
+            let _ = handle.join();
+        }
+    }
+
+    fn start_worker(&self, element: &gst::Element) {
+        if !*self.async_mode.lock().unwrap() {
+            return;
+        }
+        self.queue.start();
+        let element = element.clone();
+        let handle = thread::spawn(move || {
+            let cata = Self::from_instance(&element);
+            let res = panic::catch_unwind(panic::AssertUnwindSafe(|| cata.worker_loop(&element)));
+            // Upstream gets an error instead of feeding a queue nobody drains
+            if res.is_err() {
+                cata.queue.set_error(gst::FlowError::Error);
+                gst_element_error!(
+                    element,
+                    gst::LibraryError::Failed,
+                    ["Inference worker panicked"]
+                );
+            }
+        });
+        *self.worker.lock().unwrap() = Some(handle);
+    }
+
+    fn stop_worker(&self) {
+        let handle = self.worker.lock().unwrap().take();
+        if let Some(handle) = handle {
+            self.queue.shutdown();
+            let _ = handle.join();
+        }
+    }
+
+    // Processes the batch, pushing its results once the state is unlocked and every batch
+    // processed before is pushed, so whatever the caller pushes next follows them
+    fn flush_batch(
+        &self,
+        element: &gst::Element,
+        src_pads: &[gst::Pad],
+        mut state: MutexGuard<State<T>>,
+    ) -> Result<gst::FlowSuccess, gst::FlowError> {
+        let output = self.process_batch(element, src_pads, &mut state)?;
+        let turn = self.turns.take();
+        drop(state);
+        let _turn = self.turns.wait(turn);
+        match output {
+            Some(output) => self.push_output(element, src_pads, output),
+            None => Ok(gst::FlowSuccess::Ok),
+        }
+    }
+
+    // Processes the queued zipped buffer sets, None when there was nothing to push
+    fn process_batch(
+        &self,
+        element: &gst::Element,
+        src_pads: &[gst::Pad],
+        state: &mut State<T>,
+    ) -> Result<Option<Output>, gst::FlowError> {
+        let batch = std::mem::replace(&mut state.batch, Vec::new());
+        let started = state.batch_started.take();
+        if batch.is_empty() {
+            return Ok(None);
+        }
+        gst_trace!(self.cat, obj: element, "Processing batch of {}", batch.len());
+
+        // Prepare buffer outputs
+        let num_src_pads = src_pads.len();
+        let mut outbufs: Vec<Vec<gst::Buffer>> = batch
+            .iter()
+            .map(|_| {
+                let mut outbuf = Vec::new();
+                outbuf.resize_with(num_src_pads, || gst::Buffer::new());
+                outbuf
+            })
+            .collect();
+
+        for (idx, pad) in src_pads.iter().enumerate() {
+            T::src_linked(&mut state.processor, idx, pad.is_linked());
+        }
+
+        // Process buffers, failed batches are dropped until max-errors is exceeded
+        if let Err(err) = T::process_batch(&mut state.processor, &batch, &mut outbufs) {
+            state.errors += 1;
+            if state.errors > state.max_errors {
+                gst_element_error!(
+                    element,
+                    gst::StreamError::Failed,
+                    ["Failed to process buffers {:?}", batch[0][0].get_pts()],
+                    ["{} ({} consecutive failures)", err, state.errors]
+                );
+                return Err(gst::FlowError::Error);
+            }
+            gst_warning!(
+                self.cat,
+                obj: element,
+                "Dropping {} frame(s) from {:?}: {} ({} of {} consecutive failures allowed)",
+                batch.len(),
+                batch[0][0].get_pts(),
+                err,
+                state.errors,
+                state.max_errors
+            );
+            return Ok(None);
+        }
+        state.errors = 0;
+        for outbuf in outbufs.iter() {
             assert_eq!(
                 num_src_pads,
-                outbufs.len(),
+                outbuf.len(),
                 "Num of src pads don't match OUT buffers"
             );
+        }
 
-            // Process buffers
-            let mut state = self.state.lock().unwrap();
-            T::process(&mut state.processor, &buffers, &mut outbufs).unwrap();
-
-            // Send processed buffers through src pads
-            let src_pads = self.src_pads.lock().unwrap();
-            for _ in 0..num_src_pads {
-                let outbuf = outbufs.pop().unwrap();
-                let idx = outbufs.len();
-                let (pad, info) = src_pads.iter().find(|(_, info)| info.idx == idx).unwrap();
+        Ok(Some(Output {
+            outbufs,
+            messages: T::messages(&mut state.processor),
+            started,
+        }))
+    }
+
+    // Pushes the results of a batch and posts its messages, returning the combined flow
+    fn push_output(
+        &self,
+        element: &gst::Element,
+        src_pads: &[gst::Pad],
+        output: Output,
+    ) -> Result<gst::FlowSuccess, gst::FlowError> {
+        // Send processed buffers through src pads, stopping at the first set that fails
+        let mut flow = Ok(gst::FlowSuccess::Ok);
+        for outbuf in output.outbufs {
+            let mut flows = Vec::with_capacity(src_pads.len());
+            for (pad, outbuf) in src_pads.iter().zip(outbuf) {
                 let res = pad.push(outbuf);
-                gst_trace!(
-                    self.cat,
-                    obj: element,
-                    "Pushing buffer for stream {:?} returned {:?}",
-                    info.name,
-                    res
-                );
+                gst_trace!(self.cat, obj: pad, "Pushing buffer returned {:?}", res);
+                flows.push(res);
+            }
+            flow = combine_flows(&flows);
+            if flow.is_err() {
+                gst_debug!(self.cat, obj: element, "Pushing results returned {:?}", flow);
+                break;
             }
         }
 
-        Ok(gst::FlowSuccess::Ok)
+        for structure in output.messages {
+            if element
+                .post_message(gst::message::Element::new(structure))
+                .is_err()
+            {
+                gst_debug!(self.cat, obj: element, "No bus to post element message on");
+            }
+        }
+
+        if let Some(started) = output.started {
+            let latency = started.elapsed().as_nanos() as u64;
+            *self.latency.lock().unwrap() = latency;
+            gst_trace!(self.cat, obj: element, "Processing latency {} ns", latency);
+        }
+
+        flow
     }
 }
 
@@ -329,7 +1036,27 @@ where
         }
 
         // Install all our properties
-        klass.install_properties(T::properties());
+        klass.install_properties(&Self::properties());
+
+        // Action signals are forwarded to the processor
+        for name in T::actions() {
+            klass.add_action_signal(
+                name,
+                glib::SignalFlags::RUN_LAST,
+                &[],
+                glib::Type::Unit,
+                move |_, args| {
+                    let element = args[0]
+                        .get::<gst::Element>()
+                        .expect("signal arg")
+                        .expect("missing signal arg");
+                    let cata = Self::from_instance(&element);
+                    gst_debug!(cata.cat, obj: &element, "Action {}", name);
+                    cata.state.lock().unwrap().processor.action(name);
+                    None
+                },
+            );
+        }
     }
 
     fn with_class(klass: &subclass::simple::ClassStruct<Self>) -> Self {
@@ -413,19 +1140,36 @@ where
             );
         }
 
+        let cat = gst::DebugCategory::new(
+            T::DEBUG_CATEGORY,
+            gst::DebugColorFlags::empty(),
+            Some("Cata"),
+        );
+
         // Setup buffer zipper
-        let zipper = Zipper::with_size(sink_pads.len());
+        let mut zipper = Zipper::with_size(sink_pads.len(), cat);
+        for info in sink_pads.values() {
+            if T::sticky_sinks().contains(&info.name) {
+                zipper.set_sticky(info.idx);
+            }
+        }
 
         // Create new instance of Cata
         Self {
-            cat: gst::DebugCategory::new(
-                T::DEBUG_CATEGORY,
-                gst::DebugColorFlags::empty(),
-                Some("Cata"),
-            ),
+            cat,
             sink_pads: Mutex::new(sink_pads),
             src_pads: Mutex::new(src_pads),
             zipper: Mutex::new(zipper),
+            paced: Condvar::new(),
+            order: Mutex::new(()),
+            eos: Mutex::new(HashSet::new()),
+            async_mode: Mutex::new(false),
+            queue: worker::Queue::new(cat),
+            worker: Mutex::new(None),
+            deadline: worker::Deadline::new(),
+            flusher: Mutex::new(None),
+            turns: worker::Turns::new(),
+            latency: Mutex::new(0),
             state: Mutex::new(Default::default()),
         }
     }
@@ -439,34 +1183,105 @@ where
     fn constructed(&self, obj: &glib::Object) {
         self.parent_constructed(obj);
         let element = obj.downcast_ref::<gst::Element>().unwrap();
-        for (pad, info) in self.src_pads.lock().unwrap().iter() {
+        // Pads are added in declaration order, so auto-linking picks the first ones
+        // Stream-start, caps and segment events are forwarded from the sink pads
+        for pad in ordered(&self.src_pads.lock().unwrap()) {
             pad.set_active(true).unwrap();
-            let mut caps = info.caps.clone();
-            caps.fixate();
-            pad.push_event(gst::event::Caps::new(&caps));
-            // TODO: proper segment handling
-            let segment = gst::FormattedSegment::<gst::ClockTime>::default();
-            pad.push_event(gst::event::Segment::new(&segment));
-            element.add_pad(pad).unwrap();
+            element.add_pad(&pad).unwrap();
         }
-        for (pad, _info) in self.sink_pads.lock().unwrap().iter() {
-            element.add_pad(pad).unwrap();
+        for pad in ordered(&self.sink_pads.lock().unwrap()) {
+            element.add_pad(&pad).unwrap();
         }
     }
 
     // Called whenever a value of a property is changed. It can be called
     // at any time from any thread.
     fn set_property(&self, _obj: &glib::Object, id: usize, value: &glib::Value) {
-        let prop = &T::properties()[id];
-        let mut state = self.state.lock().unwrap();
-        T::set_property(&mut state.processor, prop, value);
+        let processor_props = T::properties().len();
+        if id < processor_props {
+            let prop = &T::properties()[id];
+            let mut state = self.state.lock().unwrap();
+            T::set_property(&mut state.processor, prop, value);
+            return;
+        }
+
+        let prop = &CATA_PROPERTIES[id - processor_props];
+        match *prop {
+            subclass::Property("zip-policy", ..) => {
+                self.zipper.lock().unwrap().policy = value.get_some().expect("zip policy");
+            }
+            subclass::Property("zip-tolerance", ..) => {
+                self.zipper.lock().unwrap().tolerance = value.get_some().expect("zip tolerance");
+            }
+            subclass::Property("max-queue", ..) => {
+                let max_queue: u32 = value.get_some().expect("max queue");
+                self.zipper.lock().unwrap().max_queue = max_queue as usize;
+            }
+            subclass::Property("max-errors", ..) => {
+                self.state.lock().unwrap().max_errors = value.get_some().expect("max errors");
+            }
+            subclass::Property("batch-size", ..) => {
+                self.state.lock().unwrap().batch_size = value.get_some().expect("batch size");
+            }
+            subclass::Property("batch-timeout", ..) => {
+                self.state.lock().unwrap().batch_timeout = value.get_some().expect("batch timeout");
+            }
+            subclass::Property("async", ..) => {
+                *self.async_mode.lock().unwrap() = value.get_some().expect("async");
+            }
+            subclass::Property("queue-size", ..) => {
+                let size: u32 = value.get_some().expect("queue size");
+                *self.queue.size.lock().unwrap() = size as usize;
+            }
+            subclass::Property("leaky", ..) => {
+                *self.queue.leaky.lock().unwrap() = value.get_some().expect("leaky");
+            }
+            _ => unimplemented!(),
+        }
     }
 
     // Called whenever a value of a property is read. It can be called
     // at any time from any thread.
     fn get_property(&self, _obj: &glib::Object, id: usize) -> Result<glib::Value, ()> {
-        let prop = &T::properties()[id];
+        let processor_props = T::properties().len();
+        if id < processor_props {
+            let prop = &T::properties()[id];
+            let state = self.state.lock().unwrap();
+            return Ok(T::get_property(&state.processor, prop));
+        }
+
+        let prop = &CATA_PROPERTIES[id - processor_props];
         match *prop {
+            subclass::Property("zip-policy", ..) => {
+                Ok(self.zipper.lock().unwrap().policy.to_value())
+            }
+            subclass::Property("zip-tolerance", ..) => {
+                Ok(self.zipper.lock().unwrap().tolerance.to_value())
+            }
+            subclass::Property("max-queue", ..) => {
+                Ok((self.zipper.lock().unwrap().max_queue as u32).to_value())
+            }
+            subclass::Property("dropped", ..) => {
+                Ok(self.zipper.lock().unwrap().dropped().to_value())
+            }
+            subclass::Property("max-errors", ..) => {
+                Ok(self.state.lock().unwrap().max_errors.to_value())
+            }
+            subclass::Property("batch-size", ..) => {
+                Ok(self.state.lock().unwrap().batch_size.to_value())
+            }
+            subclass::Property("batch-timeout", ..) => {
+                Ok(self.state.lock().unwrap().batch_timeout.to_value())
+            }
+            subclass::Property("async", ..) => Ok(self.async_mode.lock().unwrap().to_value()),
+            subclass::Property("queue-size", ..) => {
+                Ok((*self.queue.size.lock().unwrap() as u32).to_value())
+            }
+            subclass::Property("leaky", ..) => Ok(self.queue.leaky.lock().unwrap().to_value()),
+            subclass::Property("queue-dropped", ..) => Ok(self.queue.dropped().to_value()),
+            subclass::Property("processing-latency", ..) => {
+                Ok(self.latency.lock().unwrap().to_value())
+            }
             _ => unimplemented!(),
         }
     }
@@ -493,6 +1308,18 @@ where
             gst::StateChange::PlayingToPaused => {
                 self.stop(element).map_err(|_| gst::StateChangeError)?;
             }
+            gst::StateChange::ReadyToPaused => {
+                self.set_pacing(Ok(()));
+                self.start_worker(element);
+                self.start_flusher(element);
+            }
+            gst::StateChange::PausedToReady => {
+                // Pads deactivate below, unblocking a worker stuck pushing downstream
+                // and paced pads waiting for zips
+                self.queue.shutdown();
+                self.set_pacing(Err(gst::FlowError::Flushing));
+                self.deadline.shutdown();
+            }
             gst::StateChange::ReadyToNull => {
                 self.unprepare(element).map_err(|_| gst::StateChangeError)?;
             }
@@ -508,7 +1335,15 @@ where
             gst::StateChange::PausedToPlaying => {
                 self.start(element).map_err(|_| gst::StateChangeError)?;
             }
-            gst::StateChange::PausedToReady => {}
+            gst::StateChange::PausedToReady => {
+                self.stop_worker();
+                self.stop_flusher();
+                self.zipper.lock().unwrap().clear();
+                self.eos.lock().unwrap().clear();
+                let mut state = self.state.lock().unwrap();
+                state.errors = 0;
+                state.batch.clear();
+            }
             _ => (),
         }
 
@@ -526,3 +1361,25 @@ pub fn register<T: 'static + Send + Default + Registry + Process + CapsDef>(
         Cata::<T>::get_type(),
     )
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn combined_flows() {
+        use gst::FlowError::{Flushing, NotLinked};
+        let ok = Ok(gst::FlowSuccess::Ok);
+        assert_eq!(combine_flows(&[ok, Err(NotLinked)]), ok);
+        assert_eq!(
+            combine_flows(&[Err(NotLinked), Err(NotLinked)]),
+            Err(NotLinked)
+        );
+        assert_eq!(
+            combine_flows(&[ok, Err(Flushing), Err(NotLinked)]),
+            Err(Flushing)
+        );
+        assert_eq!(combine_flows(&[]), ok);
+    }
+}
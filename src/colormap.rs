@@ -0,0 +1,214 @@
+use std::path::Path;
+
+use glib;
+use glib::{GEnum, StaticType};
+use gst;
+use tch;
+use tch::Tensor;
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, GEnum)]
+#[repr(u32)]
+#[genum(type_name = "SimboticColormap")]
+pub enum Colormap {
+    #[genum(name = "Magma", nick = "magma")]
+    Magma = 0,
+    #[genum(name = "Viridis", nick = "viridis")]
+    Viridis = 1,
+    #[genum(name = "Inferno", nick = "inferno")]
+    Inferno = 2,
+    #[genum(name = "Turbo", nick = "turbo")]
+    Turbo = 3,
+    #[genum(name = "Jet", nick = "jet")]
+    Jet = 4,
+    #[genum(name = "Grayscale", nick = "grayscale")]
+    Grayscale = 5,
+    #[genum(name = "Custom: colors read from colormap-file", nick = "custom")]
+    Custom = 6,
+}
+
+// Entries of the generated lookup tables
+pub const LUT_SIZE: i64 = 1024;
+
+pub fn param_spec(name: &str) -> glib::ParamSpec {
+    glib::ParamSpec::enum_(
+        name,
+        "Colormap",
+        "Colormap used to visualize values",
+        Colormap::static_type(),
+        Colormap::Magma as i32,
+        glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_READY,
+    )
+}
+
+pub fn file_param_spec(name: &str) -> glib::ParamSpec {
+    glib::ParamSpec::string(
+        name,
+        "Colormap file",
+        "Image whose first row holds the colors of the custom colormap",
+        None,
+        glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_READY,
+    )
+}
+
+pub fn invert_param_spec(name: &str) -> glib::ParamSpec {
+    glib::ParamSpec::boolean(
+        name,
+        "Invert",
+        "Run the colormap from its brightest to its darkest color",
+        false,
+        glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_READY,
+    )
+}
+
+// Polynomial fits of the matplotlib colormaps, per channel and lowest degree first
+#[rustfmt::skip]
+const MAGMA: [[f64; 7]; 3] = [
+    [-0.002136485053939582, 0.2516605407371642, 8.353717279216625, -27.66873308576866, 52.17613981234068, -50.76852536473588, 18.65570506591883],
+    [-0.000749655052795221, 0.6775232436837668, -3.577719514958484, 14.26473078096533, -27.94360607168351, 29.04658282127291, -11.48977351997711],
+    [-0.005386127855323933, 2.494026599312351, 0.3144679030132573, -13.64921318813922, 12.94416944238394, 4.23415299384598, -5.601961508734096],
+];
+
+#[rustfmt::skip]
+const VIRIDIS: [[f64; 7]; 3] = [
+    [0.2777273272234177, 0.1050930431085774, -0.3308618287255563, -4.634230498983486, 6.228269936347081, 4.776384997670288, -5.435455855934631],
+    [0.005407344544966578, 1.404613529898575, 0.214847559468213, -5.799100973351585, 14.17993336680509, -13.74514537774601, 4.645852612178535],
+    [0.3340998053353061, 1.384590162594685, 0.09509516302823659, -19.33244095627987, 56.69055260068105, -65.35303263337234, 26.3124352495832],
+];
+
+#[rustfmt::skip]
+const INFERNO: [[f64; 7]; 3] = [
+    [0.0002189403691192265, 0.1065134194856116, 11.60249308247187, -41.70399613139459, 77.162935699427, -71.31942824499214, 25.13112622477341],
+    [0.001651004631001012, 0.5639564367884091, -3.972853965665698, 17.43639888205313, -33.40235894210092, 32.62606426397723, -12.24266895238567],
+    [-0.01948089843709184, 3.932712388889277, -15.9423941062914, 44.35414519872813, -81.80730925738993, 73.20951985803202, -23.07032500287172],
+];
+
+// Polynomial fit of Google's Turbo
+#[rustfmt::skip]
+const TURBO: [[f64; 6]; 3] = [
+    [0.13572138, 4.61539260, -42.66032258, 132.13108234, -152.94239396, 59.28637943],
+    [0.09140261, 2.19418839, 4.84296658, -14.18503333, 4.27729857, 2.82956604],
+    [0.10667330, 12.64194608, -60.58204836, 110.36276771, -89.90310912, 27.34824973],
+];
+
+fn polynomial(coefficients: &[f64], x: f64) -> f64 {
+    coefficients.iter().rev().fold(0.0, |acc, c| acc * x + c)
+}
+
+// RGB in 0..1 of a generated colormap at `x` in 0..1
+fn color(colormap: Colormap, x: f64) -> [f64; 3] {
+    let mut rgb = [0.0; 3];
+    for (channel, value) in rgb.iter_mut().enumerate() {
+        *value = match colormap {
+            Colormap::Magma => polynomial(&MAGMA[channel], x),
+            Colormap::Viridis => polynomial(&VIRIDIS[channel], x),
+            Colormap::Inferno => polynomial(&INFERNO[channel], x),
+            Colormap::Turbo => polynomial(&TURBO[channel], x),
+            Colormap::Jet => 1.5 - (4.0 * x - 3.0 + channel as f64).abs(),
+            Colormap::Grayscale | Colormap::Custom => x,
+        };
+    }
+    rgb
+}
+
+// Tensor[[3, 1, size], Uint8] lookup table, darkest entry first
+pub fn generate(colormap: Colormap, size: i64) -> Tensor {
+    let size = size.max(2);
+    let lut = (0..size)
+        .flat_map(|i| color(colormap, i as f64 / (size - 1) as f64).to_vec())
+        .map(|value| (value.max(0.0).min(1.0) * 255.0).round() as u8)
+        .collect::<Vec<u8>>();
+    Tensor::of_slice(&lut)
+        .view((size, 1, 3))
+        .permute(&[2, 1, 0])
+        .contiguous()
+}
+
+// Tensor[[3, 1, W], Uint8] lookup table from the first row of an image
+pub fn load(path: &Path) -> Result<Tensor, gst::ErrorMessage> {
+    let image = tch::vision::image::load(path).map_err(|err| {
+        gst_error_msg!(
+            gst::ResourceError::NotFound,
+            ["Failed to load color map {}", path.display()],
+            ["{}", err]
+        )
+    })?;
+    Ok(image.narrow(1, 0, 1).contiguous())
+}
+
+// Lookup table of a colormap, `file` is only read by the custom colormap
+pub fn lut(
+    colormap: Colormap,
+    file: &Option<String>,
+    invert: bool,
+) -> Result<Tensor, gst::ErrorMessage> {
+    let lut = match colormap {
+        Colormap::Custom => {
+            let file = file.as_ref().ok_or_else(|| {
+                gst_error_msg!(
+                    gst::ResourceError::NotFound,
+                    ["The custom colormap needs colormap-file"]
+                )
+            })?;
+            load(Path::new(file))?
+        }
+        _ => generate(colormap, LUT_SIZE),
+    };
+    Ok(if invert { lut.flip(&[2]) } else { lut })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn rgb(lut: &Tensor, i: i64) -> Vec<u8> {
+        Vec::from(lut.select(2, i).flatten(0, -1))
+    }
+
+    #[test]
+    fn matches_reference_endpoints() {
+        let magma = generate(Colormap::Magma, 256);
+        assert_eq!(magma.size(), vec![3, 1, 256]);
+        assert_eq!(magma.kind(), tch::Kind::Uint8);
+        // The fits stay within 2% of the reference tables
+        let close = |actual: Vec<u8>, expected: [u8; 3]| {
+            actual
+                .iter()
+                .zip(expected.iter())
+                .all(|(a, e)| (*a as i32 - *e as i32).abs() <= 5)
+        };
+        // matplotlib's magma runs from (0, 0, 4) to (252, 253, 191)
+        assert!(close(rgb(&magma, 0), [0, 0, 4]));
+        assert!(close(rgb(&magma, 255), [252, 253, 191]));
+        // viridis runs from (68, 1, 84) to (253, 231, 37)
+        let viridis = generate(Colormap::Viridis, 256);
+        assert!(close(rgb(&viridis, 0), [68, 1, 84]));
+        assert!(close(rgb(&viridis, 255), [253, 231, 37]));
+    }
+
+    #[test]
+    fn grayscale_and_jet_are_exact() {
+        let gray = generate(Colormap::Grayscale, 256);
+        assert_eq!(rgb(&gray, 0), vec![0, 0, 0]);
+        assert_eq!(rgb(&gray, 128), vec![128, 128, 128]);
+        assert_eq!(rgb(&gray, 255), vec![255, 255, 255]);
+        let jet = generate(Colormap::Jet, 5);
+        assert_eq!(rgb(&jet, 0), vec![0, 0, 128]);
+        assert_eq!(rgb(&jet, 2), vec![128, 255, 128]);
+        assert_eq!(rgb(&jet, 4), vec![128, 0, 0]);
+    }
+
+    #[test]
+    fn invert_flips_the_table() {
+        let turbo = lut(Colormap::Turbo, &None, false).unwrap();
+        let inverted = lut(Colormap::Turbo, &None, true).unwrap();
+        assert_eq!(inverted.size(), vec![3, 1, LUT_SIZE]);
+        assert_eq!(rgb(&inverted, 0), rgb(&turbo, LUT_SIZE - 1));
+        assert_eq!(rgb(&inverted, LUT_SIZE - 1), rgb(&turbo, 0));
+    }
+
+    #[test]
+    fn custom_needs_a_file() {
+        assert!(lut(Colormap::Custom, &None, false).is_err());
+    }
+}
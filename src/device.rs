@@ -0,0 +1,69 @@
+use glib;
+use glib::ToValue;
+use gst;
+use tch;
+
+lazy_static! {
+    static ref CAT: gst::DebugCategory = gst::DebugCategory::new(
+        "torchdevice",
+        gst::DebugColorFlags::empty(),
+        Some("Torch device selection"),
+    );
+}
+
+pub const DEFAULT: &str = "auto";
+
+// Parses "cpu", "cuda", "cuda:N" or "auto" into a torch device
+pub fn parse(device: &str) -> Option<tch::Device> {
+    match device.trim() {
+        "cpu" => Some(tch::Device::Cpu),
+        "cuda" => Some(tch::Device::Cuda(0)),
+        "auto" => Some(tch::Device::cuda_if_available()),
+        other => other
+            .strip_prefix("cuda:")
+            .and_then(|ordinal| ordinal.parse::<usize>().ok())
+            .map(tch::Device::Cuda),
+    }
+}
+
+pub fn to_string(device: tch::Device) -> String {
+    match device {
+        tch::Device::Cpu => "cpu".to_string(),
+        tch::Device::Cuda(ordinal) => format!("cuda:{}", ordinal),
+    }
+}
+
+pub fn to_value(device: tch::Device) -> glib::Value {
+    to_string(device).to_value()
+}
+
+pub fn default() -> tch::Device {
+    parse(DEFAULT).unwrap()
+}
+
+// Reads a `device` property value, keeping the current device if it can't be parsed
+pub fn from_value(value: &glib::Value, current: tch::Device) -> tch::Device {
+    let device: Option<String> = value.get().expect("device string");
+    match device.as_ref().map(|device| parse(device)) {
+        Some(Some(device)) => device,
+        _ => {
+            gst_warning!(
+                CAT,
+                "Invalid device {:?}, keeping {}",
+                device,
+                to_string(current)
+            );
+            current
+        }
+    }
+}
+
+pub fn param_spec(name: &str) -> glib::ParamSpec {
+    glib::ParamSpec::string(
+        name,
+        "Device",
+        "Torch device to run on: cpu, cuda, cuda:N or auto, applied when going to READY",
+        Some(DEFAULT),
+        glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_READY,
+    )
+}
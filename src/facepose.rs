@@ -1,12 +1,17 @@
-use std::env;
 use std::i32;
+use std::path::Path;
 use std::sync::Mutex;
 
 use crate::caps;
 use crate::cata;
+use crate::device;
+use crate::frame;
+use crate::meta;
+use crate::models;
 use crate::registry;
 
 use glib::subclass;
+use glib::ToValue;
 use gst;
 use gst_video;
 
@@ -24,23 +29,20 @@ const TEXTURE_EXTENT: wgpu::Extent3d = wgpu::Extent3d {
     depth: 1,
 };
 
-lazy_static! {
-    static ref IMAGENET_MEAN: Mutex<Tensor> = Mutex::new(
+// Input mean and std of the prediction model, Tensor[[3, 1, 1], Float] on `device`
+fn input_stats(device: tch::Device) -> (Tensor, Tensor) {
+    (
         Tensor::of_slice(&[127.5f32, 127.5f32, 127.5f32])
-            .to_device(tch::Device::Cuda(0))
             .view((3, 1, 1))
-    );
-    static ref IMAGENET_STD: Mutex<Tensor> = Mutex::new(
+            .to_device(device),
         Tensor::of_slice(&[128f32, 128f32, 128f32])
-            .to_device(tch::Device::Cuda(0))
             .view((3, 1, 1))
-    );
+            .to_device(device),
+    )
 }
 
-pub fn normalize(tensor: &Tensor) -> Result<Tensor, TchError> {
-    let mean = IMAGENET_MEAN.lock().unwrap();
-    let std = IMAGENET_STD.lock().unwrap();
-    tensor.to_kind(tch::Kind::Float).f_sub(&mean)?.f_div(&std)
+pub fn normalize(tensor: &Tensor, (mean, std): &(Tensor, Tensor)) -> Result<Tensor, TchError> {
+    tensor.to_kind(tch::Kind::Float).f_sub(mean)?.f_div(std)
 }
 
 struct Config {
@@ -55,6 +57,24 @@ struct Config {
     tri: Vec<u32>,
 }
 
+impl Config {
+    fn load(model_dir: &Path, device: tch::Device) -> Result<Config, gst::ErrorMessage> {
+        let path = |name: &str| model_dir.join(format!("param.{}.npy", name));
+        let param = |name: &str| models::read_npy(&path(name), device);
+        Ok(Config {
+            std: param("std")?,
+            mean: param("mean")?,
+            u: param("u")?,
+            w_shp: param("w_shp")?,
+            w_exp: param("w_exp")?,
+            u_base: param("u_base")?,
+            w_shp_base: param("w_shp_base")?,
+            w_exp_base: param("w_exp_base")?,
+            tri: tri_to_indices(models::read_npy(&path("tri"), tch::Device::Cpu)?),
+        })
+    }
+}
+
 struct Param {
     p: Tensor,
     offset: Tensor,
@@ -107,55 +127,16 @@ lazy_static! {
             ),
         ],
     ));
-    static ref FACE_MODEL: Mutex<tch::CModule> = Mutex::new(
-        tch::CModule::load(env::var("SIMBOTIC_TORCH").unwrap() + "/models/facepose/prediction.pt")
-            .unwrap()
-    );
-    static ref CONFIG: Mutex<Config> = Mutex::new(Config {
-        std: Tensor::read_npy(
-            env::var("SIMBOTIC_TORCH").unwrap() + "/models/facepose/param.std.npy"
-        )
-        .unwrap()
-        .to_device(tch::Device::Cuda(0)),
-        mean: Tensor::read_npy(
-            env::var("SIMBOTIC_TORCH").unwrap() + "/models/facepose/param.mean.npy"
-        )
-        .unwrap()
-        .to_device(tch::Device::Cuda(0)),
-        u: Tensor::read_npy(env::var("SIMBOTIC_TORCH").unwrap() + "/models/facepose/param.u.npy")
-            .unwrap()
-            .to_device(tch::Device::Cuda(0)),
-        w_shp: Tensor::read_npy(
-            env::var("SIMBOTIC_TORCH").unwrap() + "/models/facepose/param.w_shp.npy"
-        )
-        .unwrap()
-        .to_device(tch::Device::Cuda(0)),
-        w_exp: Tensor::read_npy(
-            env::var("SIMBOTIC_TORCH").unwrap() + "/models/facepose/param.w_exp.npy"
-        )
-        .unwrap()
-        .to_device(tch::Device::Cuda(0)),
-        u_base: Tensor::read_npy(
-            env::var("SIMBOTIC_TORCH").unwrap() + "/models/facepose/param.u_base.npy"
-        )
-        .unwrap()
-        .to_device(tch::Device::Cuda(0)),
-        w_shp_base: Tensor::read_npy(
-            env::var("SIMBOTIC_TORCH").unwrap() + "/models/facepose/param.w_shp_base.npy"
-        )
-        .unwrap()
-        .to_device(tch::Device::Cuda(0)),
-        w_exp_base: Tensor::read_npy(
-            env::var("SIMBOTIC_TORCH").unwrap() + "/models/facepose/param.w_exp_base.npy"
-        )
-        .unwrap()
-        .to_device(tch::Device::Cuda(0)),
-        tri: tri_to_indices(env::var("SIMBOTIC_TORCH").unwrap() + "/models/facepose/param.tri.npy"),
-    });
 }
 
-fn tri_to_indices(file: String) -> Vec<u32> {
-    let param_tri = Tensor::read_npy(file).unwrap();
+// Metadata for the properties
+static PROPERTIES: [subclass::Property; 3] = [
+    subclass::Property("device", |name| device::param_spec(name)),
+    subclass::Property("model-dir", |name| models::dir_param_spec(name)),
+    subclass::Property("attach-tensor", |name| meta::param_spec(name)),
+];
+
+fn tri_to_indices(param_tri: Tensor) -> Vec<u32> {
     let indices: Vec<i32> = Vec::from(param_tri);
     indices.iter().map(|i| (*i - 1) as u32).collect()
 }
@@ -163,11 +144,17 @@ fn tri_to_indices(file: String) -> Vec<u32> {
 pub struct FacePose {
     video_info_in: gst_video::VideoInfo,
     video_info_out: gst_video::VideoInfo,
+    torch_device: tch::Device,
+    model_dir: Option<String>,
+    model: Option<models::Model>,
+    config: Option<Config>,
+    input_stats: (Tensor, Tensor), // Mean and std on the torch device
     device: wgpu::Device,
     queue: wgpu::Queue,
-    morph_model: render::facepose::morph::Model,
+    morph_model: Option<render::facepose::morph::Model>,
     landmarks_model: render::facepose::landmarks::Model,
     output_buffer: wgpu::Buffer,
+    attach_tensor: bool,
 }
 
 impl registry::Registry for FacePose {
@@ -176,7 +163,7 @@ impl registry::Registry for FacePose {
     register_typedata!();
 
     fn properties() -> &'static [glib::subclass::Property<'static>] {
-        &[]
+        &PROPERTIES
     }
 }
 
@@ -217,9 +204,6 @@ impl std::default::Default for FacePose {
 
         let (device, queue) = futures::executor::block_on(gpu_setup());
 
-        let morph_model =
-            render::facepose::morph::model(&device, TEXTURE_EXTENT, &CONFIG.lock().unwrap().tri);
-
         let landmarks_model = render::facepose::landmarks::model(&device, TEXTURE_EXTENT);
 
         let output_buffer = device.create_buffer(&wgpu::BufferDescriptor {
@@ -232,11 +216,17 @@ impl std::default::Default for FacePose {
         FacePose {
             video_info_in: gst_video::VideoInfo::from_caps(&caps_in).unwrap(),
             video_info_out: gst_video::VideoInfo::from_caps(&caps_out).unwrap(),
+            torch_device: device::default(),
+            model_dir: None,
+            model: None,
+            config: None,
+            input_stats: input_stats(tch::Device::Cpu),
             device,
             queue,
-            morph_model,
+            morph_model: None,
             landmarks_model,
             output_buffer,
+            attach_tensor: false,
         }
     }
 }
@@ -264,42 +254,41 @@ impl cata::Process for FacePose {
         &mut self,
         inbufs: &Vec<gst::Buffer>,
         outbufs: &mut Vec<gst::Buffer>,
-    ) -> Result<(), std::io::Error> {
+    ) -> Result<(), cata::ProcessError> {
         let in_ref = inbufs[0].as_ref();
-        let in_frame =
-            gst_video::VideoFrameRef::from_buffer_ref_readable(in_ref, &self.video_info_in)
-                .unwrap();
-        let _in_stride = in_frame.plane_stride()[0] as usize;
-        let _in_format = in_frame.format();
-        let in_width = in_frame.width() as i32;
-        let in_height = in_frame.height() as i32;
-        let in_data = in_frame.plane_data(0).unwrap();
-
-        let img_slice = unsafe {
-            std::slice::from_raw_parts(in_data.as_ptr(), (in_width * in_height * 3) as usize)
-        };
-        let img = Tensor::of_data_size(
-            img_slice,
-            &[HEIGHT as i64, WIDTH as i64, 3],
-            tch::Kind::Uint8,
-        )
-        .to_device(tch::Device::Cuda(0))
-        .permute(&[2, 0, 1]);
-        let img = normalize(&img).unwrap().unsqueeze(0);
+        let in_frame = frame::map_readable(in_ref, &self.video_info_in)?;
+
+        let img = frame::to_tensor(&in_frame)
+            .f_to_device(self.torch_device)?
+            .permute(&[2, 0, 1]);
+        let img = normalize(&img, &self.input_stats)?.unsqueeze(0);
         let img: tch::IValue = tch::IValue::Tensor(img);
 
-        let face_pred = FACE_MODEL.lock().unwrap().forward_is(&[img]).unwrap();
-        let face_pred = if let tch::IValue::Tensor(face_pred) = &face_pred {
-            Some(face_pred)
-        } else {
-            None
+        let model = self.model.as_ref().ok_or_else(|| {
+            cata::ProcessError::NotPrepared("No prediction model loaded".to_string())
+        })?;
+        let face_pred = model.lock().unwrap().forward_is(&[img])?;
+        let face_pred = match &face_pred {
+            tch::IValue::Tensor(face_pred) => face_pred.squeeze(),
+            _ => {
+                return Err(cata::ProcessError::Shape(
+                    "Model didn't output a parameter tensor".to_string(),
+                ))
+            }
         };
-        let face_pred = face_pred.unwrap().squeeze();
 
-        let config = CONFIG.lock().unwrap();
+        let (config, morph_model) = match (self.config.as_ref(), self.morph_model.as_mut()) {
+            (Some(config), Some(morph_model)) => (config, morph_model),
+            _ => {
+                return Err(cata::ProcessError::NotPrepared(
+                    "No 3DMM config and morph model loaded".to_string(),
+                ))
+            }
+        };
 
-        let param = face_pred * &config.std + &config.mean;
-        let param = parse_param(&param);
+        // Tensor[[62], Float] 3DMM parameters
+        let params = face_pred * &config.std + &config.mean;
+        let param = parse_param(&params);
 
         let vertex_dense = (&config.u
             + config.w_shp.matmul(&param.alpha_shp)
@@ -323,7 +312,7 @@ impl cata::Process for FacePose {
 
         let time = in_ref.get_pts();
 
-        render::facepose::morph::update(&self.device, time, &mut self.morph_model);
+        render::facepose::morph::update(&self.device, time, morph_model);
         render::facepose::landmarks::update(&self.device, time, &mut self.landmarks_model);
 
         let out_img_size = TEXTURE_EXTENT.width;
@@ -333,10 +322,10 @@ impl cata::Process for FacePose {
         {
             let command_buffer = {
                 let mut encoder =
-                    render::facepose::morph::view(&self.device, &vertices, &self.morph_model);
+                    render::facepose::morph::view(&self.device, &vertices, morph_model);
                 encoder.copy_texture_to_buffer(
                     wgpu::TextureCopyView {
-                        texture: &self.morph_model.graphics.color_texture,
+                        texture: &morph_model.graphics.color_texture,
                         mip_level: 0,
                         array_layer: 0,
                         origin: wgpu::Origin3d::ZERO,
@@ -358,21 +347,29 @@ impl cata::Process for FacePose {
 
             self.device.poll(wgpu::Maintain::Wait);
 
-            if let Ok(mapping) = futures::executor::block_on(buffer_future) {
-                outbufs[0] = gst::Buffer::with_size(out_img_size_bytes as usize).unwrap();
-                let out_ref = outbufs[0].get_mut().unwrap();
-                out_ref.set_pts(in_ref.get_pts());
-                out_ref.set_dts(in_ref.get_pts());
-                out_ref.set_offset(in_ref.get_offset());
-                out_ref.set_duration(in_ref.get_duration());
-                let mut out_frame = gst_video::VideoFrameRef::from_buffer_ref_writable(
-                    out_ref,
-                    &self.video_info_out,
-                )
-                .unwrap();
-                let out_data = out_frame.plane_data_mut(0).unwrap();
-                out_data.clone_from_slice(mapping.as_slice());
+            let mapping = futures::executor::block_on(buffer_future).map_err(|_| {
+                cata::ProcessError::Mapping("Failed to read back rendered frame".to_string())
+            })?;
+            outbufs[0] = gst::Buffer::with_size(out_img_size_bytes as usize).map_err(|_| {
+                cata::ProcessError::Mapping("Failed to allocate buffer".to_string())
+            })?;
+            let out_ref = outbufs[0].get_mut().unwrap();
+            out_ref.set_pts(in_ref.get_pts());
+            out_ref.set_dts(in_ref.get_dts());
+            out_ref.set_offset(in_ref.get_offset());
+            out_ref.set_duration(in_ref.get_duration());
+            let mut out_frame = frame::map_writable(out_ref, &self.video_info_out)?;
+            let out_data = out_frame.plane_data_mut(0).map_err(|_| {
+                cata::ProcessError::Mapping("Frame plane is not writable".to_string())
+            })?;
+            if out_data.len() != mapping.as_slice().len() {
+                return Err(cata::ProcessError::Shape(format!(
+                    "Rendered frame of {} bytes doesn't fit a plane of {} bytes",
+                    mapping.as_slice().len(),
+                    out_data.len()
+                )));
             }
+            out_data.clone_from_slice(mapping.as_slice());
         }
 
         {
@@ -406,27 +403,80 @@ impl cata::Process for FacePose {
 
             self.device.poll(wgpu::Maintain::Wait);
 
-            if let Ok(mapping) = futures::executor::block_on(buffer_future) {
-                outbufs[1] = gst::Buffer::with_size(out_img_size_bytes as usize).unwrap();
-                let out_ref = outbufs[1].get_mut().unwrap();
-                out_ref.set_pts(in_ref.get_pts());
-                out_ref.set_dts(in_ref.get_pts());
-                out_ref.set_offset(in_ref.get_offset());
-                out_ref.set_duration(in_ref.get_duration());
-                let mut out_frame = gst_video::VideoFrameRef::from_buffer_ref_writable(
-                    out_ref,
-                    &self.video_info_out,
-                )
-                .unwrap();
-                let out_data = out_frame.plane_data_mut(0).unwrap();
-                out_data.clone_from_slice(mapping.as_slice());
+            let mapping = futures::executor::block_on(buffer_future).map_err(|_| {
+                cata::ProcessError::Mapping("Failed to read back rendered frame".to_string())
+            })?;
+            outbufs[1] = gst::Buffer::with_size(out_img_size_bytes as usize).map_err(|_| {
+                cata::ProcessError::Mapping("Failed to allocate buffer".to_string())
+            })?;
+            let out_ref = outbufs[1].get_mut().unwrap();
+            out_ref.set_pts(in_ref.get_pts());
+            out_ref.set_dts(in_ref.get_dts());
+            out_ref.set_offset(in_ref.get_offset());
+            out_ref.set_duration(in_ref.get_duration());
+            let mut out_frame = frame::map_writable(out_ref, &self.video_info_out)?;
+            let out_data = out_frame.plane_data_mut(0).map_err(|_| {
+                cata::ProcessError::Mapping("Frame plane is not writable".to_string())
+            })?;
+            if out_data.len() != mapping.as_slice().len() {
+                return Err(cata::ProcessError::Shape(format!(
+                    "Rendered frame of {} bytes doesn't fit a plane of {} bytes",
+                    mapping.as_slice().len(),
+                    out_data.len()
+                )));
+            }
+            out_data.clone_from_slice(mapping.as_slice());
+        }
+
+        if self.attach_tensor {
+            for outbuf in outbufs.iter_mut() {
+                meta::TensorMeta::add(outbuf.get_mut().unwrap(), "params", &params)?;
             }
         }
 
         Ok(())
     }
 
-    fn set_property(&mut self, _property: &subclass::Property, _value: &glib::Value) {}
+    fn set_property(&mut self, property: &subclass::Property, value: &glib::Value) {
+        match property {
+            subclass::Property("device", ..) => {
+                self.torch_device = device::from_value(value, self.torch_device);
+            }
+            subclass::Property("model-dir", ..) => {
+                self.model_dir = value.get().expect("model directory");
+            }
+            subclass::Property("attach-tensor", ..) => {
+                self.attach_tensor = value.get_some().expect("attach tensor");
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    fn get_property(&self, property: &subclass::Property) -> glib::Value {
+        match property {
+            subclass::Property("device", ..) => device::to_value(self.torch_device),
+            subclass::Property("model-dir", ..) => self.model_dir.to_value(),
+            subclass::Property("attach-tensor", ..) => self.attach_tensor.to_value(),
+            _ => unimplemented!(),
+        }
+    }
+
+    fn prepare(&mut self) -> Result<(), gst::ErrorMessage> {
+        let model_dir = models::resolve(&self.model_dir, "facepose")?;
+        self.model = Some(models::load(
+            &model_dir.join("prediction.pt"),
+            self.torch_device,
+        )?);
+        let config = Config::load(&model_dir, self.torch_device)?;
+        self.morph_model = Some(render::facepose::morph::model(
+            &self.device,
+            TEXTURE_EXTENT,
+            &config.tri,
+        ));
+        self.config = Some(config);
+        self.input_stats = input_stats(self.torch_device);
+        Ok(())
+    }
 }
 
 #[cfg(test)]
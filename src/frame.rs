@@ -0,0 +1,143 @@
+use byte_slice_cast::AsMutSliceOf;
+use gst;
+use gst_video;
+
+use tch;
+use tch::Tensor;
+
+use crate::cata::ProcessError;
+
+pub fn map_readable<'a>(
+    buffer: &'a gst::BufferRef,
+    info: &gst_video::VideoInfo,
+) -> Result<gst_video::VideoFrameRef<&'a gst::BufferRef>, ProcessError> {
+    gst_video::VideoFrameRef::from_buffer_ref_readable(buffer, info).map_err(|_| {
+        ProcessError::Mapping(format!(
+            "Buffer of {} bytes is not a readable {:?} frame",
+            buffer.get_size(),
+            info
+        ))
+    })
+}
+
+pub fn map_writable<'a>(
+    buffer: &'a mut gst::BufferRef,
+    info: &gst_video::VideoInfo,
+) -> Result<gst_video::VideoFrameRef<&'a mut gst::BufferRef>, ProcessError> {
+    let size = buffer.get_size();
+    gst_video::VideoFrameRef::from_buffer_ref_writable(buffer, info).map_err(|_| {
+        ProcessError::Mapping(format!(
+            "Buffer of {} bytes is not a writable {:?} frame",
+            size, info
+        ))
+    })
+}
+
+// Copies the first plane of a packed video frame into a [H, W, C] Uint8 tensor,
+// dropping the row padding of the stride
+pub fn to_tensor(frame: &gst_video::VideoFrameRef<&gst::BufferRef>) -> Tensor {
+    let width = frame.width() as i64;
+    let height = frame.height() as i64;
+    let channels = frame.format_info().pixel_stride()[0] as i64;
+    let stride = frame.plane_stride()[0] as i64;
+    let data = frame.plane_data(0).unwrap();
+    Tensor::of_data_size(
+        &data[..(stride * height) as usize],
+        &[height, stride],
+        tch::Kind::Uint8,
+    )
+    .narrow(1, 0, width * channels)
+    .reshape(&[height, width, channels])
+}
+
+// Copies a tensor into a new buffer of raw float32 values
+pub fn to_buffer(tensor: &Tensor) -> Result<gst::Buffer, ProcessError> {
+    let tensor = tensor.to_kind(tch::Kind::Float).contiguous();
+    let numel = tensor.numel();
+    let mut buffer = gst::Buffer::with_size(numel * std::mem::size_of::<f32>())
+        .map_err(|_| ProcessError::Mapping("Failed to allocate buffer".to_string()))?;
+    {
+        let mut map = buffer
+            .get_mut()
+            .unwrap()
+            .map_writable()
+            .map_err(|_| ProcessError::Mapping("Tensor buffer is not writable".to_string()))?;
+        let data = map
+            .as_mut_slice_of::<f32>()
+            .map_err(|_| ProcessError::Mapping("Tensor buffer is not float aligned".to_string()))?;
+        tensor.f_copy_data(data, numel)?;
+    }
+    Ok(buffer)
+}
+
+// Copies a [H, W, C] tensor into the first plane of a packed video frame,
+// honouring the row padding of the stride
+pub fn copy_to_frame(
+    tensor: &Tensor,
+    frame: &mut gst_video::VideoFrameRef<&mut gst::BufferRef>,
+) -> Result<(), ProcessError> {
+    let width = frame.width() as i64;
+    let height = frame.height() as i64;
+    let channels = frame.format_info().pixel_stride()[0] as i64;
+    let stride = frame.plane_stride()[0] as i64;
+    let row = width * channels;
+    if tensor.numel() as i64 != height * row {
+        return Err(ProcessError::Shape(format!(
+            "{:?} doesn't fit a {}x{}x{} frame",
+            tensor.size(),
+            height,
+            width,
+            channels
+        )));
+    }
+    let rows = tensor
+        .f_to_device(tch::Device::Cpu)?
+        .f_reshape(&[height, row])?;
+    let rows = if stride > row {
+        let padded = Tensor::zeros(&[height, stride], (rows.kind(), tch::Device::Cpu));
+        padded.f_narrow(1, 0, row)?.f_copy_(&rows)?;
+        padded
+    } else {
+        rows
+    };
+    let data = frame
+        .plane_data_mut(0)
+        .map_err(|_| ProcessError::Mapping("Frame plane is not writable".to_string()))?;
+    rows.f_copy_data(data, (stride * height) as usize)?;
+    Ok(())
+}
+
+// Copies a [H, W] Int16 tensor into the plane of a 16-bit gray frame in native byte order,
+// honouring the row padding of the stride
+pub fn copy_to_frame16(
+    tensor: &Tensor,
+    frame: &mut gst_video::VideoFrameRef<&mut gst::BufferRef>,
+) -> Result<(), ProcessError> {
+    let width = frame.width() as i64;
+    let height = frame.height() as i64;
+    let stride = frame.plane_stride()[0] as i64 / 2;
+    if tensor.size() != [height, width] || tensor.kind() != tch::Kind::Int16 {
+        return Err(ProcessError::Shape(format!(
+            "{:?} {:?} doesn't fit a {}x{} 16-bit frame",
+            tensor.kind(),
+            tensor.size(),
+            height,
+            width
+        )));
+    }
+    let rows = tensor.f_to_device(tch::Device::Cpu)?;
+    let rows = if stride > width {
+        let padded = Tensor::zeros(&[height, stride], (tch::Kind::Int16, tch::Device::Cpu));
+        padded.f_narrow(1, 0, width)?.f_copy_(&rows)?;
+        padded
+    } else {
+        rows.f_contiguous()?
+    };
+    let data = frame
+        .plane_data_mut(0)
+        .map_err(|_| ProcessError::Mapping("Frame plane is not writable".to_string()))?
+        .as_mut_slice_of::<i16>()
+        .map_err(|_| ProcessError::Mapping("Frame plane is not 16-bit aligned".to_string()))?;
+    rows.f_copy_data(data, (stride * height) as usize)?;
+    Ok(())
+}
@@ -0,0 +1,208 @@
+use std::fs;
+use std::path::Path;
+
+use glib;
+use gst;
+use serde::Deserialize;
+
+// A class predicted by a segmentation model and the color it is drawn with
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Label {
+    pub name: String,
+    pub color: [u8; 3],
+}
+
+impl Label {
+    fn new(name: &str, color: [u8; 3]) -> Label {
+        Label {
+            name: name.to_string(),
+            color,
+        }
+    }
+}
+
+// Upper bound of the class ids a GRAY8 label frame can hold
+pub const MAX_LABELS: usize = 256;
+
+pub fn param_spec(name: &str) -> glib::ParamSpec {
+    glib::ParamSpec::string(
+        name,
+        "Labels file",
+        "JSON or CSV file with the name and RGB color of every class, Cityscapes when unset",
+        None,
+        glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_READY,
+    )
+}
+
+#[rustfmt::skip]
+pub fn cityscapes() -> Vec<Label> {
+    vec![
+        Label::new("road",          [128,  64, 128]),
+        Label::new("sidewalk",      [244,  35, 232]),
+        Label::new("building",      [ 70,  70,  70]),
+        Label::new("wall",          [102, 102, 156]),
+        Label::new("fence",         [190, 153, 153]),
+        Label::new("pole",          [153, 153, 153]),
+        Label::new("traffic light", [250, 170,  30]),
+        Label::new("traffic sign",  [220, 220,   0]),
+        Label::new("vegetation",    [107, 142,  35]),
+        Label::new("terrain",       [152, 251, 152]),
+        Label::new("sky",           [ 70, 130, 180]),
+        Label::new("person",        [220,  20,  60]),
+        Label::new("rider",         [255,   0,   0]),
+        Label::new("car",           [  0,   0, 142]),
+        Label::new("truck",         [  0,   0,  70]),
+        Label::new("bus",           [  0,  60, 100]),
+        Label::new("train",         [  0,  80, 100]),
+        Label::new("motorcycle",    [  0,   0, 230]),
+        Label::new("bicycle",       [119,  11,  32]),
+    ]
+}
+
+// `[{"name": "road", "color": [128, 64, 128]}, ...]`
+fn parse_json(text: &str) -> Result<Vec<Label>, String> {
+    serde_json::from_str(text).map_err(|err| err.to_string())
+}
+
+// One `name,r,g,b` line per class, blank lines, `#` comments and a header are skipped
+fn parse_csv(text: &str) -> Result<Vec<Label>, String> {
+    let mut labels = Vec::new();
+    let mut header = true;
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields = line.rsplitn(4, ',').collect::<Vec<_>>();
+        if fields.len() != 4 {
+            return Err(format!("line {}: expected name,r,g,b", number + 1));
+        }
+        let channel = |field: &str| field.trim().parse::<u8>();
+        match (channel(fields[2]), channel(fields[1]), channel(fields[0])) {
+            (Ok(r), Ok(g), Ok(b)) => labels.push(Label::new(fields[3].trim(), [r, g, b])),
+            _ if header => {}
+            _ => return Err(format!("line {}: colors must be 0..255", number + 1)),
+        }
+        header = false;
+    }
+    Ok(labels)
+}
+
+// Labels of a JSON file, or of a CSV file for any other extension
+pub fn load(path: &Path) -> Result<Vec<Label>, gst::ErrorMessage> {
+    let text = fs::read_to_string(path).map_err(|err| {
+        gst_error_msg!(
+            gst::ResourceError::OpenRead,
+            ["Failed to read labels file {}", path.display()],
+            ["{}", err]
+        )
+    })?;
+    let is_json = path
+        .extension()
+        .map(|extension| extension.eq_ignore_ascii_case("json"))
+        .unwrap_or(false);
+    let labels = if is_json {
+        parse_json(&text)
+    } else {
+        parse_csv(&text)
+    }
+    .map_err(|err| {
+        gst_error_msg!(
+            gst::ResourceError::Read,
+            ["Failed to parse labels file {}", path.display()],
+            ["{}", err]
+        )
+    })?;
+    if labels.is_empty() || labels.len() > MAX_LABELS {
+        return Err(gst_error_msg!(
+            gst::ResourceError::Read,
+            [
+                "Labels file {} has {} classes, expected 1 to {}",
+                path.display(),
+                labels.len(),
+                MAX_LABELS
+            ]
+        ));
+    }
+    Ok(labels)
+}
+
+// Labels of the labels-file property, Cityscapes when unset
+pub fn resolve(path: &Option<String>) -> Result<Vec<Label>, gst::ErrorMessage> {
+    match path {
+        Some(path) => load(Path::new(path)),
+        None => Ok(cityscapes()),
+    }
+}
+
+// Class ids of comma separated label names or ids, e.g. `person,rider` or `11,12`
+pub fn select(value: &str, labels: &[Label]) -> Result<Vec<i64>, String> {
+    value
+        .split(',')
+        .map(|class| class.trim())
+        .filter(|class| !class.is_empty())
+        .map(|class| match class.parse::<usize>() {
+            Ok(id) if id < labels.len() => Ok(id as i64),
+            _ => labels
+                .iter()
+                .position(|label| label.name.eq_ignore_ascii_case(class))
+                .map(|id| id as i64)
+                .ok_or_else(|| format!("Unknown class {}", class)),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn parses_json() {
+        let labels = parse_json(
+            r#"[{"name": "wall", "color": [120, 120, 120]}, {"name": "floor", "color": [80, 50, 50]}]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            labels,
+            vec![
+                Label::new("wall", [120, 120, 120]),
+                Label::new("floor", [80, 50, 50])
+            ]
+        );
+        assert!(parse_json(r#"[{"name": "wall", "color": [300, 0, 0]}]"#).is_err());
+    }
+
+    #[test]
+    fn parses_csv() {
+        let labels =
+            parse_csv("name,r,g,b\n# ADE20K\nwall,120,120,120\n\ntraffic, light ,250,170,30\n")
+                .unwrap();
+        assert_eq!(
+            labels,
+            vec![
+                Label::new("wall", [120, 120, 120]),
+                Label::new("traffic, light", [250, 170, 30])
+            ]
+        );
+        assert!(parse_csv("wall,120,120\n").is_err());
+        assert!(parse_csv("wall,120,120,120\nfloor,a,b,c\n").is_err());
+    }
+
+    #[test]
+    fn selects_by_name_or_id() {
+        let labels = cityscapes();
+        assert_eq!(select("person, Rider", &labels), Ok(vec![11, 12]));
+        assert_eq!(select("13,bus,", &labels), Ok(vec![13, 15]));
+        assert_eq!(select("", &labels), Ok(vec![]));
+        assert!(select("person,unicorn", &labels).is_err());
+        assert!(select("19", &labels).is_err());
+    }
+
+    #[test]
+    fn defaults_to_cityscapes() {
+        let labels = resolve(&None).unwrap();
+        assert_eq!(labels.len(), 19);
+        assert_eq!(labels[13], Label::new("car", [0, 0, 142]));
+    }
+}
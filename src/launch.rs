@@ -7,7 +7,39 @@ use clap::{App, AppSettings, Arg};
 #[path = "runner.rs"]
 mod runner;
 
-fn launch_main(pipeline_str: String) {
+// Prints the PTS of a semseg-stats message and the coverage of every class present
+fn print_semseg_stats(stats: &gst::StructureRef) {
+    let pts = stats
+        .get::<gst::ClockTime>("pts")
+        .ok()
+        .flatten()
+        .unwrap_or(gst::CLOCK_TIME_NONE);
+    let classes = stats
+        .get::<gst::List>("classes")
+        .ok()
+        .flatten()
+        .map(|classes| {
+            classes
+                .as_slice()
+                .iter()
+                .filter_map(|class| class.get::<gst::Structure>().ok().flatten())
+                .map(|class| {
+                    let name = class
+                        .get::<String>("name")
+                        .ok()
+                        .flatten()
+                        .unwrap_or_default();
+                    let coverage = class.get_some::<f64>("coverage").unwrap_or(0.0);
+                    format!("{} {:.1}%", name, coverage * 100.0)
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        })
+        .unwrap_or_default();
+    println!("semseg-stats {}: {}", pts, classes);
+}
+
+fn launch_main(pipeline_str: String, semseg_stats: bool) {
     gst::init().unwrap();
 
     let mut context = gst::ParseContext::new();
@@ -30,35 +62,9 @@ fn launch_main(pipeline_str: String) {
         .set_state(gst::State::Playing)
         .expect("Unable to set the pipeline to the `Playing` state");
 
-    // let mut _last_face = gst::ClockTime(Some(0));
-
     for msg in bus.iter_timed(gst::CLOCK_TIME_NONE) {
         use gst::MessageView;
 
-        // let stc = msg.get_structure();
-        // if let Some(stc) = stc {
-        //     let name = stc.get_name();
-        //     if name == "facedetect" {
-        //         let faces = stc.get_value("faces");
-        //         if let Ok(faces) = faces {
-        //             let faces = faces.get::<gst::List>();
-        //             if let Ok(Some(faces)) = faces {
-        //                 let faces = faces.as_slice().len();
-        //                 if faces > 0 {
-        //                     let timestamp = stc
-        //                         .get_value("timestamp")
-        //                         .unwrap()
-        //                         .get::<gst::ClockTime>()
-        //                         .unwrap()
-        //                         .unwrap();
-        //                     _last_face = timestamp;
-        //                     println!("timestamp: {:?}", timestamp);
-        //                 }
-        //             }
-        //         }
-        //     }
-        // }
-
         match msg.view() {
             MessageView::Eos(..) => break,
             MessageView::Error(err) => {
@@ -70,6 +76,13 @@ fn launch_main(pipeline_str: String) {
                 );
                 break;
             }
+            MessageView::Element(element) => {
+                if let Some(stats) = element.get_structure() {
+                    if semseg_stats && stats.get_name() == "semseg-stats" {
+                        print_semseg_stats(stats);
+                    }
+                }
+            }
             _ => (),
         }
     }
@@ -82,14 +95,21 @@ fn launch_main(pipeline_str: String) {
 fn main() {
     let options = App::new("Simbotic")
         .setting(AppSettings::TrailingVarArg)
+        .arg(
+            Arg::with_name("semseg-stats")
+                .long("semseg-stats")
+                .help("Prints the semseg-stats messages posted on the bus"),
+        )
         .arg(Arg::with_name("pipeline").multiple(true))
         .get_matches();
 
+    let semseg_stats = options.is_present("semseg-stats");
+
     let pipeline: Vec<&str> = options.values_of("pipeline").unwrap().collect();
     let pipeline = pipeline.join(" ");
 
     let launch_handle = std::thread::spawn(move || {
-        runner::run(|| launch_main(pipeline));
+        runner::run(|| launch_main(pipeline, semseg_stats));
     });
 
     launch_handle.join().unwrap();
@@ -1,8 +1,10 @@
 extern crate glib;
+extern crate glib_sys;
 #[macro_use]
 extern crate gstreamer as gst;
 extern crate gstreamer_audio as gst_audio;
 extern crate gstreamer_base as gst_base;
+extern crate gstreamer_sys as gst_sys;
 extern crate gstreamer_video as gst_video;
 
 extern crate byte_slice_cast;
@@ -16,23 +18,38 @@ mod registry;
 
 extern crate rand;
 
+mod bgreplace;
 mod caps;
 mod cata;
+mod colormap;
+mod device;
 mod facepose;
+mod frame;
+mod labels;
+mod meta;
+mod models;
 mod monodepth;
+mod morphology;
 mod motiontransfer;
+mod overlay;
 mod render;
+mod resize;
 mod salientobject;
 mod semseg;
+mod torchfilter;
+mod worker;
 mod zipper;
 
 fn plugin_init(plugin: &gst::Plugin) -> Result<(), glib::BoolError> {
     python_init();
+    meta::register();
     cata::register::<monodepth::MonoDepth>(plugin)?;
     cata::register::<semseg::SemSeg>(plugin)?;
     cata::register::<motiontransfer::MotionTransfer>(plugin)?;
     cata::register::<facepose::FacePose>(plugin)?;
     cata::register::<salientobject::SalientObject>(plugin)?;
+    cata::register::<torchfilter::TorchFilter>(plugin)?;
+    cata::register::<bgreplace::BgReplace>(plugin)?;
     Ok(())
 }
 
@@ -0,0 +1,312 @@
+use std::ffi::{CStr, CString};
+use std::mem;
+use std::os::raw::c_char;
+use std::ptr;
+use std::slice;
+
+use glib;
+use glib::translate::{from_glib, ToGlib};
+use glib::{GEnum, StaticType};
+use glib_sys;
+use gst;
+use gst_sys;
+use tch;
+use tch::{TchError, Tensor};
+
+// Element type of an attached tensor
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, GEnum)]
+#[repr(u32)]
+#[genum(type_name = "SimboticTensorDType")]
+pub enum DType {
+    #[genum(name = "Unsigned 8-bit integer", nick = "uint8")]
+    Uint8 = 0,
+    #[genum(name = "Signed 8-bit integer", nick = "int8")]
+    Int8 = 1,
+    #[genum(name = "Signed 16-bit integer", nick = "int16")]
+    Int16 = 2,
+    #[genum(name = "Signed 32-bit integer", nick = "int32")]
+    Int32 = 3,
+    #[genum(name = "Signed 64-bit integer", nick = "int64")]
+    Int64 = 4,
+    #[genum(name = "16-bit float", nick = "float16")]
+    Float16 = 5,
+    #[genum(name = "32-bit float", nick = "float32")]
+    Float32 = 6,
+    #[genum(name = "64-bit float", nick = "float64")]
+    Float64 = 7,
+    #[genum(name = "Boolean, one byte each", nick = "bool")]
+    Bool = 8,
+}
+
+impl DType {
+    fn from_kind(kind: tch::Kind) -> Option<DType> {
+        match kind {
+            tch::Kind::Uint8 => Some(DType::Uint8),
+            tch::Kind::Int8 => Some(DType::Int8),
+            tch::Kind::Int16 => Some(DType::Int16),
+            tch::Kind::Int => Some(DType::Int32),
+            tch::Kind::Int64 => Some(DType::Int64),
+            tch::Kind::Half => Some(DType::Float16),
+            tch::Kind::Float => Some(DType::Float32),
+            tch::Kind::Double => Some(DType::Float64),
+            tch::Kind::Bool => Some(DType::Bool),
+            _ => None,
+        }
+    }
+
+    pub fn kind(self) -> tch::Kind {
+        match self {
+            DType::Uint8 => tch::Kind::Uint8,
+            DType::Int8 => tch::Kind::Int8,
+            DType::Int16 => tch::Kind::Int16,
+            DType::Int32 => tch::Kind::Int,
+            DType::Int64 => tch::Kind::Int64,
+            DType::Float16 => tch::Kind::Half,
+            DType::Float32 => tch::Kind::Float,
+            DType::Float64 => tch::Kind::Double,
+            DType::Bool => tch::Kind::Bool,
+        }
+    }
+}
+
+// Raw model output attached to a buffer, copied to host memory.
+// Registered as the "SimboticTensorMetaAPI" meta API, read it with `buffer.get_meta::<TensorMeta>()`.
+// Only C types are stored, so consumers outside this crate can read it as:
+//
+//   typedef struct {
+//     GstMeta meta;
+//     gchar *name;
+//     SimboticTensorDType dtype;  /* guint32, see DType */
+//     gint64 *shape;
+//     gsize n_dims;
+//     GBytes *data;               /* contiguous, native byte order */
+//   } SimboticTensorMeta;
+#[repr(C)]
+pub struct TensorMeta {
+    parent: gst_sys::GstMeta,
+    name: *mut c_char,
+    dtype: DType,
+    shape: *mut i64,
+    n_dims: usize,
+    data: *mut glib_sys::GBytes,
+}
+
+unsafe impl Send for TensorMeta {}
+unsafe impl Sync for TensorMeta {}
+
+unsafe impl gst::MetaAPI for TensorMeta {
+    type GstType = TensorMeta;
+
+    fn get_meta_api() -> glib::Type {
+        *META_API
+    }
+}
+
+impl TensorMeta {
+    // Attaches a copy of `tensor` to `buffer`, `name` tells consumers what it holds
+    pub fn add(buffer: &mut gst::BufferRef, name: &str, tensor: &Tensor) -> Result<(), TchError> {
+        let tensor = tensor.f_to_device(tch::Device::Cpu)?;
+        // Complex and quantized tensors have no C counterpart
+        let (tensor, dtype) = match DType::from_kind(tensor.kind()) {
+            Some(dtype) => (tensor, dtype),
+            None => (tensor.f_to_kind(tch::Kind::Float)?, DType::Float32),
+        };
+        let tensor = tensor.f_contiguous()?;
+        let numel = tensor.numel();
+        let mut data = vec![0u8; numel * tensor.kind().elt_size_in_bytes()];
+        tensor.f_copy_data_u8(&mut data, numel)?;
+        let name = CString::new(name).unwrap_or_default();
+        let shape = tensor.size();
+        unsafe {
+            let bytes = glib_sys::g_bytes_new(data.as_ptr() as glib_sys::gconstpointer, data.len());
+            add_meta(
+                buffer.as_mut_ptr(),
+                &Params {
+                    name: name.as_ptr(),
+                    dtype,
+                    shape: shape.as_ptr(),
+                    n_dims: shape.len(),
+                    data: bytes,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    pub fn name(&self) -> &str {
+        unsafe { CStr::from_ptr(self.name) }.to_str().unwrap_or("")
+    }
+
+    pub fn dtype(&self) -> DType {
+        self.dtype
+    }
+
+    pub fn kind(&self) -> tch::Kind {
+        self.dtype.kind()
+    }
+
+    pub fn shape(&self) -> &[i64] {
+        if self.n_dims == 0 {
+            return &[];
+        }
+        unsafe { slice::from_raw_parts(self.shape, self.n_dims) }
+    }
+
+    // Contiguous tensor data in native byte order
+    pub fn data(&self) -> &[u8] {
+        unsafe {
+            let mut size = 0;
+            let data = glib_sys::g_bytes_get_data(self.data, &mut size);
+            if data.is_null() {
+                return &[];
+            }
+            slice::from_raw_parts(data as *const u8, size)
+        }
+    }
+
+    pub fn tensor(&self) -> Tensor {
+        Tensor::of_data_size(self.data(), self.shape(), self.kind())
+    }
+}
+
+pub fn param_spec(name: &str) -> glib::ParamSpec {
+    glib::ParamSpec::boolean(
+        name,
+        "Attach tensor",
+        "Attach the raw model output to output buffers as SimboticTensorMeta",
+        false,
+        glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+    )
+}
+
+// Registers the meta API and implementation, called when the plugin loads
+pub fn register() {
+    lazy_static::initialize(&META_INFO);
+    DType::static_type();
+}
+
+// Fields copied into a new meta by `tensor_meta_init`, which takes over the `data` reference
+struct Params {
+    name: *const c_char,
+    dtype: DType,
+    shape: *const i64,
+    n_dims: usize,
+    data: *mut glib_sys::GBytes,
+}
+
+struct MetaInfo(ptr::NonNull<gst_sys::GstMetaInfo>);
+
+unsafe impl Send for MetaInfo {}
+unsafe impl Sync for MetaInfo {}
+
+lazy_static! {
+    static ref META_API: glib::Type = unsafe {
+        let mut tags = [ptr::null::<std::os::raw::c_char>()];
+        from_glib(gst_sys::gst_meta_api_type_register(
+            b"SimboticTensorMetaAPI\0".as_ptr() as *const _,
+            tags.as_mut_ptr() as *mut *const _,
+        ))
+    };
+    static ref META_INFO: MetaInfo = unsafe {
+        MetaInfo(
+            ptr::NonNull::new(gst_sys::gst_meta_register(
+                META_API.to_glib(),
+                b"SimboticTensorMeta\0".as_ptr() as *const _,
+                mem::size_of::<TensorMeta>(),
+                Some(tensor_meta_init),
+                Some(tensor_meta_free),
+                Some(tensor_meta_transform),
+            ) as *mut gst_sys::GstMetaInfo)
+            .expect("Failed to register SimboticTensorMeta"),
+        )
+    };
+}
+
+unsafe fn add_meta(buffer: *mut gst_sys::GstBuffer, params: &Params) {
+    gst_sys::gst_buffer_add_meta(
+        buffer,
+        META_INFO.0.as_ptr(),
+        params as *const Params as glib_sys::gpointer,
+    );
+}
+
+unsafe extern "C" fn tensor_meta_init(
+    meta: *mut gst_sys::GstMeta,
+    params: glib_sys::gpointer,
+    _buffer: *mut gst_sys::GstBuffer,
+) -> glib_sys::gboolean {
+    let meta = &mut *(meta as *mut TensorMeta);
+    let params = &*(params as *const Params);
+    let shape = glib_sys::g_malloc(params.n_dims * mem::size_of::<i64>()) as *mut i64;
+    if params.n_dims > 0 {
+        ptr::copy_nonoverlapping(params.shape, shape, params.n_dims);
+    }
+    ptr::write(&mut meta.name, glib_sys::g_strdup(params.name));
+    ptr::write(&mut meta.dtype, params.dtype);
+    ptr::write(&mut meta.shape, shape);
+    ptr::write(&mut meta.n_dims, params.n_dims);
+    ptr::write(&mut meta.data, params.data);
+    glib_sys::GTRUE
+}
+
+unsafe extern "C" fn tensor_meta_free(
+    meta: *mut gst_sys::GstMeta,
+    _buffer: *mut gst_sys::GstBuffer,
+) {
+    let meta = &mut *(meta as *mut TensorMeta);
+    glib_sys::g_free(meta.name as glib_sys::gpointer);
+    glib_sys::g_free(meta.shape as glib_sys::gpointer);
+    glib_sys::g_bytes_unref(meta.data);
+}
+
+// Tensors follow buffer copies, sharing the immutable data, other transformations would
+// invalidate them
+unsafe extern "C" fn tensor_meta_transform(
+    dest: *mut gst_sys::GstBuffer,
+    meta: *mut gst_sys::GstMeta,
+    _buffer: *mut gst_sys::GstBuffer,
+    type_: glib_sys::GQuark,
+    _data: glib_sys::gpointer,
+) -> glib_sys::gboolean {
+    if type_ != glib::Quark::from_string("gst-copy").to_glib() {
+        return glib_sys::GFALSE;
+    }
+    let meta = &*(meta as *const TensorMeta);
+    add_meta(
+        dest,
+        &Params {
+            name: meta.name,
+            dtype: meta.dtype,
+            shape: meta.shape,
+            n_dims: meta.n_dims,
+            data: glib_sys::g_bytes_ref(meta.data),
+        },
+    );
+    glib_sys::GTRUE
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn tensor_follows_buffer_copies() {
+        gst::init().unwrap();
+        let tensor = Tensor::of_slice(&[1f32, 2., 3., 4., 5., 6.]).view((1, 2, 3));
+        let mut buffer = gst::Buffer::new();
+        TensorMeta::add(buffer.get_mut().unwrap(), "logits", &tensor).unwrap();
+
+        let copy = buffer.copy();
+        let meta = copy.get_meta::<TensorMeta>().unwrap();
+        assert_eq!(meta.name(), "logits");
+        assert_eq!(meta.kind(), tch::Kind::Float);
+        assert_eq!(meta.dtype(), DType::Float32);
+        assert_eq!(meta.shape(), &[1, 2, 3]);
+        assert_eq!(meta.data().len(), 6 * 4);
+        assert_eq!(
+            Vec::<f32>::from(meta.tensor().flatten(0, -1)),
+            vec![1., 2., 3., 4., 5., 6.]
+        );
+    }
+}
@@ -0,0 +1,137 @@
+use std::env;
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+
+use glib;
+use gst;
+use tch;
+use tch::Tensor;
+
+// A TorchScript module loaded by one element, which holds on to the file it was read from
+pub struct Module {
+    module: Mutex<tch::CModule>,
+    _file: Arc<Vec<u8>>,
+}
+
+impl Deref for Module {
+    type Target = Mutex<tch::CModule>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.module
+    }
+}
+
+pub type Model = Arc<Module>;
+
+lazy_static! {
+    // Model files read by live modules, keyed by path
+    static ref CACHE: Mutex<Vec<(PathBuf, Weak<Vec<u8>>)>> = Mutex::new(Vec::new());
+}
+
+// Resolves a model location from its property, falling back to
+// `$SIMBOTIC_TORCH/models/<default>` when the property isn't set
+pub fn resolve(path: &Option<String>, default: &str) -> Result<PathBuf, gst::ErrorMessage> {
+    match path {
+        Some(path) => Ok(PathBuf::from(path)),
+        None => match env::var("SIMBOTIC_TORCH") {
+            Ok(root) => Ok(Path::new(&root).join("models").join(default)),
+            Err(_) => Err(gst_error_msg!(
+                gst::ResourceError::NotFound,
+                [
+                    "No model path set and SIMBOTIC_TORCH is not defined, can't locate {}",
+                    default
+                ]
+            )),
+        },
+    }
+}
+
+fn check_exists(path: &Path) -> Result<(), gst::ErrorMessage> {
+    if path.is_file() {
+        Ok(())
+    } else {
+        Err(gst_error_msg!(
+            gst::ResourceError::NotFound,
+            ["Model file {} not found", path.display()]
+        ))
+    }
+}
+
+// Model file, reusing the one already read by another element while any of its modules lives
+fn read(path: &Path) -> Result<Arc<Vec<u8>>, gst::ErrorMessage> {
+    let mut cache = CACHE.lock().unwrap();
+    cache.retain(|(_, file)| file.strong_count() > 0);
+    let cached = cache
+        .iter()
+        .filter(|(cached_path, _)| cached_path == path)
+        .find_map(|(_, file)| file.upgrade());
+    if let Some(file) = cached {
+        return Ok(file);
+    }
+
+    let file = Arc::new(fs::read(path).map_err(|err| {
+        gst_error_msg!(
+            gst::ResourceError::OpenRead,
+            ["Failed to read model {}", path.display()],
+            ["{}", err]
+        )
+    })?);
+    cache.push((path.to_path_buf(), Arc::downgrade(&file)));
+    Ok(file)
+}
+
+// Loads a model for one element. Every element runs its own module, so instances don't wait
+// on each other, while the file is only read once for elements using the same weights.
+pub fn load(path: &Path, device: tch::Device) -> Result<Model, gst::ErrorMessage> {
+    check_exists(path)?;
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+
+    let file = read(&path)?;
+    let module =
+        tch::CModule::load_data_on_device(&mut file.as_slice(), device).map_err(|err| {
+            gst_error_msg!(
+                gst::ResourceError::OpenRead,
+                ["Failed to load model {}", path.display()],
+                ["{}", err]
+            )
+        })?;
+    Ok(Arc::new(Module {
+        module: Mutex::new(module),
+        _file: file,
+    }))
+}
+
+pub fn read_npy(path: &Path, device: tch::Device) -> Result<Tensor, gst::ErrorMessage> {
+    check_exists(path)?;
+    Tensor::read_npy(path)
+        .map(|tensor| tensor.to_device(device))
+        .map_err(|err| {
+            gst_error_msg!(
+                gst::ResourceError::OpenRead,
+                ["Failed to read {}", path.display()],
+                ["{}", err]
+            )
+        })
+}
+
+pub fn path_param_spec(name: &str) -> glib::ParamSpec {
+    glib::ParamSpec::string(
+        name,
+        "Model path",
+        "TorchScript model file, defaults to the one under $SIMBOTIC_TORCH/models",
+        None,
+        glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_READY,
+    )
+}
+
+pub fn dir_param_spec(name: &str) -> glib::ParamSpec {
+    glib::ParamSpec::string(
+        name,
+        "Model directory",
+        "Directory holding the model files, defaults to the one under $SIMBOTIC_TORCH/models",
+        None,
+        glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_READY,
+    )
+}
@@ -1,12 +1,18 @@
-use std::env;
 use std::i32;
 use std::sync::Mutex;
 
 use crate::caps;
 use crate::cata;
+use crate::colormap;
+use crate::device;
+use crate::frame;
+use crate::meta;
+use crate::models;
 use crate::registry;
+use crate::resize;
 
 use glib::subclass;
+use glib::{GEnum, StaticType, ToValue};
 use gst;
 use gst_video;
 
@@ -29,9 +35,124 @@ fn tensor_map_range(
     Ok(mapped.clamp(f64::from(out_a), f64::from(out_b)))
 }
 
+// Disparity range of one frame, the whole range or clipped to percentiles in 0..100
+fn frame_range(depth: &Tensor, normalize: Normalize, percentiles: (f64, f64)) -> (f32, f32) {
+    let range = match normalize {
+        Normalize::Percentile => {
+            let values = depth.flatten(0, -1);
+            let last = (values.size()[0] - 1) as f64;
+            let at = |percentile: f64| {
+                let idx = (percentile.max(0.0).min(100.0) / 100.0 * last).round() as i64;
+                values.kthvalue(idx + 1, 0, false).0
+            };
+            Tensor::stack(&[at(percentiles.0), at(percentiles.1)], 0)
+        }
+        _ => Tensor::stack(&[depth.min(), depth.max()], 0),
+    };
+    // One copy to the host for both ends
+    let range = Vec::<f32>::from(range.to_kind(tch::Kind::Float).to_device(tch::Device::Cpu));
+    (range[0], range[1])
+}
+
+// Monodepth2's disp_to_depth, sigmoid disparity to depth between min_depth and max_depth
+fn disp_to_depth(disp: &Tensor, min_depth: f64, max_depth: f64) -> Tensor {
+    let min_disp = 1.0 / max_depth;
+    let max_disp = 1.0 / min_depth;
+    (disp * (max_disp - min_disp) + min_disp).reciprocal()
+}
+
+// Normalized vertical intrinsics Monodepth2 was trained with
+const TRAINING_FY: f64 = 1.92;
+const TRAINING_CY: f64 = 0.5;
+
+// Weight of the newest frame in the smoothed camera height scale
+const SCALE_ALPHA: f64 = 0.05;
+
+// Height of a level camera above the ground seen in the bottom rows of the input in a
+// network resolution Tensor[[1, 1, H, W], Float] depth, with the training intrinsics
+fn camera_height(depth: &Tensor, resize: &resize::Resize) -> f64 {
+    // Letterbox padding isn't part of the picture
+    let ((top, height), (left, width)) = resize.network_region();
+    let (fy, cy) = resize.network_intrinsics(TRAINING_FY, TRAINING_CY);
+    let rows = (height / 10).max(1);
+    let bottom = top + height;
+    let offsets = (bottom - rows..bottom)
+        .map(|v| ((v as f64 + 0.5 - cy) / fy) as f32)
+        .collect::<Vec<f32>>();
+    let offsets = Tensor::of_slice(&offsets)
+        .view((1, 1, rows, 1))
+        .to_device(depth.device());
+    let ground =
+        depth
+            .narrow(2, bottom - rows, rows)
+            .narrow(3, left + width / 4, (width / 2).max(1));
+    f64::from((ground * offsets).median())
+}
+
+// Baseline of the stereo pairs Monodepth2 was trained with, in model units
+const TRAINING_BASELINE: f64 = 0.1;
+
 const WIDTH: i32 = 640;
 const HEIGHT: i32 = 192;
 
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, GEnum)]
+#[repr(u32)]
+#[genum(type_name = "SimboticDepthRawOutput")]
+pub enum RawOutput {
+    #[genum(name = "Disparity: sigmoid decoder output", nick = "disparity")]
+    Disparity = 0,
+    #[genum(name = "Depth: disp_to_depth times the depth scale", nick = "depth")]
+    Depth = 1,
+}
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, GEnum)]
+#[repr(u32)]
+#[genum(type_name = "SimboticDepthScale")]
+pub enum DepthScale {
+    #[genum(name = "None: relative depth", nick = "none")]
+    None = 0,
+    #[genum(name = "Fixed: times scale-factor", nick = "fixed")]
+    Fixed = 1,
+    #[genum(name = "Stereo: from stereo-baseline", nick = "stereo")]
+    Stereo = 2,
+    #[genum(name = "Camera height: from camera-height", nick = "camera-height")]
+    CameraHeight = 3,
+}
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, GEnum)]
+#[repr(u32)]
+#[genum(type_name = "SimboticDepthNormalize")]
+pub enum Normalize {
+    #[genum(name = "EMA: min/max smoothed over frames by ema-alpha", nick = "ema")]
+    Ema = 0,
+    #[genum(name = "Per frame: min/max of every frame", nick = "per-frame")]
+    PerFrame = 1,
+    #[genum(name = "Fixed: normalize-min to normalize-max", nick = "fixed")]
+    Fixed = 2,
+    #[genum(name = "Percentile: clipped range of every frame", nick = "percentile")]
+    Percentile = 3,
+}
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, GEnum)]
+#[repr(u32)]
+#[genum(type_name = "SimboticDisparityFormat")]
+pub enum DisparityFormat {
+    #[genum(name = "GRAY16_LE: disparity scaled to 0..65535", nick = "gray16-le")]
+    Gray16Le = 0,
+    #[genum(name = "Float32: raw disparity tensor", nick = "float32")]
+    Float32 = 1,
+}
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, GEnum)]
+#[repr(u32)]
+#[genum(type_name = "SimboticDisparityResolution")]
+pub enum DisparityResolution {
+    #[genum(name = "Network: 640x192 as the decoder outputs it", nick = "network")]
+    Network = 0,
+    #[genum(name = "Input: mapped back to the input frame size", nick = "input")]
+    Input = 1,
+}
+
 lazy_static! {
     static ref CAPS: Mutex<gst::Caps> = Mutex::new(gst::Caps::new_simple(
         "video/x-raw",
@@ -40,29 +161,274 @@ lazy_static! {
                 "format",
                 &gst::List::new(&[&gst_video::VideoFormat::Rgb.to_str()]),
             ),
-            ("width", &WIDTH),
-            ("height", &HEIGHT),
+            ("width", &gst::IntRange::<i32>::new(1, i32::MAX)),
+            ("height", &gst::IntRange::<i32>::new(1, i32::MAX)),
             (
                 "framerate",
                 &gst::FractionRange::new(gst::Fraction::new(0, 1), gst::Fraction::new(i32::MAX, 1),),
             ),
         ],
     ));
-    static ref ENCODER_MODEL: Mutex<tch::CModule> = Mutex::new(
-        tch::CModule::load(env::var("SIMBOTIC_TORCH").unwrap() + "/models/monodepth/encoder.pt")
-            .unwrap()
-    );
-    static ref DECODER_MODEL: Mutex<tch::CModule> = Mutex::new(
-        tch::CModule::load(env::var("SIMBOTIC_TORCH").unwrap() + "/models/monodepth/decoder.pt")
-            .unwrap()
-    );
+    static ref CAPS_DISPARITY: Mutex<gst::Caps> = Mutex::new({
+        let mut caps = gst::Caps::new_simple(
+            "video/x-raw",
+            &[
+                (
+                    "format",
+                    &gst::List::new(&[&gst_video::VideoFormat::Gray16Le.to_str()]),
+                ),
+                ("width", &gst::IntRange::<i32>::new(1, i32::MAX)),
+                ("height", &gst::IntRange::<i32>::new(1, i32::MAX)),
+                (
+                    "framerate",
+                    &gst::FractionRange::new(
+                        gst::Fraction::new(0, 1),
+                        gst::Fraction::new(i32::MAX, 1),
+                    ),
+                ),
+            ],
+        );
+        caps.get_mut().unwrap().append(gst::Caps::new_simple(
+            caps::TENSOR_CAPS,
+            &[("dtype", &"float32")],
+        ));
+        caps
+    });
 }
 
+// Metadata for the properties
+static PROPERTIES: [subclass::Property; 25] = [
+    subclass::Property("device", |name| device::param_spec(name)),
+    subclass::Property("model-dir", |name| models::dir_param_spec(name)),
+    subclass::Property("resize-mode", |name| resize::param_spec(name)),
+    subclass::Property("attach-tensor", |name| meta::param_spec(name)),
+    subclass::Property("disparity-format", |name| {
+        glib::ParamSpec::enum_(
+            name,
+            "Disparity format",
+            "Format of the raw disparity on the disparity pad",
+            DisparityFormat::static_type(),
+            DisparityFormat::Gray16Le as i32,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_READY,
+        )
+    }),
+    subclass::Property("disparity-resolution", |name| {
+        glib::ParamSpec::enum_(
+            name,
+            "Disparity resolution",
+            "Resolution of the raw disparity on the disparity pad",
+            DisparityResolution::static_type(),
+            DisparityResolution::Input as i32,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_READY,
+        )
+    }),
+    subclass::Property("colormap", |name| colormap::param_spec(name)),
+    subclass::Property("colormap-file", |name| colormap::file_param_spec(name)),
+    subclass::Property("invert", |name| colormap::invert_param_spec(name)),
+    subclass::Property("normalize", |name| {
+        glib::ParamSpec::enum_(
+            name,
+            "Normalize",
+            "How the disparity range mapped onto the colormap is chosen",
+            Normalize::static_type(),
+            Normalize::Ema as i32,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+    subclass::Property("ema-alpha", |name| {
+        glib::ParamSpec::double(
+            name,
+            "EMA alpha",
+            "Weight of the newest frame when smoothing the range",
+            0.0,
+            1.0,
+            0.1,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+    subclass::Property("normalize-min", |name| {
+        glib::ParamSpec::double(
+            name,
+            "Normalize min",
+            "Disparity mapped to the first color in fixed mode, min-depth sets the raw depth instead",
+            std::f64::MIN,
+            std::f64::MAX,
+            0.0,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+    subclass::Property("normalize-max", |name| {
+        glib::ParamSpec::double(
+            name,
+            "Normalize max",
+            "Disparity mapped to the last color in fixed mode, max-depth sets the raw depth instead",
+            std::f64::MIN,
+            std::f64::MAX,
+            1.0,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+    subclass::Property("percentile-low", |name| {
+        glib::ParamSpec::double(
+            name,
+            "Percentile low",
+            "Percentile mapped to the first color in percentile mode",
+            0.0,
+            100.0,
+            2.0,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+    subclass::Property("percentile-high", |name| {
+        glib::ParamSpec::double(
+            name,
+            "Percentile high",
+            "Percentile mapped to the last color in percentile mode",
+            0.0,
+            100.0,
+            98.0,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+    subclass::Property("range-min", |name| {
+        glib::ParamSpec::double(
+            name,
+            "Range min",
+            "Disparity mapped to the first color of the latest frame",
+            std::f64::MIN,
+            std::f64::MAX,
+            0.0,
+            glib::ParamFlags::READABLE,
+        )
+    }),
+    subclass::Property("range-max", |name| {
+        glib::ParamSpec::double(
+            name,
+            "Range max",
+            "Disparity mapped to the last color of the latest frame",
+            std::f64::MIN,
+            std::f64::MAX,
+            1.0,
+            glib::ParamFlags::READABLE,
+        )
+    }),
+    subclass::Property("raw-output", |name| {
+        glib::ParamSpec::enum_(
+            name,
+            "Raw output",
+            "Values on the disparity pad and in the attached tensor",
+            RawOutput::static_type(),
+            RawOutput::Disparity as i32,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_READY,
+        )
+    }),
+    subclass::Property("min-depth", |name| {
+        glib::ParamSpec::double(
+            name,
+            "Min depth",
+            "Depth of the largest disparity, before scaling; normalize-min sets the colormap instead",
+            std::f64::MIN_POSITIVE,
+            std::f64::MAX,
+            0.1,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+    subclass::Property("max-depth", |name| {
+        glib::ParamSpec::double(
+            name,
+            "Max depth",
+            "Depth of the smallest disparity, before scaling; normalize-max sets the colormap instead",
+            std::f64::MIN_POSITIVE,
+            std::f64::MAX,
+            100.0,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+    subclass::Property("depth-scale", |name| {
+        glib::ParamSpec::enum_(
+            name,
+            "Depth scale",
+            "How the depth is scaled to meters",
+            DepthScale::static_type(),
+            DepthScale::None as i32,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+    subclass::Property("scale-factor", |name| {
+        glib::ParamSpec::double(
+            name,
+            "Scale factor",
+            "Meters per depth unit in fixed depth scale",
+            0.0,
+            std::f64::MAX,
+            1.0,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+    subclass::Property("depth-step", |name| {
+        glib::ParamSpec::double(
+            name,
+            "Depth step",
+            "Meters per gray16-le depth value, 0.001 saturates at 65.535 meters",
+            0.0001,
+            std::f64::MAX,
+            0.001,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+    subclass::Property("stereo-baseline", |name| {
+        glib::ParamSpec::double(
+            name,
+            "Stereo baseline",
+            "Baseline in meters of the stereo rig the model was trained on",
+            0.0,
+            std::f64::MAX,
+            0.54,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+    subclass::Property("camera-height", |name| {
+        glib::ParamSpec::double(
+            name,
+            "Camera height",
+            "Height in meters of the camera over the ground",
+            0.0,
+            std::f64::MAX,
+            1.65,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+];
+
 pub struct MonoDepth {
     video_info: gst_video::VideoInfo,
-    color_map: Tensor, // Tensor[[3, 1, 728], Uint8]
-    depth_min: f32,
+    resize_mode: resize::ResizeMode,
+    device: tch::Device,
+    model_dir: Option<String>,
+    encoder: Option<models::Model>,
+    decoder: Option<models::Model>,
+    colormap: colormap::Colormap,
+    colormap_file: Option<String>,
+    invert: bool,
+    color_map: Option<Tensor>, // Tensor[[3, 1, N], Uint8]
+    normalize: Normalize,
+    ema_alpha: f64,
+    fixed_range: (f64, f64), // (normalize-min, normalize-max)
+    percentiles: (f64, f64),
+    depth_min: f32, // Range in use
     depth_max: f32,
+    attach_tensor: bool,
+    disparity_linked: bool,
+    disparity_format: DisparityFormat,
+    disparity_resolution: DisparityResolution,
+    raw_output: RawOutput,
+    depth_range: (f64, f64), // (min-depth, max-depth)
+    depth_scale: DepthScale,
+    scale_factor: f64,
+    depth_step: f64,
+    stereo_baseline: f64,
+    camera_height: f64,
+    height_scale: Option<f64>, // camera-height over the estimated one, smoothed over frames
 }
 
 impl registry::Registry for MonoDepth {
@@ -71,7 +437,7 @@ impl registry::Registry for MonoDepth {
     register_typedata!();
 
     fn properties() -> &'static [glib::subclass::Property<'static>] {
-        &[]
+        &PROPERTIES
     }
 }
 
@@ -81,13 +447,33 @@ impl std::default::Default for MonoDepth {
         caps.fixate();
         MonoDepth {
             video_info: gst_video::VideoInfo::from_caps(&caps).unwrap(),
-            color_map: tch::vision::image::load(
-                env::var("SIMBOTIC_TORCH").unwrap() + "/assets/magma.png",
-            )
-            .unwrap()
-            .to_device(tch::Device::Cuda(0)),
+            resize_mode: resize::ResizeMode::Stretch,
+            device: device::default(),
+            model_dir: None,
+            encoder: None,
+            decoder: None,
+            colormap: colormap::Colormap::Magma,
+            colormap_file: None,
+            invert: false,
+            color_map: None,
+            normalize: Normalize::Ema,
+            ema_alpha: 0.1,
+            fixed_range: (0.0, 1.0),
+            percentiles: (2.0, 98.0),
             depth_min: 0f32,
             depth_max: 1f32,
+            attach_tensor: false,
+            disparity_linked: true,
+            disparity_format: DisparityFormat::Gray16Le,
+            disparity_resolution: DisparityResolution::Input,
+            raw_output: RawOutput::Disparity,
+            depth_range: (0.1, 100.0),
+            depth_scale: DepthScale::None,
+            scale_factor: 1.0,
+            depth_step: 0.001,
+            stereo_baseline: 0.54,
+            camera_height: 1.65,
+            height_scale: None,
         }
     }
 }
@@ -102,123 +488,590 @@ impl caps::CapsDef for MonoDepth {
             name: "depth",
             caps: CAPS.lock().unwrap().clone(),
         };
-        (vec![in_caps], vec![out_caps])
+        let disparity_caps = caps::PadCaps {
+            name: "disparity",
+            caps: CAPS_DISPARITY.lock().unwrap().clone(),
+        };
+        (vec![in_caps], vec![out_caps, disparity_caps])
     }
 }
 
-impl cata::Process for MonoDepth {
-    fn process(
-        &mut self,
-        inbuf: &Vec<gst::Buffer>,
-        outbuf: &mut Vec<gst::Buffer>,
-    ) -> Result<(), std::io::Error> {
-        for (i, buf) in inbuf.iter().enumerate() {
-            if i < outbuf.len() {
-                outbuf[i] = buf.clone();
+impl MonoDepth {
+    fn resize(&self) -> resize::Resize {
+        resize::Resize::new(
+            self.resize_mode,
+            (
+                self.video_info.height() as i64,
+                self.video_info.width() as i64,
+            ),
+            (HEIGHT as i64, WIDTH as i64),
+        )
+    }
+
+    // Tensor[[1, 3, 192, 640], Float] from an RGB buffer
+    fn input(
+        &self,
+        inbuf: &gst::Buffer,
+        resize: &resize::Resize,
+    ) -> Result<Tensor, cata::ProcessError> {
+        let in_frame = frame::map_readable(inbuf.as_ref(), &self.video_info)?;
+        let img = frame::to_tensor(&in_frame)
+            .f_to_device(self.device)?
+            .permute(&[2, 0, 1])
+            .to_kind(tch::Kind::Float)
+            / 255;
+        Ok(resize.to_network(&img.unsqueeze(0)))
+    }
+
+    // Tensor[[N, 1, 192, 640], Float] disparity of a batch of input tensors
+    fn infer(&self, img: Tensor) -> Result<Tensor, cata::ProcessError> {
+        let i_img: tch::IValue = tch::IValue::Tensor(img);
+        let encoder_output = self
+            .encoder
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .forward_is(&[i_img])?;
+        let enc_tensors = match &encoder_output {
+            tch::IValue::Tuple(enc_tensors) if enc_tensors.len() == 5 => enc_tensors,
+            _ => {
+                return Err(cata::ProcessError::Shape(
+                    "Encoder didn't output a tuple of 5 features".to_string(),
+                ))
             }
+        };
+
+        let depth_outputs = self
+            .decoder
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .forward_is(&[
+                &enc_tensors[0],
+                &enc_tensors[1],
+                &enc_tensors[2],
+                &enc_tensors[3],
+                &enc_tensors[4],
+            ])?;
+
+        if let tch::IValue::Tuple(tensors) = &depth_outputs {
+            if let Some(tch::IValue::Tensor(tensor)) = tensors.first() {
+                return Ok(tensor.shallow_clone());
+            }
+        };
+        Err(cata::ProcessError::Shape(
+            "Decoder didn't output a depth tensor".to_string(),
+        ))
+    }
+
+    // (height, width) of the raw disparity
+    fn disparity_size(&self) -> (i64, i64) {
+        match self.disparity_resolution {
+            DisparityResolution::Network => (HEIGHT as i64, WIDTH as i64),
+            DisparityResolution::Input => (
+                self.video_info.height() as i64,
+                self.video_info.width() as i64,
+            ),
+        }
+    }
+
+    // GRAY16_LE frames on the disparity pad
+    fn disparity_info(&self) -> Option<gst_video::VideoInfo> {
+        let (height, width) = self.disparity_size();
+        gst_video::VideoInfo::builder(
+            gst_video::VideoFormat::Gray16Le,
+            width as u32,
+            height as u32,
+        )
+        .fps(self.video_info.fps())
+        .build()
+        .ok()
+    }
+
+    // Scale of a depth from camera-height, smoothed over frames so metric depth doesn't flicker.
+    // Frames without a usable ground keep the previous scale.
+    fn height_scale(&mut self, depth: &Tensor, resize: &resize::Resize) -> f64 {
+        let estimate = self.camera_height / camera_height(depth, resize);
+        if estimate.is_finite() && estimate > 0.0 {
+            self.height_scale = Some(match self.height_scale {
+                Some(scale) => lerp(scale, estimate, SCALE_ALPHA),
+                None => estimate,
+            });
+        }
+        self.height_scale.unwrap_or(1.0)
+    }
+
+    // Disparity or depth in meters of one Tensor[[1, 1, 192, 640], Float] decoder output
+    fn raw(&mut self, depth_output: &Tensor, resize: &resize::Resize) -> Tensor {
+        if self.raw_output == RawOutput::Disparity {
+            return depth_output.shallow_clone();
+        }
+        let depth = disp_to_depth(depth_output, self.depth_range.0, self.depth_range.1);
+        let scale = match self.depth_scale {
+            DepthScale::None => 1.0,
+            DepthScale::Fixed => self.scale_factor,
+            DepthScale::Stereo => self.stereo_baseline / TRAINING_BASELINE,
+            DepthScale::CameraHeight => self.height_scale(&depth, resize),
+        };
+        depth * scale
+    }
+
+    fn raw_name(&self) -> &'static str {
+        match self.raw_output {
+            RawOutput::Disparity => "disparity",
+            RawOutput::Depth => "depth",
         }
+    }
 
-        let mut depth_buf = inbuf[0].copy();
+    // Buffer for the disparity pad from a raw network resolution output,
+    // timestamped like its input buffer
+    fn disparity(
+        &self,
+        inbuf: &gst::Buffer,
+        raw: &Tensor,
+        resize: &resize::Resize,
+    ) -> Result<gst::Buffer, cata::ProcessError> {
+        let disparity = match self.disparity_resolution {
+            DisparityResolution::Network => raw.shallow_clone(),
+            DisparityResolution::Input => resize.from_network(raw),
+        };
+        let mut disparity_buf = match self.disparity_format {
+            DisparityFormat::Float32 => frame::to_buffer(&disparity)?,
+            DisparityFormat::Gray16Le => {
+                let (height, width) = self.disparity_size();
+                let info = self.disparity_info().ok_or_else(|| {
+                    cata::ProcessError::Shape(format!("No GRAY16_LE frame of {}x{}", width, height))
+                })?;
+                // Sigmoid disparity spans 0..1, depth is stored in depth-step units
+                let values = match self.raw_output {
+                    RawOutput::Disparity => disparity.clamp(0.0, 1.0) * 65535.0,
+                    RawOutput::Depth => (disparity / self.depth_step).clamp(0.0, 65535.0),
+                };
+                // 0..65535 wraps into Int16 with the bits of the u16, little-endian on the host
+                let values = values
+                    .round()
+                    .f_to_kind(tch::Kind::Int)?
+                    .f_to_kind(tch::Kind::Int16)?
+                    .f_view((height, width))?;
+                let mut buf = gst::Buffer::with_size(info.size()).map_err(|_| {
+                    cata::ProcessError::Mapping("Failed to allocate buffer".to_string())
+                })?;
+                {
+                    let mut out_frame = frame::map_writable(buf.get_mut().unwrap(), &info)?;
+                    frame::copy_to_frame16(&values, &mut out_frame)?;
+                }
+                buf
+            }
+        };
         {
-            let rgb_ref = inbuf[0].as_ref();
-            let in_frame =
-                gst_video::VideoFrameRef::from_buffer_ref_readable(rgb_ref, &self.video_info)
-                    .unwrap();
-            let _in_stride = in_frame.plane_stride()[0] as usize;
-            let _in_format = in_frame.format();
-            let in_width = in_frame.width() as i32;
-            let in_height = in_frame.height() as i32;
-            let in_data = in_frame.plane_data(0).unwrap();
+            let in_ref = inbuf.as_ref();
+            let out_ref = disparity_buf.get_mut().unwrap();
+            out_ref.set_pts(in_ref.get_pts());
+            out_ref.set_dts(in_ref.get_dts());
+            out_ref.set_offset(in_ref.get_offset());
+            out_ref.set_duration(in_ref.get_duration());
+        }
+        Ok(disparity_buf)
+    }
 
+    // Colorizes one Tensor[[1, 1, 192, 640], Float] disparity into a copy of its input buffer
+    fn colorize(
+        &mut self,
+        inbuf: &gst::Buffer,
+        depth_output: &Tensor,
+        resize: &resize::Resize,
+    ) -> Result<gst::Buffer, cata::ProcessError> {
+        let mut depth_buf = inbuf.copy();
+        {
             let depth_ref = depth_buf.get_mut().unwrap();
-            let mut out_frame =
-                gst_video::VideoFrameRef::from_buffer_ref_writable(depth_ref, &self.video_info)
-                    .unwrap();
-            let _out_stride = out_frame.plane_stride()[0] as usize;
-            let _out_format = out_frame.format();
-            let out_data = out_frame.plane_data_mut(0).unwrap();
-
-            let img_slice = unsafe { std::slice::from_raw_parts(in_data.as_ptr(), in_data.len()) };
-            let img = Tensor::of_data_size(
-                img_slice,
-                &[in_height as i64, in_width as i64, 3],
-                tch::Kind::Uint8,
-            )
-            .to_device(tch::Device::Cuda(0))
-            .permute(&[2, 0, 1])
-            .to_kind(tch::Kind::Float)
-                / 255;
-            let i_img: tch::IValue = tch::IValue::Tensor(img.unsqueeze(0));
-            let encoder_output = ENCODER_MODEL.lock().unwrap().forward_is(&[i_img]).unwrap();
-            let enc_tensors = match &encoder_output {
-                tch::IValue::Tuple(enc_tensors) => Some(enc_tensors),
-                _ => None,
-            }
-            .unwrap();
+            let mut out_frame = frame::map_writable(depth_ref, &self.video_info)?;
 
-            let depth_outputs = DECODER_MODEL
-                .lock()
-                .unwrap()
-                .forward_is(&[
-                    &enc_tensors[0],
-                    &enc_tensors[1],
-                    &enc_tensors[2],
-                    &enc_tensors[3],
-                    &enc_tensors[4],
-                ])
-                .unwrap();
-
-            // Tensor[[1, 1, 192, 640], Float]
-            let mut depth_output = None;
-            if let tch::IValue::Tuple(tensors) = &depth_outputs {
-                if let tch::IValue::Tensor(tensor) = &tensors[0] {
-                    depth_output = Some(tensor);
+            // The range is measured at network resolution, over the picture without padding
+            let ((top, height), (left, width)) = resize.network_region();
+            let network_depth = depth_output.narrow(2, top, height).narrow(3, left, width);
+            // Tensor[[1, 1, H, W], Float] at input resolution
+            let depth_output = resize.from_network(depth_output);
+            let (depth_min, depth_max) = match self.normalize {
+                Normalize::Fixed => (self.fixed_range.0 as f32, self.fixed_range.1 as f32),
+                Normalize::Ema => {
+                    let (depth_min, depth_max) =
+                        frame_range(&network_depth, self.normalize, self.percentiles);
+                    let alpha = self.ema_alpha as f32;
+                    (
+                        lerp(self.depth_min, depth_min, alpha),
+                        lerp(self.depth_max, depth_max, alpha),
+                    )
                 }
+                _ => frame_range(&network_depth, self.normalize, self.percentiles),
             };
+            self.depth_min = depth_min;
+            // Flat frames would divide by zero
+            self.depth_max = depth_max.max(depth_min + std::f32::EPSILON);
 
-            let depth_output = depth_output.unwrap();
-            let depth_min = depth_output.min();
-            let depth_max = depth_output.max();
-            self.depth_min = lerp(self.depth_min, f32::from(depth_min), 0.1f32);
-            self.depth_max = lerp(self.depth_max, f32::from(depth_max), 0.1f32);
-
-            let depth_min = Tensor::from(self.depth_min).to_device(tch::Device::Cuda(0));
-            let depth_max = Tensor::from(self.depth_max).to_device(tch::Device::Cuda(0));
-            let depth_map_min = Tensor::from(0f64).to_device(tch::Device::Cuda(0));
-            let depth_map_max = Tensor::from(1f64).to_device(tch::Device::Cuda(0));
+            let depth_min = Tensor::from(self.depth_min).to_device(self.device);
+            let depth_max = Tensor::from(self.depth_max).to_device(self.device);
+            let depth_map_min = Tensor::from(0f64).to_device(self.device);
+            let depth_map_max = Tensor::from(1f64).to_device(self.device);
             let depth_output = tensor_map_range(
-                depth_output,
+                &depth_output,
                 &depth_min,
                 &depth_max,
                 &depth_map_min,
                 &depth_map_max,
-            )
-            .unwrap();
+            )?;
 
+            let color_map = self.color_map.as_ref().unwrap();
             let color_index = depth_output
-                .f_mul(&Tensor::from(727f32))
-                .unwrap()
+                .f_mul(&Tensor::from((color_map.size()[2] - 1) as f32))?
                 .flatten(0, 3)
                 .to_kind(tch::Kind::Int64);
 
-            let depth_color = self
-                .color_map
-                .index_select(2, &color_index)
-                .permute(&[2, 1, 0])
-                .to_device(tch::Device::Cpu);
+            let depth_color = color_map
+                .f_index_select(2, &color_index)?
+                .permute(&[2, 1, 0]);
 
-            let depth_out = unsafe {
-                std::slice::from_raw_parts_mut(out_data.as_mut_ptr(), (WIDTH * HEIGHT * 3) as usize)
-            };
-            depth_color
-                .to_kind(tch::Kind::Uint8)
-                .copy_data(depth_out, (WIDTH * HEIGHT * 3) as usize);
+            frame::copy_to_frame(&depth_color.to_kind(tch::Kind::Uint8), &mut out_frame)?;
         }
+        Ok(depth_buf)
+    }
 
-        outbuf[0] = depth_buf;
+    // Colorized and raw buffers of one Tensor[[1, 1, 192, 640], Float] disparity
+    fn outputs(
+        &mut self,
+        inbuf: &gst::Buffer,
+        depth_output: &Tensor,
+        resize: &resize::Resize,
+        outbuf: &mut Vec<gst::Buffer>,
+    ) -> Result<(), cata::ProcessError> {
+        let raw = self.raw(depth_output, resize);
+        outbuf[0] = self.colorize(inbuf, depth_output, resize)?;
+        if self.attach_tensor {
+            meta::TensorMeta::add(outbuf[0].get_mut().unwrap(), self.raw_name(), &raw)?;
+        }
+        if self.disparity_linked {
+            outbuf[1] = self.disparity(inbuf, &raw, resize)?;
+        }
+        Ok(())
+    }
+}
+
+impl cata::Process for MonoDepth {
+    fn process(
+        &mut self,
+        inbuf: &Vec<gst::Buffer>,
+        outbuf: &mut Vec<gst::Buffer>,
+    ) -> Result<(), cata::ProcessError> {
+        let resize = self.resize();
+        let img = self.input(&inbuf[0], &resize)?;
+        let depth_output = self.infer(img)?;
+        self.outputs(&inbuf[0], &depth_output, &resize, outbuf)
+    }
+
+    // One forward pass over the stacked frames, results keep the buffers' timestamps
+    fn process_batch(
+        &mut self,
+        inbufs: &[Vec<gst::Buffer>],
+        outbufs: &mut Vec<Vec<gst::Buffer>>,
+    ) -> Result<(), cata::ProcessError> {
+        let resize = self.resize();
+        let imgs = inbufs
+            .iter()
+            .map(|inbuf| self.input(&inbuf[0], &resize))
+            .collect::<Result<Vec<Tensor>, cata::ProcessError>>()?;
+        let depth_outputs = self.infer(Tensor::f_cat(&imgs, 0)?)?;
+        for (i, (inbuf, outbuf)) in inbufs.iter().zip(outbufs.iter_mut()).enumerate() {
+            let depth_output = depth_outputs.f_narrow(0, i as i64, 1)?;
+            self.outputs(&inbuf[0], &depth_output, &resize, outbuf)?;
+        }
+        Ok(())
+    }
+
+    fn set_property(&mut self, property: &subclass::Property, value: &glib::Value) {
+        match property {
+            subclass::Property("device", ..) => {
+                self.device = device::from_value(value, self.device);
+            }
+            subclass::Property("model-dir", ..) => {
+                self.model_dir = value.get().expect("model directory");
+            }
+            subclass::Property("resize-mode", ..) => {
+                self.resize_mode = value.get_some().expect("resize mode");
+                self.height_scale = None;
+            }
+            subclass::Property("attach-tensor", ..) => {
+                self.attach_tensor = value.get_some().expect("attach tensor");
+            }
+            subclass::Property("disparity-format", ..) => {
+                self.disparity_format = value.get_some().expect("disparity format");
+            }
+            subclass::Property("disparity-resolution", ..) => {
+                self.disparity_resolution = value.get_some().expect("disparity resolution");
+            }
+            subclass::Property("colormap", ..) => {
+                self.colormap = value.get_some().expect("colormap");
+            }
+            subclass::Property("colormap-file", ..) => {
+                self.colormap_file = value.get().expect("colormap file");
+            }
+            subclass::Property("invert", ..) => {
+                self.invert = value.get_some().expect("invert");
+            }
+            subclass::Property("normalize", ..) => {
+                self.normalize = value.get_some().expect("normalize");
+            }
+            subclass::Property("ema-alpha", ..) => {
+                self.ema_alpha = value.get_some().expect("EMA alpha");
+            }
+            subclass::Property("normalize-min", ..) => {
+                self.fixed_range.0 = value.get_some().expect("normalize min");
+            }
+            subclass::Property("normalize-max", ..) => {
+                self.fixed_range.1 = value.get_some().expect("normalize max");
+            }
+            subclass::Property("percentile-low", ..) => {
+                self.percentiles.0 = value.get_some().expect("percentile low");
+            }
+            subclass::Property("percentile-high", ..) => {
+                self.percentiles.1 = value.get_some().expect("percentile high");
+            }
+            subclass::Property("raw-output", ..) => {
+                self.raw_output = value.get_some().expect("raw output");
+            }
+            subclass::Property("min-depth", ..) => {
+                self.depth_range.0 = value.get_some().expect("min depth");
+            }
+            subclass::Property("max-depth", ..) => {
+                self.depth_range.1 = value.get_some().expect("max depth");
+            }
+            subclass::Property("depth-scale", ..) => {
+                self.depth_scale = value.get_some().expect("depth scale");
+            }
+            subclass::Property("scale-factor", ..) => {
+                self.scale_factor = value.get_some().expect("scale factor");
+            }
+            subclass::Property("depth-step", ..) => {
+                self.depth_step = value.get_some().expect("depth step");
+            }
+            subclass::Property("stereo-baseline", ..) => {
+                self.stereo_baseline = value.get_some().expect("stereo baseline");
+            }
+            subclass::Property("camera-height", ..) => {
+                self.camera_height = value.get_some().expect("camera height");
+                self.height_scale = None;
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    fn get_property(&self, property: &subclass::Property) -> glib::Value {
+        match property {
+            subclass::Property("device", ..) => device::to_value(self.device),
+            subclass::Property("model-dir", ..) => self.model_dir.to_value(),
+            subclass::Property("resize-mode", ..) => self.resize_mode.to_value(),
+            subclass::Property("attach-tensor", ..) => self.attach_tensor.to_value(),
+            subclass::Property("disparity-format", ..) => self.disparity_format.to_value(),
+            subclass::Property("disparity-resolution", ..) => self.disparity_resolution.to_value(),
+            subclass::Property("colormap", ..) => self.colormap.to_value(),
+            subclass::Property("colormap-file", ..) => self.colormap_file.to_value(),
+            subclass::Property("invert", ..) => self.invert.to_value(),
+            subclass::Property("normalize", ..) => self.normalize.to_value(),
+            subclass::Property("ema-alpha", ..) => self.ema_alpha.to_value(),
+            subclass::Property("normalize-min", ..) => self.fixed_range.0.to_value(),
+            subclass::Property("normalize-max", ..) => self.fixed_range.1.to_value(),
+            subclass::Property("percentile-low", ..) => self.percentiles.0.to_value(),
+            subclass::Property("percentile-high", ..) => self.percentiles.1.to_value(),
+            subclass::Property("range-min", ..) => (self.depth_min as f64).to_value(),
+            subclass::Property("range-max", ..) => (self.depth_max as f64).to_value(),
+            subclass::Property("raw-output", ..) => self.raw_output.to_value(),
+            subclass::Property("min-depth", ..) => self.depth_range.0.to_value(),
+            subclass::Property("max-depth", ..) => self.depth_range.1.to_value(),
+            subclass::Property("depth-scale", ..) => self.depth_scale.to_value(),
+            subclass::Property("scale-factor", ..) => self.scale_factor.to_value(),
+            subclass::Property("depth-step", ..) => self.depth_step.to_value(),
+            subclass::Property("stereo-baseline", ..) => self.stereo_baseline.to_value(),
+            subclass::Property("camera-height", ..) => self.camera_height.to_value(),
+            _ => unimplemented!(),
+        }
+    }
+
+    fn set_caps(&mut self, _sink: usize, caps: &gst::Caps) -> bool {
+        match gst_video::VideoInfo::from_caps(caps) {
+            Ok(video_info) => {
+                self.video_info = video_info;
+                self.height_scale = None;
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    // The colorized depth matches the input resolution, the disparity follows its properties
+    fn src_caps(&self, src: usize) -> Option<gst::Caps> {
+        if src == 0 {
+            return self.video_info.to_caps().ok();
+        }
+        let (height, width) = self.disparity_size();
+        match self.disparity_format {
+            DisparityFormat::Float32 => Some(caps::tensor_caps(&[1, 1, height, width])),
+            DisparityFormat::Gray16Le => self.disparity_info()?.to_caps().ok(),
+        }
+    }
 
+    fn src_linked(&mut self, src: usize, linked: bool) {
+        if src == 1 {
+            self.disparity_linked = linked;
+        }
+    }
+
+    fn prepare(&mut self) -> Result<(), gst::ErrorMessage> {
+        let model_dir = models::resolve(&self.model_dir, "monodepth")?;
+        self.encoder = Some(models::load(&model_dir.join("encoder.pt"), self.device)?);
+        self.decoder = Some(models::load(&model_dir.join("decoder.pt"), self.device)?);
+
+        self.color_map = Some(
+            colormap::lut(self.colormap, &self.colormap_file, self.invert)?.to_device(self.device),
+        );
         Ok(())
     }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn gray16_disparity_spans_u16() {
+        gst::init().unwrap();
+        let mut monodepth = MonoDepth::default();
+        monodepth.disparity_resolution = DisparityResolution::Network;
+        let raw = Tensor::full(
+            &[1, 1, HEIGHT as i64, WIDTH as i64],
+            0.25,
+            (tch::Kind::Float, tch::Device::Cpu),
+        );
+        let _ = raw.get(0).get(0).get(0).get(0).fill_(1.0);
+        let resize = monodepth.resize();
+        let buf = monodepth
+            .disparity(&gst::Buffer::new(), &raw, &resize)
+            .unwrap();
+        let map = buf.map_readable().unwrap();
+        assert_eq!(map.len(), (WIDTH * HEIGHT * 2) as usize);
+        assert_eq!(&map[..4], &[0xff, 0xff, 0x00, 0x40]);
+    }
+
+    #[test]
+    fn gray16_depth_in_depth_steps() {
+        gst::init().unwrap();
+        let mut monodepth = MonoDepth::default();
+        monodepth.disparity_resolution = DisparityResolution::Network;
+        monodepth.raw_output = RawOutput::Depth;
+        monodepth.depth_step = 0.002;
+        let raw = Tensor::full(
+            &[1, 1, HEIGHT as i64, WIDTH as i64],
+            100.0,
+            (tch::Kind::Float, tch::Device::Cpu),
+        );
+        let resize = monodepth.resize();
+        let buf = monodepth
+            .disparity(&gst::Buffer::new(), &raw, &resize)
+            .unwrap();
+        let map = buf.map_readable().unwrap();
+        // 100 meters in 2 millimeter steps
+        assert_eq!(&map[..2], &50000u16.to_le_bytes());
+    }
+
+    #[test]
+    fn percentile_clips_outliers() {
+        let mut values = vec![0.5f32; 100];
+        values[0] = -10.0;
+        values[99] = 10.0;
+        values[1] = 0.2;
+        values[98] = 0.8;
+        let depth = Tensor::of_slice(&values).view((1, 1, 10, 10));
+        assert_eq!(
+            frame_range(&depth, Normalize::PerFrame, (2.0, 98.0)),
+            (-10.0, 10.0)
+        );
+        assert_eq!(
+            frame_range(&depth, Normalize::Percentile, (1.0, 99.0)),
+            (0.2, 0.8)
+        );
+        assert_eq!(
+            frame_range(&depth, Normalize::Percentile, (0.0, 100.0)),
+            (-10.0, 10.0)
+        );
+    }
 
-    fn set_property(&mut self, _property: &subclass::Property, _value: &glib::Value) {}
+    #[test]
+    fn disp_to_depth_spans_depth_range() {
+        let disp = Tensor::of_slice(&[0f32, 1.0]);
+        let depth = Vec::<f32>::from(disp_to_depth(&disp, 0.1, 100.0));
+        assert!((depth[0] - 100.0).abs() < 1e-3);
+        assert!((depth[1] - 0.1).abs() < 1e-6);
+    }
+
+    // Network resolution depth of a flat ground 2 units below a level camera, sky above the
+    // horizon, 0 outside the `rows` showing the input
+    fn flat_ground(rows: std::ops::Range<i64>, fy: f64, cy: f64) -> Tensor {
+        let (height, width) = (HEIGHT as i64, WIDTH as i64);
+        let depth = (0..height)
+            .map(|v| {
+                let offset = (v as f64 + 0.5 - cy) / fy;
+                if !rows.contains(&v) {
+                    0.0
+                } else if offset > 0.0 {
+                    (2.0 / offset) as f32
+                } else {
+                    100.0
+                }
+            })
+            .collect::<Vec<f32>>();
+        Tensor::of_slice(&depth)
+            .view((1, 1, height, 1))
+            .expand(&[1, 1, height, width], false)
+    }
+
+    #[test]
+    fn camera_height_from_flat_ground() {
+        let height = HEIGHT as i64;
+        let resize = resize::Resize::new(
+            resize::ResizeMode::Stretch,
+            (height, WIDTH as i64),
+            (height, WIDTH as i64),
+        );
+        let depth = flat_ground(0..height, 1.92 * height as f64, 0.5 * height as f64);
+        assert!((camera_height(&depth, &resize) - 2.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn camera_height_skips_letterbox_padding() {
+        // A 640x96 input is padded by 48 rows above and below
+        let resize = resize::Resize::new(
+            resize::ResizeMode::Letterbox,
+            (96, WIDTH as i64),
+            (HEIGHT as i64, WIDTH as i64),
+        );
+        let depth = flat_ground(48..144, 1.92 * 96.0, 48.0 + 0.5 * 96.0);
+        assert!((camera_height(&depth, &resize) - 2.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn height_scale_is_smoothed() {
+        let height = HEIGHT as i64;
+        let resize = resize::Resize::new(
+            resize::ResizeMode::Stretch,
+            (height, WIDTH as i64),
+            (height, WIDTH as i64),
+        );
+        let mut monodepth = MonoDepth::default();
+        monodepth.camera_height = 2.0;
+        let depth = flat_ground(0..height, 1.92 * height as f64, 0.5 * height as f64);
+        assert!((monodepth.height_scale(&depth, &resize) - 1.0).abs() < 1e-3);
+        let farther = depth * 2.0;
+        let scale = monodepth.height_scale(&farther, &resize);
+        assert!((scale - lerp(1.0, 0.5, SCALE_ALPHA)).abs() < 1e-3);
+    }
 }
@@ -0,0 +1,243 @@
+use tch;
+use tch::Tensor;
+
+// Binary morphology on Tensor[[1, 1, H, W], Float] masks of 0s and 1s, with square
+// structuring elements so it runs as pooling on the mask's device
+
+pub fn dilate(mask: &Tensor, radius: i64) -> Tensor {
+    if radius <= 0 {
+        return mask.shallow_clone();
+    }
+    let size = 2 * radius + 1;
+    mask.max_pool2d(&[size, size], &[1, 1], &[radius, radius], &[1, 1], false)
+}
+
+pub fn erode(mask: &Tensor, radius: i64) -> Tensor {
+    if radius <= 0 {
+        return mask.shallow_clone();
+    }
+    // Outside the frame counts as foreground, so blobs touching the border don't shrink from it
+    1.0 - dilate(&(1.0 - mask), radius)
+}
+
+// Tensor[[1, 1, H, W], Float] 1 where a Tensor[[1, 1, H, W]] of values has a different
+// value in its 3x3 neighbourhood, the frame border is not an edge
+pub fn edges(values: &Tensor) -> Tensor {
+    let values = values.to_kind(tch::Kind::Float);
+    let max = values.max_pool2d(&[3, 3], &[1, 1], &[1, 1], &[1, 1], false);
+    let min = -(-values).max_pool2d(&[3, 3], &[1, 1], &[1, 1], &[1, 1], false);
+    (max - min).gt(0.0).to_kind(tch::Kind::Float)
+}
+
+// Label propagation rounds between convergence checks, and at most
+const STEPS: usize = 4;
+const MAX_ROUNDS: usize = 64;
+// Pointer jumps per round
+const JUMPS: usize = 2;
+
+// Tensor[[1, 1, H, W], Float] 8-connected components of a mask, background is 0 and
+// every blob is labeled by its largest pixel index + 1, exact up to 2^24 pixels.
+// Every round spreads labels over the 3x3 neighbourhood, then replaces them by the label of
+// the pixel they point at, so they travel along a blob much faster than pooling alone.
+// Rounds are capped, so a blob too winding to converge in time can stay split in a few labels.
+pub fn components(mask: &Tensor) -> Tensor {
+    let size = mask.size();
+    let mask = mask.gt(0.0).to_kind(tch::Kind::Float);
+    let flat_mask = mask.flatten(0, -1);
+    let mut labels = (Tensor::arange(size[2] * size[3], (tch::Kind::Float, mask.device())) + 1.0)
+        .view((1, 1, size[2], size[3]))
+        * &mask;
+    for round in 1..=MAX_ROUNDS {
+        let mut next = labels.max_pool2d(&[3, 3], &[1, 1], &[1, 1], &[1, 1], false) * &mask;
+        // The pixel a label points at belongs to the same blob and never has a smaller label
+        for _ in 0..JUMPS {
+            let flat = next.flatten(0, -1);
+            let pointers = (&flat - 1.0).clamp_min(0.0).to_kind(tch::Kind::Int64);
+            next = (flat.index_select(0, &pointers) * &flat_mask).view_as(&labels);
+        }
+        let converged = round % STEPS == 0 && next.equal(&labels);
+        labels = next;
+        if converged {
+            break;
+        }
+    }
+    labels
+}
+
+// Pixel count of the blob every pixel of a components() labeling belongs to, 0 on background
+pub fn areas(labels: &Tensor) -> Tensor {
+    let flat = labels.flatten(0, -1).to_kind(tch::Kind::Int64);
+    let counts = flat.bincount::<Tensor>(None, 0).index_fill(
+        0,
+        &Tensor::of_slice(&[0i64]).to_device(flat.device()),
+        0,
+    );
+    counts.index_select(0, &flat).view_as(labels)
+}
+
+// Tensor[[K, 6], Double] area, left, top, width, height and mean of `values` of the K blobs
+// of a components() labeling with at least `min_area` pixels, in label order.
+// Sorting the pixels by label makes every blob one run, so its bounds are the ends of the run
+// and nothing is read back per blob.
+pub fn blobs(labels: &Tensor, values: &Tensor, min_area: i64) -> Tensor {
+    let size = labels.size();
+    let width = size[size.len() - 1];
+    let numel = labels.numel() as i64;
+    let labels = labels.flatten(0, -1).to_kind(tch::Kind::Int64);
+    let index = Tensor::arange(numel, (tch::Kind::Int64, labels.device()));
+    // Within a blob, pixels sorted by index go top to bottom and by column left to right
+    let by_index = (&labels * numel + &index).sort(0, false).0.remainder(numel);
+    let by_column = (&labels * width + index.remainder(width))
+        .sort(0, false)
+        .0
+        .remainder(width);
+
+    let areas = labels.bincount::<Tensor>(None, numel + 1);
+    let ends = areas.cumsum(0, tch::Kind::Int64);
+    let ids = areas
+        .narrow(0, 1, numel)
+        .ge(min_area)
+        .nonzero()
+        .squeeze_dim(1)
+        + 1;
+    let area = areas.index_select(0, &ids);
+    let first = ends.index_select(0, &ids) - &area;
+    let last = ends.index_select(0, &ids) - 1;
+
+    let top = by_index.index_select(0, &first).floor_divide_scalar(width);
+    let bottom = by_index.index_select(0, &last).floor_divide_scalar(width);
+    let left = by_column.index_select(0, &first);
+    let right = by_column.index_select(0, &last);
+    let sums = labels.bincount(
+        Some(values.flatten(0, -1).to_kind(tch::Kind::Double)),
+        numel + 1,
+    );
+    let mean = sums.index_select(0, &ids) / area.to_kind(tch::Kind::Double);
+    Tensor::stack(
+        &[
+            area.to_kind(tch::Kind::Double),
+            left.to_kind(tch::Kind::Double),
+            top.to_kind(tch::Kind::Double),
+            (right - left + 1).to_kind(tch::Kind::Double),
+            (bottom - top + 1).to_kind(tch::Kind::Double),
+            mean,
+        ],
+        1,
+    )
+}
+
+// Drops the blobs of a mask smaller than `min_area` pixels
+pub fn remove_small(mask: &Tensor, min_area: i64) -> Tensor {
+    if min_area <= 1 {
+        return mask.shallow_clone();
+    }
+    let areas = areas(&components(mask));
+    areas.ge(min_area).to_kind(mask.kind()) * mask
+}
+
+// Left, top, width and height in pixels of the nonzero pixels of a Tensor[[H, W]],
+// None when there are none
+pub fn bbox(mask: &Tensor) -> Option<[i64; 4]> {
+    let rows = mask.any_dim(1, false).nonzero();
+    let cols = mask.any_dim(0, false).nonzero();
+    if rows.numel() == 0 {
+        return None;
+    }
+    let top = rows.min().int64_value(&[]);
+    let left = cols.min().int64_value(&[]);
+    let bottom = rows.max().int64_value(&[]);
+    let right = cols.max().int64_value(&[]);
+    Some([left, top, right - left + 1, bottom - top + 1])
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn mask(rows: &[&str]) -> Tensor {
+        let values = rows
+            .iter()
+            .flat_map(|row| row.chars().map(|c| if c == '#' { 1f32 } else { 0f32 }))
+            .collect::<Vec<f32>>();
+        Tensor::of_slice(&values).view((1, 1, rows.len() as i64, rows[0].len() as i64))
+    }
+
+    #[test]
+    fn dilate_and_erode() {
+        let point = mask(&[".....", ".....", "..#..", ".....", "....."]);
+        let square = mask(&[".....", ".###.", ".###.", ".###.", "....."]);
+        assert!(dilate(&point, 1).equal(&square));
+        assert!(erode(&square, 1).equal(&point));
+        assert!(erode(&point, 1).equal(&mask(&[".....", ".....", ".....", ".....", "....."])));
+    }
+
+    #[test]
+    fn edges_between_values() {
+        let square = mask(&[".....", ".###.", ".###.", ".###.", "....."]);
+        let ring = mask(&["#####", "#####", "##.##", "#####", "#####"]);
+        assert!(edges(&square).equal(&ring));
+        assert!(edges(&mask(&["###", "###"])).equal(&mask(&["...", "..."])));
+    }
+
+    #[test]
+    fn boxes_nonzero_pixels() {
+        let blob = mask(&[".....", "..#..", ".##..", "....."]).view((4, 5));
+        assert_eq!(bbox(&blob), Some([1, 1, 2, 2]));
+        assert_eq!(bbox(&blob.zeros_like()), None);
+    }
+
+    #[test]
+    fn labels_connected_blobs() {
+        let blobs = mask(&["##...", "#...#", "...##", "#....", "....#"]);
+        let labels = components(&blobs);
+        let values = Vec::<f32>::from(labels.flatten(0, -1));
+        // Diagonal neighbours belong to the same blob
+        assert_eq!(values[0], values[5]);
+        assert_eq!(values[9], values[13]);
+        assert_ne!(values[0], values[9]);
+        assert_eq!(values[2], 0.0);
+        let areas = Vec::<i64>::from(areas(&labels).flatten(0, -1));
+        assert_eq!(areas[0], 3);
+        assert_eq!(areas[9], 3);
+        assert_eq!(areas[15], 1);
+        assert_eq!(areas[2], 0);
+    }
+
+    #[test]
+    fn labels_winding_blob() {
+        let snake = mask(&[
+            "#########",
+            "........#",
+            "#########",
+            "#........",
+            "#########",
+            "........#",
+            "#########",
+        ]);
+        let labels = components(&snake);
+        let values = Vec::<f32>::from(labels.masked_select(&snake.gt(0.0)));
+        assert!(values.iter().all(|&label| label == values[0]));
+        assert_eq!(values[0], 63.0);
+    }
+
+    #[test]
+    fn measures_blobs() {
+        let blobs_mask = mask(&["##...", "#...#", "...##", "#....", "....#"]);
+        let values = blobs_mask.shallow_clone() * 0.5;
+        let stats = blobs(&components(&blobs_mask), &values, 2);
+        assert_eq!(stats.size(), [2, 6]);
+        assert_eq!(
+            Vec::<f64>::from(stats.flatten(0, -1)),
+            vec![3., 0., 0., 2., 2., 0.5, 3., 3., 1., 2., 2., 0.5]
+        );
+    }
+
+    #[test]
+    fn removes_small_blobs() {
+        let blobs = mask(&["##...", "#...#", "...##", "#....", "....#"]);
+        let kept = mask(&["##...", "#...#", "...##", ".....", "....."]);
+        assert!(remove_small(&blobs, 2).equal(&kept));
+        assert!(remove_small(&blobs, 1).equal(&blobs));
+    }
+}
@@ -1,21 +1,65 @@
-use std::env;
 use std::i32;
 use std::sync::Mutex;
 
 use crate::caps;
 use crate::cata;
+use crate::device;
+use crate::frame;
+use crate::models;
 use crate::registry;
 
 use glib::subclass;
+use glib::{GEnum, StaticType, ToValue};
 use gst;
 use gst_video;
 
 use tch;
 use tch::Tensor;
 
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, GEnum)]
+#[repr(u32)]
+#[genum(type_name = "SimboticSourceMode")]
+pub enum SourceMode {
+    #[genum(name = "Still: the source-image file", nick = "still")]
+    Still = 0,
+    #[genum(
+        name = "First frame: first frame of the source pad",
+        nick = "first-frame"
+    )]
+    FirstFrame = 1,
+    #[genum(
+        name = "Continuous: latest frame of the source pad",
+        nick = "continuous"
+    )]
+    Continuous = 2,
+}
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, GEnum)]
+#[repr(u32)]
+#[genum(type_name = "SimboticCalibrateOn")]
+pub enum CalibrateOn {
+    #[genum(name = "First frame: first driving frame", nick = "first-frame")]
+    FirstFrame = 0,
+    #[genum(name = "Manual: on the recalibrate signal", nick = "manual")]
+    Manual = 1,
+    #[genum(name = "Auto: best aligned of the first frames", nick = "auto")]
+    Auto = 2,
+}
+
+// Driving frames considered by an auto calibration
+const AUTO_CALIBRATION_FRAMES: u32 = 30;
+
 const WIDTH: i32 = 256;
 const HEIGHT: i32 = 256;
 
+lazy_static! {
+    static ref CAT: gst::DebugCategory = gst::DebugCategory::new(
+        "motiontransfer",
+        gst::DebugColorFlags::empty(),
+        Some("Motion transfer"),
+    );
+}
+
 lazy_static! {
     static ref CAPS: Mutex<gst::Caps> = Mutex::new(gst::Caps::new_simple(
         "video/x-raw",
@@ -32,37 +76,71 @@ lazy_static! {
             ),
         ],
     ));
-    static ref DETECTOR_MODEL: Mutex<tch::CModule> = Mutex::new(
-        tch::CModule::load(
-            env::var("SIMBOTIC_TORCH").unwrap() + "/models/motiontransfer/detector.pt"
-        )
-        .unwrap()
-    );
-    static ref GENERATOR_MODEL: Mutex<tch::CModule> = Mutex::new(
-        tch::CModule::load(
-            env::var("SIMBOTIC_TORCH").unwrap() + "/models/motiontransfer/generator.pt"
-        )
-        .unwrap()
-    );
 }
 
 // Metadata for the properties
-static PROPERTIES: [subclass::Property; 1] = [subclass::Property("source-image", |name| {
-    glib::ParamSpec::string(
-        name,
-        "Source image",
-        "Source image to be driven",
-        None,
-        glib::ParamFlags::READWRITE,
-    )
-})];
+static PROPERTIES: [subclass::Property; 6] = [
+    subclass::Property("source-image", |name| {
+        glib::ParamSpec::string(
+            name,
+            "Source image",
+            "Source image to be driven",
+            None,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+    subclass::Property("device", |name| device::param_spec(name)),
+    subclass::Property("model-dir", |name| models::dir_param_spec(name)),
+    subclass::Property("source-mode", |name| {
+        glib::ParamSpec::enum_(
+            name,
+            "Source mode",
+            "Where the driven source image comes from",
+            SourceMode::static_type(),
+            SourceMode::Still as i32,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+    subclass::Property("calibrate-on", |name| {
+        glib::ParamSpec::enum_(
+            name,
+            "Calibrate on",
+            "Which driving frames set the neutral pose",
+            CalibrateOn::static_type(),
+            CalibrateOn::FirstFrame as i32,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+    subclass::Property("calibration-file", |name| {
+        glib::ParamSpec::string(
+            name,
+            "Calibration file",
+            "Neutral pose loaded on start or when set, and saved after each calibration",
+            None,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+];
 
 pub struct MotionTransfer {
     video_info: gst_video::VideoInfo,
+    device: tch::Device,
+    model_dir: Option<String>,
+    source_image_path: Option<String>,
+    detector: Option<models::Model>,
+    generator: Option<models::Model>,
     source_image: Option<Tensor>, // Tensor[[3, 256, 256], Uint8]
-    source: Option<Tensor>,       // Tensor[[1, 3, 256, 256], Float]
+    source_mode: SourceMode,
+    source_frame: Option<gst::Buffer>, // Last frame taken from the source pad
+    live_source: Option<Tensor>,       // Tensor[[3, 256, 256], Uint8]
+    source: Option<Tensor>,            // Tensor[[1, 3, 256, 256], Float]
     kp_source: Option<(Tensor, Tensor)>,
     kp_driving_initial: Option<(Tensor, Tensor)>,
+    calibrate_on: CalibrateOn,
+    calibration_file: Option<String>,
+    calibrating: u32,       // Driving frames left to consider for the neutral pose
+    calibration_score: f64, // Alignment of the current neutral pose, lower is better
+    calibration_reference: Option<Tensor>, // Source keypoint values the calibration aligns to
 }
 
 impl registry::Registry for MotionTransfer {
@@ -73,6 +151,10 @@ impl registry::Registry for MotionTransfer {
     fn properties() -> &'static [glib::subclass::Property<'static>] {
         &PROPERTIES
     }
+
+    fn actions() -> &'static [&'static str] {
+        &["recalibrate"]
+    }
 }
 
 impl std::default::Default for MotionTransfer {
@@ -81,10 +163,23 @@ impl std::default::Default for MotionTransfer {
         caps.fixate();
         MotionTransfer {
             video_info: gst_video::VideoInfo::from_caps(&caps).unwrap(),
+            device: device::default(),
+            model_dir: None,
+            source_image_path: None,
+            detector: None,
+            generator: None,
             source_image: None,
+            source_mode: SourceMode::Still,
+            source_frame: None,
+            live_source: None,
             source: None,
             kp_source: None,
             kp_driving_initial: None,
+            calibrate_on: CalibrateOn::FirstFrame,
+            calibration_file: None,
+            calibrating: 1,
+            calibration_score: f64::INFINITY,
+            calibration_reference: None,
         }
     }
 }
@@ -95,145 +190,244 @@ impl caps::CapsDef for MotionTransfer {
             name: "rgb",
             caps: CAPS.lock().unwrap().clone(),
         };
+        let source_caps = caps::PadCaps {
+            name: "source",
+            caps: CAPS.lock().unwrap().clone(),
+        };
         let out_caps = caps::PadCaps {
             name: "transfer",
             caps: CAPS.lock().unwrap().clone(),
         };
-        (vec![in_caps], vec![out_caps])
+        (vec![in_caps, source_caps], vec![out_caps])
+    }
+
+    // The source pad can stay unlinked when driving a still source-image.
+    // A non-live source is paced by PTS against the driving stream.
+    fn sticky_sinks() -> &'static [&'static str] {
+        &["source"]
+    }
+}
+
+impl MotionTransfer {
+    // Takes the source image from a frame of the source pad according to source-mode,
+    // source keypoints are recomputed when it changes. A source in use stays frozen while
+    // calibrating against it.
+    fn update_source(&mut self, source_buf: &gst::Buffer) -> Result<(), cata::ProcessError> {
+        let frozen = self.calibrating > 0 && self.kp_source.is_some();
+        let take = match self.source_mode {
+            SourceMode::Still => false,
+            SourceMode::FirstFrame => self.source_frame.is_none(),
+            SourceMode::Continuous => {
+                !frozen
+                    && self
+                        .source_frame
+                        .as_ref()
+                        .map_or(true, |frame| frame.as_ptr() != source_buf.as_ptr())
+            }
+        };
+        // The source pad has an empty buffer until its first frame
+        if !take || source_buf.get_size() == 0 {
+            return Ok(());
+        }
+        let source_frame = frame::map_readable(source_buf.as_ref(), &self.video_info)?;
+        self.live_source = Some(
+            frame::to_tensor(&source_frame)
+                .f_to_device(self.device)?
+                .permute(&[2, 0, 1]),
+        );
+        self.source_frame = Some(source_buf.clone());
+        self.source = None;
+        self.kp_source = None;
+        Ok(())
+    }
+
+    // Keeps the driving keypoints as the neutral pose while calibrating. The first frame
+    // wins unless a later one aligns better with the source keypoints, or looks more neutral
+    // when there was no source yet as the calibration started.
+    fn calibrate(&mut self, kp_driving: &(Tensor, Tensor)) -> Result<(), cata::ProcessError> {
+        if self.calibrating == 0 {
+            return Ok(());
+        }
+        // Every frame of a calibration is scored against the same reference
+        if self.kp_driving_initial.is_none() {
+            self.calibration_reference = self.kp_source.as_ref().map(|(value, _)| value.copy());
+        }
+        let score = match &self.calibration_reference {
+            Some(reference) => alignment(&kp_driving.0, reference),
+            None => neutrality(&kp_driving.1),
+        };
+        if self.kp_driving_initial.is_none() || score < self.calibration_score {
+            self.kp_driving_initial = Some((kp_driving.0.copy(), kp_driving.1.copy()));
+            self.calibration_score = score;
+        }
+        self.calibrating -= 1;
+        if self.calibrating == 0 {
+            if let Err(err) = self.save_calibration() {
+                gst_warning!(CAT, "Failed to save the calibration: {}", err);
+            }
+            gst_debug!(CAT, "Calibrated with alignment {}", self.calibration_score);
+        }
+        Ok(())
+    }
+
+    fn save_calibration(&self) -> Result<(), tch::TchError> {
+        match (&self.calibration_file, &self.kp_driving_initial) {
+            (Some(path), Some((value, jacobian))) => {
+                Tensor::save_multi(&[("value", value), ("jacobian", jacobian)], path)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Loads an existing calibration-file. A missing or unreadable one leaves the calibration
+    // to the driving frames.
+    fn restore_calibration(&mut self) {
+        if let Some(path) = self.calibration_file.clone() {
+            if std::path::Path::new(&path).exists() {
+                if let Err(err) = self.load_calibration(&path) {
+                    gst_warning!(CAT, "Recalibrating: {:?}", err);
+                }
+            }
+        }
+    }
+
+    fn load_calibration(&mut self, path: &str) -> Result<(), gst::ErrorMessage> {
+        let tensors = Tensor::load_multi_with_device(path, self.device).map_err(|err| {
+            gst_error_msg!(
+                gst::ResourceError::OpenRead,
+                ["Failed to load calibration {}", path],
+                ["{}", err]
+            )
+        })?;
+        let find = |name: &str| {
+            tensors
+                .iter()
+                .find(|(n, _)| n.as_str() == name)
+                .map(|(_, t)| t.copy())
+        };
+        match (find("value"), find("jacobian")) {
+            (Some(value), Some(jacobian)) => {
+                self.kp_driving_initial = Some((value, jacobian));
+                self.calibration_score = f64::INFINITY;
+                self.calibrating = 0;
+                Ok(())
+            }
+            _ => Err(gst_error_msg!(
+                gst::ResourceError::OpenRead,
+                ["Calibration {} has no keypoint value and jacobian", path]
+            )),
+        }
     }
 }
 
+// Mean distance between keypoints once both sets are centered, so a performer standing
+// off to the side still calibrates well
+fn alignment(kp: &Tensor, reference: &Tensor) -> f64 {
+    let kp = kp - kp.mean_dim(&[-2], true, tch::Kind::Float);
+    let reference = reference - reference.mean_dim(&[-2], true, tch::Kind::Float);
+    let diff = kp - reference;
+    (&diff * &diff)
+        .sum_dim_intlist(&[-1], false, tch::Kind::Float)
+        .sqrt()
+        .mean(tch::Kind::Float)
+        .double_value(&[])
+}
+
+// Mean distance of the keypoint jacobians from preserving area, a driving-only measure
+// favoring a performer facing the camera over one turned away or leaning in
+fn neutrality(jacobian: &Tensor) -> f64 {
+    jacobian
+        .det()
+        .abs()
+        .log()
+        .abs()
+        .mean(tch::Kind::Float)
+        .double_value(&[])
+}
+
+// Keypoint values and jacobians detected in a [1, 3, H, W] frame
+fn keypoints(
+    detector: &tch::CModule,
+    frame: Tensor,
+) -> Result<(Tensor, Tensor), cata::ProcessError> {
+    let detector_output = detector.forward_is(&[tch::IValue::Tensor(frame)])?;
+    if let tch::IValue::Tuple(det_tensors) = &detector_output {
+        if let [tch::IValue::Tensor(value), tch::IValue::Tensor(jacobian), ..] = &det_tensors[..] {
+            return Ok((value.copy(), jacobian.copy()));
+        }
+    }
+    Err(cata::ProcessError::Shape(
+        "Keypoint detector didn't output values and jacobians".to_string(),
+    ))
+}
+
 impl cata::Process for MotionTransfer {
     fn process(
         &mut self,
         inbuf: &Vec<gst::Buffer>,
         outbuf: &mut Vec<gst::Buffer>,
-    ) -> Result<(), std::io::Error> {
+    ) -> Result<(), cata::ProcessError> {
         for (i, buf) in inbuf.iter().enumerate() {
             if i < outbuf.len() {
                 outbuf[i] = buf.clone();
             }
         }
 
+        if let Some(source_buf) = inbuf.get(1) {
+            self.update_source(source_buf)?;
+        }
+
         let mut driven_buf = inbuf[0].copy();
         {
             let rgb_ref = inbuf[0].as_ref();
-            let in_frame =
-                gst_video::VideoFrameRef::from_buffer_ref_readable(rgb_ref, &self.video_info)
-                    .unwrap();
-            let _in_stride = in_frame.plane_stride()[0] as usize;
-            let _in_format = in_frame.format();
-            let _in_width = in_frame.width() as i32;
-            let _in_height = in_frame.height() as i32;
-            let in_data = in_frame.plane_data(0).unwrap();
+            let in_frame = frame::map_readable(rgb_ref, &self.video_info)?;
 
             let driven_ref = driven_buf.get_mut().unwrap();
-            let mut out_frame =
-                gst_video::VideoFrameRef::from_buffer_ref_writable(driven_ref, &self.video_info)
-                    .unwrap();
-            let _out_stride = out_frame.plane_stride()[0] as usize;
-            let _out_format = out_frame.format();
-            let out_data = out_frame.plane_data_mut(0).unwrap();
-
-            let img_slice = unsafe {
-                std::slice::from_raw_parts(in_data.as_ptr(), (WIDTH * HEIGHT * 3) as usize)
-            };
-            let img_bytes = Tensor::of_data_size(
-                img_slice,
-                &[HEIGHT as i64, WIDTH as i64, 3],
-                tch::Kind::Uint8,
-            )
-            .to_device(tch::Device::Cuda(0))
-            .permute(&[2, 0, 1]);
+            let mut out_frame = frame::map_writable(driven_ref, &self.video_info)?;
+
+            let img_bytes = frame::to_tensor(&in_frame)
+                .f_to_device(self.device)?
+                .permute(&[2, 0, 1]);
 
             let driving_frame = img_bytes.to_kind(tch::Kind::Float) / 255;
 
-            if let Some(source_image) = &self.source_image {
+            // Models are shared handles, so calibrating can borrow self while they're locked
+            let (detector, generator) = (self.detector.clone(), self.generator.clone());
+            let detector = detector.as_ref().unwrap().lock().unwrap();
+            let generator = generator.as_ref().unwrap().lock().unwrap();
+
+            let source_image = match self.source_mode {
+                SourceMode::Still => self.source_image.as_ref(),
+                _ => self.live_source.as_ref().or(self.source_image.as_ref()),
+            };
+            if let Some(source_image) = source_image {
                 if self.kp_source == None {
                     let source = source_image.to_kind(tch::Kind::Float) / 255;
                     let source = source.unsqueeze(0);
                     self.source = Some(source.copy());
-                    let source = tch::IValue::Tensor(source);
-                    let detector_output = DETECTOR_MODEL
-                        .lock()
-                        .unwrap()
-                        .forward_is(&[source])
-                        .unwrap();
-                    let det_tensors = match &detector_output {
-                        tch::IValue::Tuple(det_tensors) => Some(det_tensors),
-                        _ => None,
-                    }
-                    .unwrap();
-                    match (&det_tensors[0], &det_tensors[1]) {
-                        (tch::IValue::Tensor(value), tch::IValue::Tensor(jacobian)) => {
-                            self.kp_source = Some((value.copy(), jacobian.copy()))
-                        }
-                        _ => (),
-                    };
+                    self.kp_source = Some(keypoints(&detector, source)?);
                 }
             }
 
-            if self.kp_driving_initial == None {
-                let driving_initial = tch::IValue::Tensor(driving_frame.unsqueeze(0));
-                let detector_output = DETECTOR_MODEL
-                    .lock()
-                    .unwrap()
-                    .forward_is(&[driving_initial])
-                    .unwrap();
-                let det_tensors = match &detector_output {
-                    tch::IValue::Tuple(det_tensors) => Some(det_tensors),
-                    _ => None,
-                }
-                .unwrap();
-                match (&det_tensors[0], &det_tensors[1]) {
-                    (tch::IValue::Tensor(value), tch::IValue::Tensor(jacobian)) => {
-                        self.kp_driving_initial = Some((value.copy(), jacobian.copy()))
-                    }
-                    _ => (),
-                };
-            };
-
-            let mut kp_driving: Option<(Tensor, Tensor)> = None;
-            {
-                let kp_driving_img = tch::IValue::Tensor(driving_frame.unsqueeze(0));
-                let detector_output = DETECTOR_MODEL
-                    .lock()
-                    .unwrap()
-                    .forward_is(&[kp_driving_img])
-                    .unwrap();
-                let det_tensors = match &detector_output {
-                    tch::IValue::Tuple(det_tensors) => Some(det_tensors),
-                    _ => None,
-                }
-                .unwrap();
-                match (&det_tensors[0], &det_tensors[1]) {
-                    (tch::IValue::Tensor(value), tch::IValue::Tensor(jacobian)) => {
-                        kp_driving = Some((value.copy(), jacobian.copy()))
-                    }
-                    _ => (),
-                };
-            }
+            let kp_driving = keypoints(&detector, driving_frame.unsqueeze(0))?;
+            self.calibrate(&kp_driving)?;
 
             let mut prediction: Option<Tensor> = None;
-            match (
-                &self.source,
-                &self.kp_source,
-                &kp_driving,
-                &self.kp_driving_initial,
-            ) {
+            match (&self.source, &self.kp_source, &self.kp_driving_initial) {
                 (
                     Some(source),
                     Some((kp_source_value, kp_source_jacobian)),
-                    Some((kp_driving_value, kp_driving_jacobian)),
                     Some((kp_driving_initial_value, kp_driving_initial_jacobian)),
                 ) => {
-                    let kp_value_diff = kp_driving_value - kp_driving_initial_value;
-                    let kp_driving_value = kp_value_diff + kp_source_value;
+                    let (kp_driving_value, kp_driving_jacobian) = &kp_driving;
+                    let kp_value_diff = kp_driving_value.f_sub(kp_driving_initial_value)?;
+                    let kp_driving_value = kp_value_diff.f_add(kp_source_value)?;
 
-                    let kp_driving_initial_jacobian_inv = kp_driving_initial_jacobian.inverse();
+                    let kp_driving_initial_jacobian_inv =
+                        kp_driving_initial_jacobian.f_inverse()?;
                     let jacobian_diff =
-                        kp_driving_jacobian.matmul(&kp_driving_initial_jacobian_inv);
-                    let kp_driving_jacobian = jacobian_diff.matmul(&kp_source_jacobian);
+                        kp_driving_jacobian.f_matmul(&kp_driving_initial_jacobian_inv)?;
+                    let kp_driving_jacobian = jacobian_diff.f_matmul(&kp_source_jacobian)?;
 
                     let source = tch::IValue::Tensor(source.copy());
                     let kp_source_value = tch::IValue::Tensor(kp_source_value.copy());
@@ -241,37 +435,32 @@ impl cata::Process for MotionTransfer {
                     let kp_driving_value = tch::IValue::Tensor(kp_driving_value);
                     let kp_driving_jacobian = tch::IValue::Tensor(kp_driving_jacobian);
 
-                    let gen_pred = GENERATOR_MODEL
-                        .lock()
-                        .unwrap()
-                        .forward_is(&[
-                            source,
-                            kp_source_value,
-                            kp_source_jacobian,
-                            kp_driving_value,
-                            kp_driving_jacobian,
-                        ])
-                        .unwrap();
-                    let gen_pred = if let tch::IValue::Tensor(gen_pred) = &gen_pred {
-                        Some(gen_pred)
-                    } else {
-                        None
+                    let gen_pred = generator.forward_is(&[
+                        source,
+                        kp_source_value,
+                        kp_source_jacobian,
+                        kp_driving_value,
+                        kp_driving_jacobian,
+                    ])?;
+                    let gen_pred = match &gen_pred {
+                        tch::IValue::Tensor(gen_pred) => gen_pred,
+                        _ => {
+                            return Err(cata::ProcessError::Shape(
+                                "Generator didn't output an image tensor".to_string(),
+                            ))
+                        }
                     };
-                    prediction = Some(gen_pred.unwrap().squeeze());
+                    prediction = Some(gen_pred.squeeze());
                 }
                 _ => (),
             };
 
-            let driven_out = unsafe {
-                std::slice::from_raw_parts_mut(out_data.as_mut_ptr(), (WIDTH * HEIGHT * 3) as usize)
-            };
-
             if let Some(prediction) = prediction {
                 let prediction = prediction * 255;
-                prediction
-                    .to_kind(tch::Kind::Uint8)
-                    .permute(&[1, 2, 0])
-                    .copy_data(driven_out, (WIDTH * HEIGHT * 3) as usize);
+                frame::copy_to_frame(
+                    &prediction.to_kind(tch::Kind::Uint8).permute(&[1, 2, 0]),
+                    &mut out_frame,
+                )?;
             }
         }
 
@@ -283,14 +472,126 @@ impl cata::Process for MotionTransfer {
     fn set_property(&mut self, property: &subclass::Property, value: &glib::Value) {
         match property {
             subclass::Property("source-image", ..) => {
-                let source_path: String = value.get().expect("source image path").unwrap();
-                self.source_image = match tch::vision::image::load(source_path) {
-                    Ok(tensor) => Some(tensor.to_device(tch::Device::Cuda(0))),
-                    _ => None,
-                };
+                self.source_image_path = value.get().expect("source image path");
+                self.source_image =
+                    self.source_image_path.as_ref().and_then(
+                        |path| match tch::vision::image::load(path) {
+                            Ok(tensor) => Some(tensor.to_device(self.device)),
+                            _ => None,
+                        },
+                    );
+                self.source = None;
+                self.kp_source = None;
+            }
+            subclass::Property("device", ..) => {
+                let device = device::from_value(value, self.device);
+                if device != self.device {
+                    self.device = device;
+                    self.source_image = self.source_image.as_ref().map(|t| t.to_device(device));
+                    self.live_source = self.live_source.as_ref().map(|t| t.to_device(device));
+                    self.source = None;
+                    self.kp_source = None;
+                    self.kp_driving_initial = self
+                        .kp_driving_initial
+                        .as_ref()
+                        .map(|(v, j)| (v.to_device(device), j.to_device(device)));
+                    self.calibration_reference = self
+                        .calibration_reference
+                        .as_ref()
+                        .map(|r| r.to_device(device));
+                }
+            }
+            subclass::Property("model-dir", ..) => {
+                self.model_dir = value.get().expect("model directory");
+            }
+            subclass::Property("source-mode", ..) => {
+                self.source_mode = value.get_some().expect("source mode");
+                self.source_frame = None;
+                self.live_source = None;
+                self.source = None;
                 self.kp_source = None;
             }
+            subclass::Property("calibrate-on", ..) => {
+                self.calibrate_on = value.get_some().expect("calibrate on");
+            }
+            subclass::Property("calibration-file", ..) => {
+                self.calibration_file = value.get().expect("calibration file");
+                // Once started, a new file replaces the neutral pose right away
+                if self.detector.is_some() {
+                    self.restore_calibration();
+                }
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    fn get_property(&self, property: &subclass::Property) -> glib::Value {
+        match property {
+            subclass::Property("source-image", ..) => self.source_image_path.to_value(),
+            subclass::Property("device", ..) => device::to_value(self.device),
+            subclass::Property("model-dir", ..) => self.model_dir.to_value(),
+            subclass::Property("source-mode", ..) => self.source_mode.to_value(),
+            subclass::Property("calibrate-on", ..) => self.calibrate_on.to_value(),
+            subclass::Property("calibration-file", ..) => self.calibration_file.to_value(),
             _ => unimplemented!(),
         }
     }
+
+    fn prepare(&mut self) -> Result<(), gst::ErrorMessage> {
+        let model_dir = models::resolve(&self.model_dir, "motiontransfer")?;
+        self.detector = Some(models::load(&model_dir.join("detector.pt"), self.device)?);
+        self.generator = Some(models::load(&model_dir.join("generator.pt"), self.device)?);
+        self.kp_driving_initial = None;
+        self.calibration_score = f64::INFINITY;
+        self.calibrating = match self.calibrate_on {
+            CalibrateOn::FirstFrame => 1,
+            CalibrateOn::Manual => 0,
+            CalibrateOn::Auto => AUTO_CALIBRATION_FRAMES,
+        };
+        self.restore_calibration();
+        Ok(())
+    }
+
+    fn action(&mut self, name: &str) {
+        if name == "recalibrate" {
+            self.kp_driving_initial = None;
+            self.calibration_score = f64::INFINITY;
+            self.calibrating = match self.calibrate_on {
+                CalibrateOn::Auto => AUTO_CALIBRATION_FRAMES,
+                _ => 1,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alignment_ignores_translation() {
+        let kp = Tensor::of_slice(&[0.0f32, 0.0, 1.0, 0.0, 0.0, 1.0]).view([1, 3, 2]);
+        let shifted = &kp + 0.5;
+        assert!(alignment(&kp, &shifted).abs() < 1e-6);
+
+        let spread = &kp * 2.0;
+        assert!(alignment(&kp, &spread) > 0.1);
+    }
+
+    #[test]
+    fn calibrates_without_source() {
+        gst::init().unwrap();
+        let mut motiontransfer = MotionTransfer::default();
+        motiontransfer.calibrating = 3;
+        let kp = Tensor::zeros(&[1, 2, 2], (tch::Kind::Float, tch::Device::Cpu));
+        let eye = Tensor::eye(2, (tch::Kind::Float, tch::Device::Cpu)).view([1, 1, 2, 2]);
+        for scale in &[2.0, 1.0, 0.5] {
+            let jacobian = (&eye * *scale).expand(&[1, 2, 2, 2], false);
+            motiontransfer.calibrate(&(kp.copy(), jacobian)).unwrap();
+        }
+        assert_eq!(motiontransfer.calibrating, 0);
+        assert!(motiontransfer.calibration_score.abs() < 1e-6);
+        let (_, jacobian) = motiontransfer.kp_driving_initial.as_ref().unwrap();
+        assert!(f64::from((jacobian - &eye).abs().max()) < 1e-6);
+    }
 }
@@ -0,0 +1,71 @@
+use glib;
+use gst;
+use tch;
+use tch::Tensor;
+
+pub fn param_spec(name: &str) -> glib::ParamSpec {
+    glib::ParamSpec::boolean(
+        name,
+        "Overlay",
+        "Blend the colorized prediction onto the input frame",
+        false,
+        glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_READY,
+    )
+}
+
+pub fn alpha_param_spec(name: &str) -> glib::ParamSpec {
+    glib::ParamSpec::double(
+        name,
+        "Alpha",
+        "Opacity of the prediction blended in overlay mode",
+        0.0,
+        1.0,
+        0.5,
+        glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+    )
+}
+
+pub fn outline_param_spec(name: &str) -> glib::ParamSpec {
+    glib::ParamSpec::boolean(
+        name,
+        "Outline only",
+        "Only blend the outlines of the predicted regions in overlay mode",
+        false,
+        glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+    )
+}
+
+// Tensor[[H, W, 3], Uint8] of `color` blended onto `image`, both Tensor[[H, W, 3]],
+// with `alpha` scaled by the Tensor[[H, W], Float] `weight` in 0..1 of every pixel
+pub fn blend(image: &Tensor, color: &Tensor, weight: &Tensor, alpha: f64) -> Tensor {
+    let image = image.to_kind(tch::Kind::Float);
+    let weight = weight.to_kind(tch::Kind::Float).unsqueeze(-1) * alpha;
+    (&image + (color.to_kind(tch::Kind::Float) - &image) * weight)
+        .round()
+        .clamp(0.0, 255.0)
+        .to_kind(tch::Kind::Uint8)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn blends_by_weight() {
+        let image = Tensor::of_slice(&[0u8, 100, 200, 0, 100, 200]).view((1, 2, 3));
+        let color = Tensor::of_slice(&[200u8, 100, 0, 200, 100, 0]).view((1, 2, 3));
+        let weight = Tensor::of_slice(&[1f32, 0.]).view((1, 2));
+        let blended = blend(&image, &color, &weight, 0.5);
+        assert_eq!(blended.kind(), tch::Kind::Uint8);
+        assert_eq!(
+            Vec::<u8>::from(blended.flatten(0, -1)),
+            vec![100, 100, 100, 0, 100, 200]
+        );
+        let opaque = blend(&image, &color, &weight, 1.0);
+        assert_eq!(
+            Vec::<u8>::from(opaque.flatten(0, -1)),
+            vec![200, 100, 0, 0, 100, 200]
+        );
+    }
+}
@@ -4,6 +4,11 @@ pub trait Registry {
 
     fn type_data() -> ::std::ptr::NonNull<glib::subclass::TypeData>;
     fn properties() -> &'static [glib::subclass::Property<'static>];
+
+    // Names of argument-less action signals, emitted to the processor's `action`
+    fn actions() -> &'static [&'static str] {
+        &[]
+    }
 }
 
 macro_rules! register_typedata {
@@ -0,0 +1,178 @@
+use glib;
+use glib::{GEnum, StaticType};
+use gst;
+use tch;
+use tch::Tensor;
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, GEnum)]
+#[repr(u32)]
+#[genum(type_name = "SimboticResizeMode")]
+pub enum ResizeMode {
+    #[genum(name = "Stretch: scale to the network resolution", nick = "stretch")]
+    Stretch = 0,
+    #[genum(name = "Letterbox: fit and pad the borders", nick = "letterbox")]
+    Letterbox = 1,
+    #[genum(name = "Center crop: fill and crop the borders", nick = "center-crop")]
+    CenterCrop = 2,
+}
+
+pub fn param_spec(name: &str) -> glib::ParamSpec {
+    glib::ParamSpec::enum_(
+        name,
+        "Resize mode",
+        "How input frames are fitted to the network resolution",
+        ResizeMode::static_type(),
+        ResizeMode::Stretch as i32,
+        glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+    )
+}
+
+fn resize(tensor: &Tensor, height: i64, width: i64) -> Tensor {
+    let size = tensor.size();
+    if size[2] == height && size[3] == width {
+        tensor.shallow_clone()
+    } else {
+        tensor.upsample_bilinear2d(&[height, width], false, None::<f64>, None::<f64>)
+    }
+}
+
+// Maps frames between the input resolution and the network resolution.
+// Sizes are (height, width), tensors are [N, C, H, W] floats.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Resize {
+    pub mode: ResizeMode,
+    pub input: (i64, i64),
+    pub network: (i64, i64),
+    scaled: (i64, i64),
+    offset: (i64, i64),
+}
+
+impl Resize {
+    pub fn new(mode: ResizeMode, input: (i64, i64), network: (i64, i64)) -> Resize {
+        let scale_h = network.0 as f64 / input.0 as f64;
+        let scale_w = network.1 as f64 / input.1 as f64;
+        let scale = match mode {
+            ResizeMode::Stretch => 1.0,
+            ResizeMode::Letterbox => scale_h.min(scale_w),
+            ResizeMode::CenterCrop => scale_h.max(scale_w),
+        };
+        let scaled = match mode {
+            ResizeMode::Stretch => network,
+            _ => (
+                ((input.0 as f64 * scale).round() as i64).max(1),
+                ((input.1 as f64 * scale).round() as i64).max(1),
+            ),
+        };
+        // Letterbox pads the scaled frame up to the network size, center crop trims it
+        let offset = (
+            (network.0 - scaled.0).abs() / 2,
+            (network.1 - scaled.1).abs() / 2,
+        );
+        Resize {
+            mode,
+            input,
+            network,
+            scaled,
+            offset,
+        }
+    }
+
+    // Rows and columns, as (start, length), of the network frame showing the input
+    pub fn network_region(&self) -> ((i64, i64), (i64, i64)) {
+        match self.mode {
+            ResizeMode::Letterbox => (
+                (self.offset.0, self.scaled.0),
+                (self.offset.1, self.scaled.1),
+            ),
+            _ => ((0, self.network.0), (0, self.network.1)),
+        }
+    }
+
+    // Vertical focal length and principal point in network pixels, of a camera with
+    // intrinsics `fy` and `cy` normalized by the input height
+    pub fn network_intrinsics(&self, fy: f64, cy: f64) -> (f64, f64) {
+        let height = self.scaled.0 as f64;
+        let offset = match self.mode {
+            ResizeMode::Letterbox => self.offset.0 as f64,
+            ResizeMode::CenterCrop => -(self.offset.0 as f64),
+            ResizeMode::Stretch => 0.0,
+        };
+        (fy * height, cy * height + offset)
+    }
+
+    pub fn to_network(&self, tensor: &Tensor) -> Tensor {
+        let scaled = resize(tensor, self.scaled.0, self.scaled.1);
+        match self.mode {
+            ResizeMode::Stretch => scaled,
+            ResizeMode::Letterbox => {
+                let size = scaled.size();
+                let padded = Tensor::zeros(
+                    &[size[0], size[1], self.network.0, self.network.1],
+                    (scaled.kind(), scaled.device()),
+                );
+                padded
+                    .narrow(2, self.offset.0, self.scaled.0)
+                    .narrow(3, self.offset.1, self.scaled.1)
+                    .copy_(&scaled);
+                padded
+            }
+            ResizeMode::CenterCrop => scaled.narrow(2, self.offset.0, self.network.0).narrow(
+                3,
+                self.offset.1,
+                self.network.1,
+            ),
+        }
+    }
+
+    pub fn from_network(&self, tensor: &Tensor) -> Tensor {
+        let scaled = match self.mode {
+            ResizeMode::Stretch => tensor.shallow_clone(),
+            ResizeMode::Letterbox => tensor.narrow(2, self.offset.0, self.scaled.0).narrow(
+                3,
+                self.offset.1,
+                self.scaled.1,
+            ),
+            // Borders that were cropped away extend the nearest prediction
+            ResizeMode::CenterCrop => tensor.replication_pad2d(&[
+                self.offset.1,
+                self.scaled.1 - self.network.1 - self.offset.1,
+                self.offset.0,
+                self.scaled.0 - self.network.0 - self.offset.0,
+            ]),
+        };
+        resize(&scaled, self.input.0, self.input.1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn letterbox_pads_to_network() {
+        let resize = Resize::new(ResizeMode::Letterbox, (480, 640), (192, 640));
+        assert_eq!(resize.scaled, (192, 256));
+        assert_eq!(resize.offset, (0, 192));
+        assert_eq!(resize.network_region(), ((0, 192), (192, 256)));
+
+        let img = Tensor::ones(&[1, 3, 480, 640], tch::kind::FLOAT_CPU);
+        let net = resize.to_network(&img);
+        assert_eq!(net.size(), vec![1, 3, 192, 640]);
+        assert_eq!(resize.from_network(&net).size(), vec![1, 3, 480, 640]);
+    }
+
+    #[test]
+    fn center_crop_fills_network() {
+        let resize = Resize::new(ResizeMode::CenterCrop, (480, 640), (192, 640));
+        assert_eq!(resize.scaled, (480, 640));
+        assert_eq!(resize.offset, (144, 0));
+        assert_eq!(resize.network_region(), ((0, 192), (0, 640)));
+        assert_eq!(resize.network_intrinsics(1.0, 0.5), (480.0, 96.0));
+
+        let img = Tensor::ones(&[1, 3, 480, 640], tch::kind::FLOAT_CPU);
+        let net = resize.to_network(&img);
+        assert_eq!(net.size(), vec![1, 3, 192, 640]);
+        assert_eq!(resize.from_network(&net).size(), vec![1, 3, 480, 640]);
+    }
+}
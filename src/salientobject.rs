@@ -1,41 +1,126 @@
-use std::env;
 use std::i32;
 use std::sync::Mutex;
 
 use crate::caps;
 use crate::cata;
+use crate::colormap;
+use crate::device;
+use crate::frame;
+use crate::meta;
+use crate::models;
+use crate::morphology;
+use crate::overlay;
 use crate::registry;
 
 use glib::subclass;
+use glib::{GEnum, StaticType, ToSendValue, ToValue};
 use gst;
 use gst_video;
 
 use tch;
 use tch::{TchError, Tensor};
 
-lazy_static! {
-    static ref IMAGENET_MEAN: Mutex<Tensor> = Mutex::new(
+// ImageNet channel mean and std, Tensor[[3, 1, 1], Float] on `device`
+pub fn imagenet(device: tch::Device) -> (Tensor, Tensor) {
+    (
         Tensor::of_slice(&[0.485f32, 0.456, 0.406])
-            .to_device(tch::Device::Cuda(0))
             .view((3, 1, 1))
-    );
-    static ref IMAGENET_STD: Mutex<Tensor> = Mutex::new(
+            .to_device(device),
         Tensor::of_slice(&[0.229f32, 0.224, 0.225])
-            .to_device(tch::Device::Cuda(0))
             .view((3, 1, 1))
-    );
+            .to_device(device),
+    )
 }
 
-pub fn normalize(tensor: &Tensor) -> Result<Tensor, TchError> {
-    let mean = IMAGENET_MEAN.lock().unwrap();
-    let std = IMAGENET_STD.lock().unwrap();
+pub fn normalize(tensor: &Tensor, (mean, std): &(Tensor, Tensor)) -> Result<Tensor, TchError> {
     (tensor.to_kind(tch::Kind::Float) / 255.0)
-        .f_sub(&mean)?
-        .f_div(&std)
+        .f_sub(mean)?
+        .f_div(std)
+}
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, GEnum)]
+#[repr(u32)]
+#[genum(type_name = "SimboticSaliencyNormalize")]
+pub enum Normalize {
+    #[genum(name = "Per frame: min/max of every frame", nick = "per-frame")]
+    PerFrame = 0,
+    #[genum(name = "None: model output clamped to 0..1", nick = "none")]
+    None = 1,
+    #[genum(name = "Sigmoid: model output read as logits", nick = "sigmoid")]
+    Sigmoid = 2,
 }
 
-const WIDTH: i32 = 320;
-const HEIGHT: i32 = 320;
+// Shared by every element running the saliency model
+pub const DEFAULT_NORMALIZE: Normalize = Normalize::PerFrame;
+
+// Tensor[[1, H, W], Float] saliency in 0..1 of a raw model output
+pub fn scale(saliency: &Tensor, normalize: Normalize) -> Tensor {
+    match normalize {
+        Normalize::PerFrame => {
+            let max = saliency.max();
+            let min = saliency.min();
+            (saliency - &min) / (max - &min).clamp_min(f64::from(f32::EPSILON))
+        }
+        Normalize::None => saliency.clamp(0.0, 1.0),
+        Normalize::Sigmoid => saliency.sigmoid(),
+    }
+}
+
+// Connected blob of salient pixels
+#[derive(Debug, PartialEq)]
+struct Region {
+    // Left, top, width and height in pixels
+    bbox: [i64; 4],
+    area: i64,
+    // Mean saliency of the blob
+    confidence: f64,
+}
+
+// Blobs of at least `min_area` pixels above `threshold` in a Tensor[[1, H, W], Float]
+// saliency in 0..1, largest first
+fn regions(saliency: &Tensor, threshold: f64, min_area: i64) -> Vec<Region> {
+    let mask = saliency
+        .ge(threshold)
+        .to_kind(tch::Kind::Float)
+        .unsqueeze(0);
+    let labels = morphology::components(&mask);
+    let blobs = morphology::blobs(&labels, saliency, min_area.max(1)).to_device(tch::Device::Cpu);
+    let mut regions = Vec::<f64>::from(blobs.flatten(0, -1))
+        .chunks(6)
+        .map(|blob| Region {
+            bbox: [
+                blob[1] as i64,
+                blob[2] as i64,
+                blob[3] as i64,
+                blob[4] as i64,
+            ],
+            area: blob[0] as i64,
+            confidence: blob[5],
+        })
+        .collect::<Vec<Region>>();
+    regions.sort_by(|a, b| b.area.cmp(&a.area));
+    regions
+}
+
+pub const WIDTH: i32 = 320;
+pub const HEIGHT: i32 = 320;
+
+// Tensor[[1, H, W], Float] raw saliency of a normalized Tensor[[1, 3, H, W], Float] image
+pub fn saliency(model: &models::Model, img: Tensor) -> Result<Tensor, cata::ProcessError> {
+    let i_img: tch::IValue = tch::IValue::Tensor(img);
+    let model_output = model.lock().unwrap().forward_is(&[i_img])?;
+    let prediction = match &model_output {
+        tch::IValue::Tuple(model_tensors) => match model_tensors.first() {
+            Some(tch::IValue::Tensor(tensor)) => Some(tensor),
+            _ => None,
+        },
+        _ => None,
+    }
+    .ok_or_else(|| {
+        cata::ProcessError::Shape("Model didn't output a tuple of saliency maps".to_string())
+    })?;
+    Ok(prediction.squeeze().unsqueeze(0))
+}
 
 lazy_static! {
     static ref CAPS_IN: Mutex<gst::Caps> = Mutex::new(gst::Caps::new_simple(
@@ -58,7 +143,10 @@ lazy_static! {
         &[
             (
                 "format",
-                &gst::List::new(&[&gst_video::VideoFormat::Gray8.to_str()]),
+                &gst::List::new(&[
+                    &gst_video::VideoFormat::Gray8.to_str(),
+                    &gst_video::VideoFormat::Rgb.to_str()
+                ]),
             ),
             ("width", &WIDTH),
             ("height", &HEIGHT),
@@ -68,15 +156,99 @@ lazy_static! {
             ),
         ],
     ));
-    static ref MODEL: Mutex<tch::CModule> = Mutex::new(
-        tch::CModule::load(env::var("SIMBOTIC_TORCH").unwrap() + "/models/salientobject/model.pt")
-            .unwrap()
-    );
 }
 
+// Metadata for the properties
+static PROPERTIES: [subclass::Property; 12] = [
+    subclass::Property("device", |name| device::param_spec(name)),
+    subclass::Property("model-path", |name| models::path_param_spec(name)),
+    subclass::Property("attach-tensor", |name| meta::param_spec(name)),
+    subclass::Property("overlay", |name| overlay::param_spec(name)),
+    subclass::Property("alpha", |name| overlay::alpha_param_spec(name)),
+    subclass::Property("outline-only", |name| overlay::outline_param_spec(name)),
+    subclass::Property("normalize", |name| {
+        glib::ParamSpec::enum_(
+            name,
+            "Normalize",
+            "How the model output is mapped to 0..1",
+            Normalize::static_type(),
+            DEFAULT_NORMALIZE as i32,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+    subclass::Property("threshold", |name| {
+        glib::ParamSpec::double(
+            name,
+            "Threshold",
+            "Saliency above which pixels belong to the hard mask and the outline",
+            0.0,
+            1.0,
+            0.5,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+    subclass::Property("temporal-alpha", |name| {
+        glib::ParamSpec::double(
+            name,
+            "Temporal alpha",
+            "Weight of the newest frame when smoothing saliency, 1 disables smoothing",
+            0.0,
+            1.0,
+            1.0,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+    subclass::Property("hard-mask", |name| {
+        glib::ParamSpec::boolean(
+            name,
+            "Hard mask",
+            "Emit a binary mask of the pixels above threshold",
+            false,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+    subclass::Property("regions", |name| {
+        glib::ParamSpec::boolean(
+            name,
+            "Regions",
+            "Attach salient blobs as ROI metas and post salient-regions messages",
+            false,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+    subclass::Property("min-area", |name| {
+        glib::ParamSpec::uint(
+            name,
+            "Min area",
+            "Salient blobs smaller than this many pixels aren't reported as regions",
+            0,
+            u32::MAX,
+            64,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+];
+
 pub struct SalientObject {
     video_info_in: gst_video::VideoInfo,
     video_info_out: gst_video::VideoInfo,
+    device: tch::Device,
+    model_path: Option<String>,
+    model: Option<models::Model>,
+    attach_tensor: bool,
+    overlay: bool,
+    alpha: f64,
+    outline_only: bool,
+    color_map: Option<Tensor>,  // Tensor[[3, 1, N], Uint8]
+    imagenet: (Tensor, Tensor), // Mean and std on the device
+    normalize: Normalize,
+    threshold: f64,
+    temporal_alpha: f64,
+    hard_mask: bool,
+    smoothed: Option<Tensor>, // Tensor[[1, H, W], Float]
+    regions: bool,
+    min_area: u32,
+    messages: Vec<gst::Structure>,
 }
 
 impl registry::Registry for SalientObject {
@@ -85,7 +257,7 @@ impl registry::Registry for SalientObject {
     register_typedata!();
 
     fn properties() -> &'static [glib::subclass::Property<'static>] {
-        &[]
+        &PROPERTIES
     }
 }
 
@@ -98,6 +270,23 @@ impl std::default::Default for SalientObject {
         SalientObject {
             video_info_in: gst_video::VideoInfo::from_caps(&caps_in).unwrap(),
             video_info_out: gst_video::VideoInfo::from_caps(&caps_out).unwrap(),
+            device: device::default(),
+            model_path: None,
+            model: None,
+            attach_tensor: false,
+            overlay: false,
+            alpha: 0.5,
+            outline_only: false,
+            color_map: None,
+            imagenet: imagenet(tch::Device::Cpu),
+            normalize: DEFAULT_NORMALIZE,
+            threshold: 0.5,
+            temporal_alpha: 1.0,
+            hard_mask: false,
+            smoothed: None,
+            regions: false,
+            min_area: 64,
+            messages: Vec::new(),
         }
     }
 }
@@ -112,7 +301,99 @@ impl caps::CapsDef for SalientObject {
             name: "mask",
             caps: CAPS_OUT.lock().unwrap().clone(),
         };
-        (vec![in_caps], vec![out_caps])
+        let passthrough_caps = caps::PadCaps {
+            name: "passthrough",
+            caps: CAPS_IN.lock().unwrap().clone(),
+        };
+        (vec![in_caps], vec![out_caps, passthrough_caps])
+    }
+}
+
+impl SalientObject {
+    // Attaches salient regions to the output buffers as "salient" ROI metas and queues their
+    // salient-regions message
+    fn add_regions(&mut self, matte: &Tensor, outbufs: &mut Vec<gst::Buffer>) {
+        let regions = regions(matte, self.threshold, self.min_area as i64);
+        for outbuf in outbufs.iter_mut() {
+            let out_ref = outbuf.get_mut().unwrap();
+            for region in &regions {
+                let bbox = region.bbox;
+                let mut roi = gst_video::VideoRegionOfInterestMeta::add(
+                    out_ref,
+                    "salient",
+                    (
+                        bbox[0] as u32,
+                        bbox[1] as u32,
+                        bbox[2] as u32,
+                        bbox[3] as u32,
+                    ),
+                );
+                roi.add_param(
+                    gst::Structure::builder("detection")
+                        .field("confidence", &region.confidence)
+                        .build(),
+                );
+            }
+        }
+        let regions = regions
+            .iter()
+            .map(|region| {
+                gst::Structure::builder("region")
+                    .field("x", &(region.bbox[0] as u32))
+                    .field("y", &(region.bbox[1] as u32))
+                    .field("width", &(region.bbox[2] as u32))
+                    .field("height", &(region.bbox[3] as u32))
+                    .field("area", &(region.area as u32))
+                    .field("confidence", &region.confidence)
+                    .build()
+                    .to_send_value()
+            })
+            .collect::<Vec<glib::SendValue>>();
+        self.messages.push(
+            gst::Structure::builder("salient-regions")
+                .field("pts", &outbufs[0].get_pts())
+                .field("regions", &gst::List::from_owned(regions))
+                .build(),
+        );
+    }
+
+    // Tensor[[H, W, 3], Uint8] of a Tensor[[1, H, W], Float] saliency in 0..1 colorized
+    // and blended onto the Tensor[[H, W, 3], Uint8] input image
+    fn overlay(&self, img: &Tensor, saliency: &Tensor) -> Result<Tensor, cata::ProcessError> {
+        let color_map = self.color_map.as_ref().unwrap();
+        let size = saliency.size();
+        let color_index = (saliency * (color_map.size()[2] - 1) as f64)
+            .to_kind(tch::Kind::Int64)
+            .flatten(0, -1);
+        let color = color_map
+            .f_index_select(2, &color_index)?
+            .permute(&[2, 1, 0])
+            .reshape(&[size[1], size[2], 3]);
+        let weight = if self.outline_only {
+            morphology::edges(&saliency.ge(self.threshold).unsqueeze(0))
+        } else {
+            saliency.shallow_clone()
+        };
+        let weight = weight.reshape(&[size[1], size[2]]);
+        Ok(overlay::blend(img, &color, &weight, self.alpha))
+    }
+
+    // Tensor[[1, H, W], Float] matte in 0..1 of a raw saliency, smoothed over frames
+    // and hardened as configured
+    fn matte(&mut self, saliency: &Tensor) -> Tensor {
+        let scaled = scale(saliency, self.normalize);
+        let smoothed = match &self.smoothed {
+            Some(previous) if previous.size() == scaled.size() => {
+                previous + (&scaled - previous) * self.temporal_alpha
+            }
+            _ => scaled,
+        };
+        self.smoothed = Some(smoothed.shallow_clone());
+        if self.hard_mask {
+            smoothed.ge(self.threshold).to_kind(tch::Kind::Float)
+        } else {
+            smoothed
+        }
     }
 }
 
@@ -121,70 +402,204 @@ impl cata::Process for SalientObject {
         &mut self,
         inbuf: &Vec<gst::Buffer>,
         outbufs: &mut Vec<gst::Buffer>,
-    ) -> Result<(), std::io::Error> {
+    ) -> Result<(), cata::ProcessError> {
         let in_ref = inbuf[0].as_ref();
-        let in_frame =
-            gst_video::VideoFrameRef::from_buffer_ref_readable(in_ref, &self.video_info_in)
-                .unwrap();
-        let _in_stride = in_frame.plane_stride()[0] as usize;
-        let _in_format = in_frame.format();
-        let in_width = in_frame.width() as i32;
-        let in_height = in_frame.height() as i32;
-        let in_data = in_frame.plane_data(0).unwrap();
-
-        let img_slice = unsafe { std::slice::from_raw_parts(in_data.as_ptr(), in_data.len()) };
-        let img = Tensor::of_data_size(
-            img_slice,
-            &[in_height as i64, in_width as i64, 3],
-            tch::Kind::Uint8,
-        )
-        .to_device(tch::Device::Cuda(0))
-        .permute(&[2, 0, 1]);
-        let img = normalize(&img).unwrap();
+        let in_frame = frame::map_readable(in_ref, &self.video_info_in)?;
+
+        let rgb = frame::to_tensor(&in_frame).f_to_device(self.device)?;
+        let img = normalize(&rgb.permute(&[2, 0, 1]), &self.imagenet)?;
         let img = img.unsqueeze(0);
 
-        let i_img: tch::IValue = tch::IValue::Tensor(img);
-        let model_output = MODEL.lock().unwrap().forward_is(&[i_img]).unwrap();
-        let model_tensors = match &model_output {
-            tch::IValue::Tuple(enc_tensors) => Some(enc_tensors),
-            _ => None,
+        let saliency = saliency(self.model.as_ref().unwrap(), img)?;
+        let prediction = self.matte(&saliency);
+
+        if self.overlay {
+            let annotated = self.overlay(&rgb, &prediction)?;
+            outbufs[0] = inbuf[0].copy();
+            let out_ref = outbufs[0].get_mut().unwrap();
+            let mut out_frame = frame::map_writable(out_ref, &self.video_info_in)?;
+            frame::copy_to_frame(&annotated, &mut out_frame)?;
+        } else {
+            let prediction = prediction * Tensor::of_slice(&[255f32]).to_device(self.device);
+
+            outbufs[0] = gst::Buffer::with_size((WIDTH * HEIGHT) as usize).map_err(|_| {
+                cata::ProcessError::Mapping("Failed to allocate buffer".to_string())
+            })?;
+            let out_ref = outbufs[0].get_mut().unwrap();
+            out_ref.set_pts(in_ref.get_pts());
+            out_ref.set_dts(in_ref.get_dts());
+            out_ref.set_offset(in_ref.get_offset());
+            out_ref.set_duration(in_ref.get_duration());
+            let mut out_frame = frame::map_writable(out_ref, &self.video_info_out)?;
+            frame::copy_to_frame(&prediction.to_kind(tch::Kind::Uint8), &mut out_frame)?;
+        }
+        let out_ref = outbufs[0].get_mut().unwrap();
+        if self.attach_tensor {
+            meta::TensorMeta::add(out_ref, "saliency", &saliency)?;
+        }
+        outbufs[1] = inbuf[0].copy();
+        if self.regions {
+            self.add_regions(&prediction, outbufs);
         }
-        .unwrap();
 
-        let prediction: &tch::IValue = &model_tensors[0];
-        let prediction = match &prediction {
-            tch::IValue::Tensor(tensor) => Some(tensor),
-            _ => None,
+        Ok(())
+    }
+
+    fn set_property(&mut self, property: &subclass::Property, value: &glib::Value) {
+        match property {
+            subclass::Property("device", ..) => {
+                self.device = device::from_value(value, self.device);
+            }
+            subclass::Property("model-path", ..) => {
+                self.model_path = value.get().expect("model path");
+            }
+            subclass::Property("attach-tensor", ..) => {
+                self.attach_tensor = value.get_some().expect("attach tensor");
+            }
+            subclass::Property("overlay", ..) => {
+                self.overlay = value.get_some().expect("overlay");
+            }
+            subclass::Property("alpha", ..) => {
+                self.alpha = value.get_some().expect("alpha");
+            }
+            subclass::Property("outline-only", ..) => {
+                self.outline_only = value.get_some().expect("outline only");
+            }
+            subclass::Property("normalize", ..) => {
+                self.normalize = value.get_some().expect("normalize");
+            }
+            subclass::Property("threshold", ..) => {
+                self.threshold = value.get_some().expect("threshold");
+            }
+            subclass::Property("temporal-alpha", ..) => {
+                self.temporal_alpha = value.get_some().expect("temporal alpha");
+            }
+            subclass::Property("hard-mask", ..) => {
+                self.hard_mask = value.get_some().expect("hard mask");
+            }
+            subclass::Property("regions", ..) => {
+                self.regions = value.get_some().expect("regions");
+            }
+            subclass::Property("min-area", ..) => {
+                self.min_area = value.get_some().expect("min area");
+            }
+            _ => unimplemented!(),
         }
-        .unwrap();
+    }
 
-        let prediction = prediction.squeeze().unsqueeze(0);
-        let prediction: Tensor = {
-            let max = tch::Tensor::max(&prediction);
-            let min = tch::Tensor::min(&prediction);
-            (&prediction - &min) / (&max - &min)
-        };
-        let prediction = prediction * Tensor::of_slice(&[255f32]).to_device(tch::Device::Cuda(0));
+    fn get_property(&self, property: &subclass::Property) -> glib::Value {
+        match property {
+            subclass::Property("device", ..) => device::to_value(self.device),
+            subclass::Property("model-path", ..) => self.model_path.to_value(),
+            subclass::Property("attach-tensor", ..) => self.attach_tensor.to_value(),
+            subclass::Property("overlay", ..) => self.overlay.to_value(),
+            subclass::Property("alpha", ..) => self.alpha.to_value(),
+            subclass::Property("outline-only", ..) => self.outline_only.to_value(),
+            subclass::Property("normalize", ..) => self.normalize.to_value(),
+            subclass::Property("threshold", ..) => self.threshold.to_value(),
+            subclass::Property("temporal-alpha", ..) => self.temporal_alpha.to_value(),
+            subclass::Property("hard-mask", ..) => self.hard_mask.to_value(),
+            subclass::Property("regions", ..) => self.regions.to_value(),
+            subclass::Property("min-area", ..) => self.min_area.to_value(),
+            _ => unimplemented!(),
+        }
+    }
 
-        outbufs[0] = gst::Buffer::with_size((WIDTH * HEIGHT) as usize).unwrap();
-        let out_ref = outbufs[0].get_mut().unwrap();
-        out_ref.set_pts(in_ref.get_pts());
-        out_ref.set_dts(in_ref.get_pts());
-        out_ref.set_offset(in_ref.get_offset());
-        out_ref.set_duration(in_ref.get_duration());
-        let mut out_frame =
-            gst_video::VideoFrameRef::from_buffer_ref_writable(out_ref, &self.video_info_out)
-                .unwrap();
-        let out_data = out_frame.plane_data_mut(0).unwrap();
-        let pred_out = unsafe {
-            std::slice::from_raw_parts_mut(out_data.as_mut_ptr(), (WIDTH * HEIGHT) as usize)
-        };
-        prediction
-            .to_kind(tch::Kind::Uint8)
-            .copy_data(pred_out, (WIDTH * HEIGHT) as usize);
+    // The input on passthrough, and on mask in overlay mode instead of the GRAY8 template
+    fn src_caps(&self, src: usize) -> Option<gst::Caps> {
+        if self.overlay || src == 1 {
+            self.video_info_in.to_caps().ok()
+        } else {
+            None
+        }
+    }
+
+    fn messages(&mut self) -> Vec<gst::Structure> {
+        std::mem::replace(&mut self.messages, Vec::new())
+    }
 
+    fn prepare(&mut self) -> Result<(), gst::ErrorMessage> {
+        let model_path = models::resolve(&self.model_path, "salientobject/model.pt")?;
+        self.model = Some(models::load(&model_path, self.device)?);
+        self.smoothed = None;
+        self.imagenet = imagenet(self.device);
+        self.color_map = Some(
+            colormap::generate(colormap::Colormap::Turbo, colormap::LUT_SIZE)
+                .to_device(self.device),
+        );
         Ok(())
     }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn empty_frames_stay_dark() {
+        let saliency = Tensor::of_slice(&[0.01f32, 0.02, 0.9]).view((1, 1, 3));
+        let per_frame = Vec::<f32>::from(scale(&saliency, Normalize::PerFrame).flatten(0, -1));
+        assert!((per_frame[2] - 1.0).abs() < 1e-6);
+        // A frame without a salient object isn't stretched to full contrast
+        let empty = Tensor::of_slice(&[0.01f32, 0.02, 0.02]).view((1, 1, 3));
+        let none = Vec::<f32>::from(scale(&empty, Normalize::None).flatten(0, -1));
+        assert!(none.iter().all(|value| *value < 0.05));
+        let flat = Tensor::of_slice(&[0.3f32, 0.3, 0.3]).view((1, 1, 3));
+        let flat = Vec::<f32>::from(scale(&flat, Normalize::PerFrame).flatten(0, -1));
+        assert!(flat.iter().all(|value| *value == 0.0));
+        let sigmoid =
+            Vec::<f32>::from(scale(&empty.zeros_like(), Normalize::Sigmoid).flatten(0, -1));
+        assert!(sigmoid.iter().all(|value| (*value - 0.5).abs() < 1e-6));
+    }
+
+    #[test]
+    fn regions_of_large_blobs() {
+        #[rustfmt::skip]
+        let saliency = Tensor::of_slice(&[
+            0.9f32, 0.7, 0.0, 0.0, 0.0,
+            0.8,    0.0, 0.0, 0.0, 0.6,
+            0.0,    0.0, 0.0, 0.0, 0.0,
+            0.0,    0.0, 0.6, 0.6, 0.0,
+            0.0,    0.0, 0.6, 0.6, 0.0,
+        ])
+        .view((1, 5, 5));
+        let regions = regions(&saliency, 0.5, 2);
+        assert_eq!(regions.len(), 2);
+        assert_eq!(regions[0].bbox, [2, 3, 2, 2]);
+        assert_eq!(regions[0].area, 4);
+        assert!((regions[0].confidence - 0.6).abs() < 1e-6);
+        assert_eq!(regions[1].bbox, [0, 0, 2, 2]);
+        assert_eq!(regions[1].area, 3);
+        assert!((regions[1].confidence - 0.8).abs() < 1e-6);
+    }
 
-    fn set_property(&mut self, _property: &subclass::Property, _value: &glib::Value) {}
+    #[test]
+    fn smooths_and_hardens_over_frames() {
+        gst::init().unwrap();
+        let mut salient = SalientObject::default();
+        salient.normalize = Normalize::None;
+        salient.temporal_alpha = 0.5;
+        let on = Tensor::of_slice(&[1f32, 0.]).view((1, 1, 2));
+        let off = Tensor::of_slice(&[0f32, 0.]).view((1, 1, 2));
+        assert_eq!(
+            Vec::<f32>::from(salient.matte(&on).flatten(0, -1)),
+            vec![1., 0.]
+        );
+        assert_eq!(
+            Vec::<f32>::from(salient.matte(&off).flatten(0, -1)),
+            vec![0.5, 0.]
+        );
+        salient.hard_mask = true;
+        salient.threshold = 0.3;
+        assert_eq!(
+            Vec::<f32>::from(salient.matte(&off).flatten(0, -1)),
+            vec![0., 0.]
+        );
+        salient.smoothed = None;
+        assert_eq!(
+            Vec::<f32>::from(salient.matte(&on).flatten(0, -1)),
+            vec![1., 0.]
+        );
+    }
 }
@@ -1,68 +1,63 @@
-use std::env;
 use std::i32;
 use std::sync::Mutex;
 
 use crate::caps;
 use crate::cata;
+use crate::device;
+use crate::frame;
+use crate::labels;
+use crate::meta;
+use crate::models;
+use crate::morphology;
+use crate::overlay;
 use crate::registry;
+use crate::resize;
 
 use glib::subclass;
+use glib::{ToSendValue, ToValue};
 use gst;
 use gst_video;
 
 use tch;
 use tch::{TchError, Tensor};
 
+lazy_static! {
+    static ref CAT: gst::DebugCategory = gst::DebugCategory::new(
+        "semseg",
+        gst::DebugColorFlags::empty(),
+        Some("Semantic segmentation"),
+    );
+}
+
 const WIDTH: i32 = 640;
 const HEIGHT: i32 = 192;
 
-lazy_static! {
-    static ref IMAGENET_MEAN: Mutex<Tensor> = Mutex::new(
+// Channel mean and std the model was trained with, Tensor[[3, 1, 1], Float] on `device`
+fn imagenet(device: tch::Device) -> (Tensor, Tensor) {
+    (
         Tensor::of_slice(&[0.485f32, 0.456, 0.406])
-            .to_device(tch::Device::Cuda(0))
             .view((3, 1, 1))
-    );
-    static ref IMAGENET_STD: Mutex<Tensor> = Mutex::new(
+            .to_device(device),
         Tensor::of_slice(&[0.229f32, 0.224, 0.225])
-            .to_device(tch::Device::Cuda(0))
             .view((3, 1, 1))
-    );
+            .to_device(device),
+    )
 }
 
-pub fn normalize(tensor: &Tensor) -> Result<Tensor, TchError> {
-    let mean = IMAGENET_MEAN.lock().unwrap();
-    let std = IMAGENET_STD.lock().unwrap();
+pub fn normalize(tensor: &Tensor, (mean, std): &(Tensor, Tensor)) -> Result<Tensor, TchError> {
     (tensor.to_kind(tch::Kind::Float) / 255.0)
-        .f_sub(&mean)?
-        .f_div(&std)
+        .f_sub(mean)?
+        .f_div(std)
 }
 
-#[rustfmt::skip]
-fn label_map() -> Tensor {
-    let mut labels = vec![vec![30, 15, 60]; 19];
-    labels[ 0] = vec![128,  64, 128];  // 'road'
-    labels[ 1] = vec![244,  35, 232];  // 'sidewalk'
-    labels[ 2] = vec![ 70,  70,  70];  // 'building'
-    labels[ 3] = vec![102, 102, 156];  // 'wall'
-    labels[ 4] = vec![190, 153, 153];  // 'fence'
-    labels[ 5] = vec![153, 153, 153];  // 'pole'
-    labels[ 6] = vec![250, 170,  30];  // 'traffic light'
-    labels[ 7] = vec![220, 220,   0];  // 'traffic sign'
-    labels[ 8] = vec![107, 142,  35];  // 'vegetation'
-    labels[ 9] = vec![152, 251, 152];  // 'terrain'
-    labels[10] = vec![ 70, 130, 180];  // 'sky'
-    labels[11] = vec![220,  20,  60];  // 'person'
-    labels[12] = vec![255,   0,   0];  // 'rider'
-    labels[13] = vec![  0,   0, 142];  // 'car'
-    labels[14] = vec![  0,   0,  70];  // 'truck'
-    labels[15] = vec![  0,  60, 100];  // 'bus'
-    labels[16] = vec![  0,  80, 100];  // 'train'
-    labels[17] = vec![  0,   0, 230];  // 'motorcycle'
-    labels[18] = vec![119,  11,  32];  // 'bicycle'
-    
-    let labels = labels.into_iter().flatten().collect::<Vec<u8>>();
-    Tensor::of_slice(&labels)
-        .reshape(&[19, 1, 3])
+// Tensor[[3, 1, N], Uint8] colors of N labels
+fn label_map(labels: &[labels::Label]) -> Tensor {
+    let colors = labels
+        .iter()
+        .flat_map(|label| label.color.to_vec())
+        .collect::<Vec<u8>>();
+    Tensor::of_slice(&colors)
+        .reshape(&[labels.len() as i64, 1, 3])
         .permute(&[2, 1, 0])
 }
 
@@ -74,23 +69,151 @@ lazy_static! {
                 "format",
                 &gst::List::new(&[&gst_video::VideoFormat::Rgb.to_str()]),
             ),
-            ("width", &WIDTH),
-            ("height", &HEIGHT),
+            ("width", &gst::IntRange::<i32>::new(1, i32::MAX)),
+            ("height", &gst::IntRange::<i32>::new(1, i32::MAX)),
             (
                 "framerate",
                 &gst::FractionRange::new(gst::Fraction::new(0, 1), gst::Fraction::new(i32::MAX, 1),),
             ),
         ],
     ));
-    static ref SEMSEG_MODEL: Mutex<tch::CModule> = Mutex::new(
-        tch::CModule::load(env::var("SIMBOTIC_TORCH").unwrap() + "/models/semseg/semseg.pt")
-            .unwrap()
+    static ref CAPS_GRAY: Mutex<gst::Caps> = Mutex::new(gst::Caps::new_simple(
+        "video/x-raw",
+        &[
+            (
+                "format",
+                &gst::List::new(&[&gst_video::VideoFormat::Gray8.to_str()]),
+            ),
+            ("width", &gst::IntRange::<i32>::new(1, i32::MAX)),
+            ("height", &gst::IntRange::<i32>::new(1, i32::MAX)),
+            (
+                "framerate",
+                &gst::FractionRange::new(gst::Fraction::new(0, 1), gst::Fraction::new(i32::MAX, 1),),
+            ),
+        ],
+    ));
+}
+
+// Pixel coverage and bounding box of a class present in a frame
+#[derive(Debug, PartialEq)]
+struct ClassStats {
+    id: i64,
+    coverage: f64,
+    // Left, top, width and height in pixels
+    bbox: [i64; 4],
+}
+
+// Stats of the classes present in a Tensor[[H, W], Int64] of class ids, by increasing id
+fn class_stats(classes: &Tensor, num_labels: i64) -> Vec<ClassStats> {
+    let size = classes.size();
+    let total = (size[0] * size[1]) as f64;
+    let counts = Vec::<i64>::from(
+        classes
+            .flatten(0, -1)
+            .bincount::<Tensor>(None, num_labels)
+            .to_device(tch::Device::Cpu),
     );
+    counts
+        .iter()
+        .enumerate()
+        .filter(|(_, count)| **count > 0)
+        .map(|(id, count)| ClassStats {
+            id: id as i64,
+            coverage: *count as f64 / total,
+            bbox: morphology::bbox(&classes.eq(id as i64)).unwrap(),
+        })
+        .collect()
 }
 
+// Metadata for the properties
+static PROPERTIES: [subclass::Property; 13] = [
+    subclass::Property("device", |name| device::param_spec(name)),
+    subclass::Property("model-path", |name| models::path_param_spec(name)),
+    subclass::Property("resize-mode", |name| resize::param_spec(name)),
+    subclass::Property("attach-tensor", |name| meta::param_spec(name)),
+    subclass::Property("labels-file", |name| labels::param_spec(name)),
+    subclass::Property("classes", |name| {
+        glib::ParamSpec::string(
+            name,
+            "Classes",
+            "Comma separated names or ids of the classes on the mask pad",
+            None,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+    subclass::Property("dilate", |name| {
+        glib::ParamSpec::uint(
+            name,
+            "Dilate",
+            "Radius in pixels the mask is dilated by, after eroding",
+            0,
+            u32::MAX,
+            0,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+    subclass::Property("erode", |name| {
+        glib::ParamSpec::uint(
+            name,
+            "Erode",
+            "Radius in pixels the mask is eroded by",
+            0,
+            u32::MAX,
+            0,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+    subclass::Property("min-area", |name| {
+        glib::ParamSpec::uint(
+            name,
+            "Min area",
+            "Blobs of the mask smaller than this many pixels are dropped",
+            0,
+            u32::MAX,
+            0,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+    subclass::Property("overlay", |name| overlay::param_spec(name)),
+    subclass::Property("alpha", |name| overlay::alpha_param_spec(name)),
+    subclass::Property("outline-only", |name| overlay::outline_param_spec(name)),
+    subclass::Property("stats-interval", |name| {
+        glib::ParamSpec::uint(
+            name,
+            "Stats interval",
+            "Post a semseg-stats element message every N frames, 0 disables them",
+            0,
+            u32::MAX,
+            0,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+];
+
 pub struct SemSeg {
     video_info: gst_video::VideoInfo,
-    color_map: Tensor, // Tensor[[3, 1, 728], Uint8]
+    resize_mode: resize::ResizeMode,
+    device: tch::Device,
+    model_path: Option<String>,
+    model: Option<models::Model>,
+    labels_file: Option<String>,
+    labels: Vec<labels::Label>,
+    color_map: Option<Tensor>,  // Tensor[[3, 1, N], Uint8]
+    imagenet: (Tensor, Tensor), // Mean and std on the device
+    attach_tensor: bool,
+    labels_linked: bool,
+    mask_linked: bool,
+    classes: Option<String>,
+    class_ids: Vec<i64>,
+    dilate: u32,
+    erode: u32,
+    min_area: u32,
+    overlay: bool,
+    alpha: f64,
+    outline_only: bool,
+    stats_interval: u32,
+    frames: u64,
+    messages: Vec<gst::Structure>,
 }
 
 impl registry::Registry for SemSeg {
@@ -99,7 +222,7 @@ impl registry::Registry for SemSeg {
     register_typedata!();
 
     fn properties() -> &'static [glib::subclass::Property<'static>] {
-        &[]
+        &PROPERTIES
     }
 }
 
@@ -109,7 +232,28 @@ impl std::default::Default for SemSeg {
         caps.fixate();
         SemSeg {
             video_info: gst_video::VideoInfo::from_caps(&caps).unwrap(),
-            color_map: label_map().to_device(tch::Device::Cuda(0)),
+            resize_mode: resize::ResizeMode::Stretch,
+            device: device::default(),
+            model_path: None,
+            model: None,
+            labels_file: None,
+            labels: labels::cityscapes(),
+            color_map: None,
+            imagenet: imagenet(tch::Device::Cpu),
+            attach_tensor: false,
+            labels_linked: true,
+            mask_linked: true,
+            classes: None,
+            class_ids: Vec::new(),
+            dilate: 0,
+            erode: 0,
+            min_area: 0,
+            overlay: false,
+            alpha: 0.5,
+            outline_only: false,
+            stats_interval: 0,
+            frames: 0,
+            messages: Vec::new(),
         }
     }
 }
@@ -124,7 +268,232 @@ impl caps::CapsDef for SemSeg {
             name: "semseg",
             caps: CAPS.lock().unwrap().clone(),
         };
-        (vec![in_caps], vec![out_caps])
+        let labels_caps = caps::PadCaps {
+            name: "labels",
+            caps: CAPS_GRAY.lock().unwrap().clone(),
+        };
+        let mask_caps = caps::PadCaps {
+            name: "mask",
+            caps: CAPS_GRAY.lock().unwrap().clone(),
+        };
+        (vec![in_caps], vec![out_caps, labels_caps, mask_caps])
+    }
+}
+
+impl SemSeg {
+    fn resize(&self) -> resize::Resize {
+        resize::Resize::new(
+            self.resize_mode,
+            (
+                self.video_info.height() as i64,
+                self.video_info.width() as i64,
+            ),
+            (HEIGHT as i64, WIDTH as i64),
+        )
+    }
+
+    // Normalized Tensor[[1, 3, H, W], Float] at network resolution from an RGB buffer
+    fn input(
+        &self,
+        inbuf: &gst::Buffer,
+        resize: &resize::Resize,
+    ) -> Result<Tensor, cata::ProcessError> {
+        let in_frame = frame::map_readable(inbuf.as_ref(), &self.video_info)?;
+        let img = frame::to_tensor(&in_frame)
+            .f_to_device(self.device)?
+            .permute(&[2, 0, 1]);
+        let img = normalize(&img, &self.imagenet)?;
+        Ok(resize.to_network(&img.unsqueeze(0)))
+    }
+
+    // Tensor[[N, C, H, W], Float] class scores of a batch of input tensors
+    fn infer(&self, img: Tensor) -> Result<Tensor, cata::ProcessError> {
+        let img: tch::IValue = tch::IValue::Tensor(img);
+        let semseg_pred = self
+            .model
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .forward_is(&[img])?;
+        match &semseg_pred {
+            tch::IValue::Tensor(semseg_pred) if semseg_pred.dim() == 4 => {
+                Ok(semseg_pred.shallow_clone())
+            }
+            _ => Err(cata::ProcessError::Shape(
+                "Model didn't output a [N, C, H, W] tensor".to_string(),
+            )),
+        }
+    }
+
+    // GRAY8 frames on the labels and mask pads
+    fn gray_info(&self) -> Option<gst_video::VideoInfo> {
+        gst_video::VideoInfo::builder(
+            gst_video::VideoFormat::Gray8,
+            self.video_info.width(),
+            self.video_info.height(),
+        )
+        .fps(self.video_info.fps())
+        .par(self.video_info.par())
+        .build()
+        .ok()
+    }
+
+    // Tensor[[H, W], Int64] class ids at input resolution of a Tensor[[1, C, H, W], Float]
+    fn classes(
+        &self,
+        semseg_pred: &Tensor,
+        resize: &resize::Resize,
+    ) -> Result<Tensor, cata::ProcessError> {
+        let classes = semseg_pred.size()[1];
+        if classes > self.labels.len() as i64 {
+            return Err(cata::ProcessError::Shape(format!(
+                "Model predicts {} classes but only {} labels are defined",
+                classes,
+                self.labels.len()
+            )));
+        }
+        // Class scores are mapped back to the input resolution before picking labels
+        let semseg_pred = resize.from_network(semseg_pred).squeeze_dim(0);
+        Ok(semseg_pred.f_argmax(0, false)?)
+    }
+
+    // Colorizes class ids into a copy of their input buffer, blended onto it in overlay mode
+    fn colorize(
+        &self,
+        inbuf: &gst::Buffer,
+        classes: &Tensor,
+    ) -> Result<gst::Buffer, cata::ProcessError> {
+        let color_index = classes.flatten(0, 1);
+
+        let semseg_color = self
+            .color_map
+            .as_ref()
+            .unwrap()
+            .f_index_select(2, &color_index)?
+            .permute(&[2, 1, 0])
+            .reshape(&[classes.size()[0], classes.size()[1], 3]);
+
+        let semseg_color = if self.overlay {
+            let in_frame = frame::map_readable(inbuf.as_ref(), &self.video_info)?;
+            let img = frame::to_tensor(&in_frame).f_to_device(self.device)?;
+            let weight = if self.outline_only {
+                morphology::edges(&classes.unsqueeze(0).unsqueeze(0))
+                    .squeeze_dim(0)
+                    .squeeze_dim(0)
+            } else {
+                classes.ones_like().to_kind(tch::Kind::Float)
+            };
+            overlay::blend(&img, &semseg_color, &weight, self.alpha)
+        } else {
+            semseg_color.to_kind(tch::Kind::Uint8)
+        };
+
+        let mut semseg_buf = inbuf.copy();
+        {
+            let semseg_ref = semseg_buf.get_mut().unwrap();
+            let mut out_frame = frame::map_writable(semseg_ref, &self.video_info)?;
+            frame::copy_to_frame(&semseg_color, &mut out_frame)?;
+        }
+        Ok(semseg_buf)
+    }
+
+    // Tensor[[H, W], Uint8] 255 on the selected classes, 0 elsewhere
+    fn mask(&self, classes: &Tensor) -> Tensor {
+        let mask = self
+            .class_ids
+            .iter()
+            .fold(
+                classes.zeros_like().to_kind(tch::Kind::Float),
+                |mask, id| mask + classes.eq(*id).to_kind(tch::Kind::Float),
+            )
+            .clamp(0.0, 1.0)
+            .unsqueeze(0)
+            .unsqueeze(0);
+        let mask = morphology::erode(&mask, self.erode as i64);
+        let mask = morphology::dilate(&mask, self.dilate as i64);
+        let mask = morphology::remove_small(&mask, self.min_area as i64);
+        (mask.squeeze_dim(0).squeeze_dim(0) * 255.0).to_kind(tch::Kind::Uint8)
+    }
+
+    // GRAY8 frame of a Tensor[[H, W]], timestamped like its input buffer
+    fn gray(
+        &self,
+        inbuf: &gst::Buffer,
+        values: &Tensor,
+    ) -> Result<gst::Buffer, cata::ProcessError> {
+        let info = self
+            .gray_info()
+            .ok_or_else(|| cata::ProcessError::Shape("No GRAY8 frame for labels".to_string()))?;
+        let mut gray_buf = gst::Buffer::with_size(info.size())
+            .map_err(|_| cata::ProcessError::Mapping("Failed to allocate buffer".to_string()))?;
+        {
+            let in_ref = inbuf.as_ref();
+            let gray_ref = gray_buf.get_mut().unwrap();
+            gray_ref.set_pts(in_ref.get_pts());
+            gray_ref.set_dts(in_ref.get_dts());
+            gray_ref.set_offset(in_ref.get_offset());
+            gray_ref.set_duration(in_ref.get_duration());
+            let mut out_frame = frame::map_writable(gray_ref, &info)?;
+            frame::copy_to_frame(
+                &values.to_kind(tch::Kind::Uint8).unsqueeze(-1),
+                &mut out_frame,
+            )?;
+        }
+        Ok(gray_buf)
+    }
+
+    // semseg-stats message of the class ids of the frame at `pts`
+    fn stats(&self, pts: gst::ClockTime, classes: &Tensor) -> gst::Structure {
+        let stats = class_stats(classes, self.labels.len() as i64)
+            .into_iter()
+            .map(|stats| {
+                gst::Structure::builder("class")
+                    .field("id", &(stats.id as u32))
+                    .field("name", &self.labels[stats.id as usize].name)
+                    .field("coverage", &stats.coverage)
+                    .field("x", &(stats.bbox[0] as u32))
+                    .field("y", &(stats.bbox[1] as u32))
+                    .field("width", &(stats.bbox[2] as u32))
+                    .field("height", &(stats.bbox[3] as u32))
+                    .build()
+                    .to_send_value()
+            })
+            .collect::<Vec<glib::SendValue>>();
+        gst::Structure::builder("semseg-stats")
+            .field("pts", &pts)
+            .field("classes", &gst::List::from_owned(stats))
+            .build()
+    }
+
+    // Colorized, class id and mask buffers of one Tensor[[1, C, H, W], Float] prediction,
+    // and its semseg-stats message every stats-interval frames
+    fn outputs(
+        &mut self,
+        inbuf: &gst::Buffer,
+        semseg_pred: &Tensor,
+        resize: &resize::Resize,
+        outbuf: &mut Vec<gst::Buffer>,
+    ) -> Result<(), cata::ProcessError> {
+        let classes = self.classes(semseg_pred, resize)?;
+        outbuf[0] = self.colorize(inbuf, &classes)?;
+        if self.attach_tensor {
+            meta::TensorMeta::add(outbuf[0].get_mut().unwrap(), "logits", semseg_pred)?;
+        }
+        if self.labels_linked {
+            outbuf[1] = self.gray(inbuf, &classes)?;
+        }
+        if self.mask_linked {
+            outbuf[2] = self.gray(inbuf, &self.mask(&classes))?;
+        }
+        if self.stats_interval > 0 {
+            if self.frames % self.stats_interval as u64 == 0 {
+                let stats = self.stats(inbuf.get_pts(), &classes);
+                self.messages.push(stats);
+            }
+            self.frames += 1;
+        }
+        Ok(())
     }
 }
 
@@ -133,73 +502,180 @@ impl cata::Process for SemSeg {
         &mut self,
         inbuf: &Vec<gst::Buffer>,
         outbuf: &mut Vec<gst::Buffer>,
-    ) -> Result<(), std::io::Error> {
-        for (i, buf) in inbuf.iter().enumerate() {
-            if i < outbuf.len() {
-                outbuf[i] = buf.clone();
-            }
-        }
+    ) -> Result<(), cata::ProcessError> {
+        let resize = self.resize();
+        let img = self.input(&inbuf[0], &resize)?;
+        let semseg_pred = self.infer(img)?;
+        self.outputs(&inbuf[0], &semseg_pred, &resize, outbuf)
+    }
 
-        let mut semseg_buf = inbuf[0].copy();
-        {
-            let rgb_ref = inbuf[0].as_ref();
-            let in_frame =
-                gst_video::VideoFrameRef::from_buffer_ref_readable(rgb_ref, &self.video_info)
-                    .unwrap();
-            let _in_stride = in_frame.plane_stride()[0] as usize;
-            let _in_format = in_frame.format();
-            let in_width = in_frame.width() as i32;
-            let in_height = in_frame.height() as i32;
-            let in_data = in_frame.plane_data(0).unwrap();
+    // One forward pass over the stacked frames, results keep the buffers' timestamps
+    fn process_batch(
+        &mut self,
+        inbufs: &[Vec<gst::Buffer>],
+        outbufs: &mut Vec<Vec<gst::Buffer>>,
+    ) -> Result<(), cata::ProcessError> {
+        let resize = self.resize();
+        let imgs = inbufs
+            .iter()
+            .map(|inbuf| self.input(&inbuf[0], &resize))
+            .collect::<Result<Vec<Tensor>, cata::ProcessError>>()?;
+        let semseg_preds = self.infer(Tensor::f_cat(&imgs, 0)?)?;
+        for (i, (inbuf, outbuf)) in inbufs.iter().zip(outbufs.iter_mut()).enumerate() {
+            let semseg_pred = semseg_preds.f_narrow(0, i as i64, 1)?;
+            self.outputs(&inbuf[0], &semseg_pred, &resize, outbuf)?;
+        }
+        Ok(())
+    }
 
-            let semseg_ref = semseg_buf.get_mut().unwrap();
-            let mut out_frame =
-                gst_video::VideoFrameRef::from_buffer_ref_writable(semseg_ref, &self.video_info)
-                    .unwrap();
-            let _out_stride = out_frame.plane_stride()[0] as usize;
-            let _out_format = out_frame.format();
-            let out_data = out_frame.plane_data_mut(0).unwrap();
-
-            let img_slice = unsafe { std::slice::from_raw_parts(in_data.as_ptr(), in_data.len()) };
-            let img = Tensor::of_data_size(
-                img_slice,
-                &[in_height as i64, in_width as i64, 3],
-                tch::Kind::Uint8,
-            )
-            .to_device(tch::Device::Cuda(0))
-            .permute(&[2, 0, 1]);
-            let img = normalize(&img).unwrap();
-            let img: tch::IValue = tch::IValue::Tensor(img.unsqueeze(0));
+    fn set_property(&mut self, property: &subclass::Property, value: &glib::Value) {
+        match property {
+            subclass::Property("device", ..) => {
+                self.device = device::from_value(value, self.device);
+            }
+            subclass::Property("model-path", ..) => {
+                self.model_path = value.get().expect("model path");
+            }
+            subclass::Property("resize-mode", ..) => {
+                self.resize_mode = value.get_some().expect("resize mode");
+            }
+            subclass::Property("attach-tensor", ..) => {
+                self.attach_tensor = value.get_some().expect("attach tensor");
+            }
+            subclass::Property("labels-file", ..) => {
+                self.labels_file = value.get().expect("labels file");
+            }
+            subclass::Property("classes", ..) => {
+                self.classes = value.get().expect("classes");
+                // Before READY the selection is resolved once the labels are loaded
+                if self.model.is_some() {
+                    match labels::select(self.classes.as_deref().unwrap_or(""), &self.labels) {
+                        Ok(class_ids) => self.class_ids = class_ids,
+                        Err(err) => gst_warning!(CAT, "Keeping the previous classes: {}", err),
+                    }
+                }
+            }
+            subclass::Property("dilate", ..) => {
+                self.dilate = value.get_some().expect("dilate");
+            }
+            subclass::Property("erode", ..) => {
+                self.erode = value.get_some().expect("erode");
+            }
+            subclass::Property("min-area", ..) => {
+                self.min_area = value.get_some().expect("min area");
+            }
+            subclass::Property("overlay", ..) => {
+                self.overlay = value.get_some().expect("overlay");
+            }
+            subclass::Property("alpha", ..) => {
+                self.alpha = value.get_some().expect("alpha");
+            }
+            subclass::Property("outline-only", ..) => {
+                self.outline_only = value.get_some().expect("outline only");
+            }
+            subclass::Property("stats-interval", ..) => {
+                self.stats_interval = value.get_some().expect("stats interval");
+            }
+            _ => unimplemented!(),
+        }
+    }
 
-            let semseg_pred = SEMSEG_MODEL.lock().unwrap().forward_is(&[img]).unwrap();
-            let semseg_pred = if let tch::IValue::Tensor(semseg_pred) = &semseg_pred {
-                Some(semseg_pred)
-            } else {
-                None
-            };
-            let semseg_pred = semseg_pred.unwrap().squeeze();
-            let semseg_pred = semseg_pred.argmax(0, false).to_kind(tch::Kind::Uint8);
+    fn get_property(&self, property: &subclass::Property) -> glib::Value {
+        match property {
+            subclass::Property("device", ..) => device::to_value(self.device),
+            subclass::Property("model-path", ..) => self.model_path.to_value(),
+            subclass::Property("resize-mode", ..) => self.resize_mode.to_value(),
+            subclass::Property("attach-tensor", ..) => self.attach_tensor.to_value(),
+            subclass::Property("labels-file", ..) => self.labels_file.to_value(),
+            subclass::Property("classes", ..) => self.classes.to_value(),
+            subclass::Property("dilate", ..) => self.dilate.to_value(),
+            subclass::Property("erode", ..) => self.erode.to_value(),
+            subclass::Property("min-area", ..) => self.min_area.to_value(),
+            subclass::Property("overlay", ..) => self.overlay.to_value(),
+            subclass::Property("alpha", ..) => self.alpha.to_value(),
+            subclass::Property("outline-only", ..) => self.outline_only.to_value(),
+            subclass::Property("stats-interval", ..) => self.stats_interval.to_value(),
+            _ => unimplemented!(),
+        }
+    }
 
-            let color_index = semseg_pred.flatten(0, 1).to_kind(tch::Kind::Int64);
+    fn set_caps(&mut self, _sink: usize, caps: &gst::Caps) -> bool {
+        match gst_video::VideoInfo::from_caps(caps) {
+            Ok(video_info) => {
+                self.video_info = video_info;
+                true
+            }
+            Err(_) => false,
+        }
+    }
 
-            let semseg_color = self
-                .color_map
-                .index_select(2, &color_index)
-                .permute(&[2, 1, 0])
-                .to_device(tch::Device::Cpu);
+    // The colorized, class id and mask frames match the input resolution
+    fn src_caps(&self, src: usize) -> Option<gst::Caps> {
+        match src {
+            0 => self.video_info.to_caps().ok(),
+            _ => self.gray_info()?.to_caps().ok(),
+        }
+    }
 
-            let semseg_out = unsafe {
-                std::slice::from_raw_parts_mut(out_data.as_mut_ptr(), (WIDTH * HEIGHT * 3) as usize)
-            };
-            semseg_color
-                .to_kind(tch::Kind::Uint8)
-                .copy_data(semseg_out, (WIDTH * HEIGHT * 3) as usize);
+    fn src_linked(&mut self, src: usize, linked: bool) {
+        match src {
+            1 => self.labels_linked = linked,
+            2 => self.mask_linked = linked,
+            _ => {}
         }
+    }
 
-        outbuf[0] = semseg_buf;
+    fn messages(&mut self) -> Vec<gst::Structure> {
+        std::mem::replace(&mut self.messages, Vec::new())
+    }
 
+    fn prepare(&mut self) -> Result<(), gst::ErrorMessage> {
+        let model_path = models::resolve(&self.model_path, "semseg/semseg.pt")?;
+        self.model = Some(models::load(&model_path, self.device)?);
+        self.labels = labels::resolve(&self.labels_file)?;
+        self.color_map = Some(label_map(&self.labels).to_device(self.device));
+        self.imagenet = imagenet(self.device);
+        self.class_ids = labels::select(self.classes.as_deref().unwrap_or(""), &self.labels)
+            .map_err(|err| {
+                gst_error_msg!(
+                    gst::LibraryError::Settings,
+                    ["Invalid classes property"],
+                    ["{}", err]
+                )
+            })?;
         Ok(())
     }
+}
 
-    fn set_property(&mut self, _property: &subclass::Property, _value: &glib::Value) {}
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn class_coverage_and_boxes() {
+        #[rustfmt::skip]
+        let classes = Tensor::of_slice(&[
+            0i64, 0, 0, 0,
+            0, 2, 2, 0,
+            0, 0, 2, 0,
+        ])
+        .view((3, 4));
+        let stats = class_stats(&classes, 3);
+        assert_eq!(
+            stats,
+            vec![
+                ClassStats {
+                    id: 0,
+                    coverage: 9.0 / 12.0,
+                    bbox: [0, 0, 4, 3]
+                },
+                ClassStats {
+                    id: 2,
+                    coverage: 3.0 / 12.0,
+                    bbox: [1, 1, 2, 2]
+                },
+            ]
+        );
+    }
 }
@@ -0,0 +1,606 @@
+use std::i32;
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::caps;
+use crate::cata;
+use crate::device;
+use crate::frame;
+use crate::meta;
+use crate::models;
+use crate::registry;
+use crate::resize;
+
+use glib::subclass;
+use glib::{GEnum, StaticType, ToValue};
+use gst;
+use gst_video;
+
+use tch;
+use tch::Tensor;
+
+lazy_static! {
+    static ref CAT: gst::DebugCategory = gst::DebugCategory::new(
+        "torchfilter",
+        gst::DebugColorFlags::empty(),
+        Some("Generic TorchScript filter"),
+    );
+}
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, GEnum)]
+#[repr(u32)]
+#[genum(type_name = "SimboticChannelOrder")]
+pub enum ChannelOrder {
+    #[genum(name = "RGB", nick = "rgb")]
+    Rgb = 0,
+    #[genum(name = "BGR", nick = "bgr")]
+    Bgr = 1,
+}
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, GEnum)]
+#[repr(u32)]
+#[genum(type_name = "SimboticTorchOutput")]
+pub enum Output {
+    #[genum(name = "Image: [1, 3, H, W] scaled like the input", nick = "image")]
+    Image = 0,
+    #[genum(name = "Class map: [1, C, H, W] scores, colorized", nick = "class-map")]
+    ClassMap = 1,
+    #[genum(name = "Mask: [1, 1, H, W] in 0..1, as GRAY8", nick = "mask")]
+    Mask = 2,
+    #[genum(name = "Tensor: raw float32 output", nick = "tensor")]
+    Tensor = 3,
+}
+
+lazy_static! {
+    static ref CAPS_IN: Mutex<gst::Caps> = Mutex::new(gst::Caps::new_simple(
+        "video/x-raw",
+        &[
+            (
+                "format",
+                &gst::List::new(&[
+                    &gst_video::VideoFormat::Rgb.to_str(),
+                    &gst_video::VideoFormat::Bgr.to_str()
+                ]),
+            ),
+            ("width", &gst::IntRange::<i32>::new(1, i32::MAX)),
+            ("height", &gst::IntRange::<i32>::new(1, i32::MAX)),
+            (
+                "framerate",
+                &gst::FractionRange::new(gst::Fraction::new(0, 1), gst::Fraction::new(i32::MAX, 1),),
+            ),
+        ],
+    ));
+    static ref CAPS_OUT: Mutex<gst::Caps> = Mutex::new({
+        let mut caps = gst::Caps::new_simple(
+            "video/x-raw",
+            &[
+                (
+                    "format",
+                    &gst::List::new(&[
+                        &gst_video::VideoFormat::Rgb.to_str(),
+                        &gst_video::VideoFormat::Bgr.to_str(),
+                        &gst_video::VideoFormat::Gray8.to_str(),
+                    ]),
+                ),
+                ("width", &gst::IntRange::<i32>::new(1, i32::MAX)),
+                ("height", &gst::IntRange::<i32>::new(1, i32::MAX)),
+                (
+                    "framerate",
+                    &gst::FractionRange::new(
+                        gst::Fraction::new(0, 1),
+                        gst::Fraction::new(i32::MAX, 1),
+                    ),
+                ),
+            ],
+        );
+        caps.get_mut().unwrap().append(gst::Caps::new_simple(
+            caps::TENSOR_CAPS,
+            &[("dtype", &"float32")],
+        ));
+        caps
+    });
+}
+
+// Colors for up to 256 classes, spreading the bits of the class index over the channels
+fn palette() -> Tensor {
+    let mut colors = Vec::with_capacity(256 * 3);
+    for class in 0..256u32 {
+        let mut rgb = [0u8; 3];
+        let mut c = class;
+        for bit in 0..8 {
+            for (channel, value) in rgb.iter_mut().enumerate() {
+                *value |= (((c >> channel) & 1) << (7 - bit)) as u8;
+            }
+            c >>= 3;
+        }
+        colors.extend_from_slice(&rgb);
+    }
+    Tensor::of_slice(&colors).view((256, 3))
+}
+
+// Parses "a,b,c" into per-channel values, a single value applies to every channel
+fn parse_channels(values: &str) -> Option<Vec<f64>> {
+    let values: Vec<f64> = values
+        .split(',')
+        .map(|value| value.trim().parse::<f64>())
+        .collect::<Result<_, _>>()
+        .ok()?;
+    match values.len() {
+        1 => Some(vec![values[0]; 3]),
+        3 => Some(values),
+        _ => None,
+    }
+}
+
+fn channels_to_string(values: &[f64]) -> String {
+    values
+        .iter()
+        .map(|value| value.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn channels_from_value(name: &str, value: &glib::Value, current: &[f64]) -> Vec<f64> {
+    let values: Option<String> = value.get().expect("channel values");
+    match values.as_ref().and_then(|values| parse_channels(values)) {
+        Some(values) => values,
+        None => {
+            gst_warning!(
+                CAT,
+                "Invalid {} {:?}, expected 1 or 3 comma separated numbers",
+                name,
+                values
+            );
+            current.to_vec()
+        }
+    }
+}
+
+// Metadata for the properties
+static PROPERTIES: [subclass::Property; 11] = [
+    subclass::Property("model", |name| {
+        glib::ParamSpec::string(
+            name,
+            "Model",
+            "TorchScript model file",
+            None,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_READY,
+        )
+    }),
+    subclass::Property("device", |name| device::param_spec(name)),
+    subclass::Property("input-width", |name| {
+        glib::ParamSpec::uint(
+            name,
+            "Input width",
+            "Width the model expects, 0 to keep the frame width",
+            0,
+            i32::MAX as u32,
+            0,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_READY,
+        )
+    }),
+    subclass::Property("input-height", |name| {
+        glib::ParamSpec::uint(
+            name,
+            "Input height",
+            "Height the model expects, 0 to keep the frame height",
+            0,
+            i32::MAX as u32,
+            0,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_READY,
+        )
+    }),
+    subclass::Property("resize-mode", |name| resize::param_spec(name)),
+    subclass::Property("channel-order", |name| {
+        glib::ParamSpec::enum_(
+            name,
+            "Channel order",
+            "Channel order the model expects",
+            ChannelOrder::static_type(),
+            ChannelOrder::Rgb as i32,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+    subclass::Property("mean", |name| {
+        glib::ParamSpec::string(
+            name,
+            "Mean",
+            "Per-channel mean subtracted after scaling, e.g. 0.485,0.456,0.406",
+            Some("0,0,0"),
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+    subclass::Property("std", |name| {
+        glib::ParamSpec::string(
+            name,
+            "Std",
+            "Per-channel standard deviation dividing the scaled values, e.g. 0.229,0.224,0.225",
+            Some("1,1,1"),
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+    subclass::Property("scale", |name| {
+        glib::ParamSpec::double(
+            name,
+            "Scale",
+            "Factor applied to the 0..255 pixel values before mean and std",
+            0.0,
+            std::f64::MAX,
+            1.0 / 255.0,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+        )
+    }),
+    subclass::Property("output", |name| {
+        glib::ParamSpec::enum_(
+            name,
+            "Output",
+            "How the model output is interpreted",
+            Output::static_type(),
+            Output::Image as i32,
+            glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_READY,
+        )
+    }),
+    subclass::Property("attach-tensor", |name| meta::param_spec(name)),
+];
+
+pub struct TorchFilter {
+    video_info: Option<gst_video::VideoInfo>,
+    device: tch::Device,
+    model_path: Option<String>,
+    model: Option<models::Model>,
+    input_size: (u32, u32), // (height, width), 0 keeps the frame size
+    resize_mode: resize::ResizeMode,
+    channel_order: ChannelOrder,
+    mean: Vec<f64>,
+    std: Vec<f64>,
+    scale: f64,
+    output: Output,
+    output_shape: Vec<i64>, // Raw tensor shape, probed when going to READY
+    palette: Tensor,        // Tensor[[256, 3], Uint8] on the device
+    attach_tensor: bool,
+}
+
+impl registry::Registry for TorchFilter {
+    const NAME: &'static str = "torchfilter";
+    const DEBUG_CATEGORY: &'static str = "torchfilter";
+    register_typedata!();
+
+    fn properties() -> &'static [glib::subclass::Property<'static>] {
+        &PROPERTIES
+    }
+}
+
+impl std::default::Default for TorchFilter {
+    fn default() -> Self {
+        TorchFilter {
+            video_info: None,
+            device: device::default(),
+            model_path: None,
+            model: None,
+            input_size: (0, 0),
+            resize_mode: resize::ResizeMode::Stretch,
+            channel_order: ChannelOrder::Rgb,
+            mean: vec![0.0; 3],
+            std: vec![1.0; 3],
+            scale: 1.0 / 255.0,
+            output: Output::Image,
+            output_shape: Vec::new(),
+            palette: palette(),
+            attach_tensor: false,
+        }
+    }
+}
+
+impl caps::CapsDef for TorchFilter {
+    fn caps_def() -> (Vec<caps::PadCaps>, Vec<caps::PadCaps>) {
+        let in_caps = caps::PadCaps {
+            name: "sink",
+            caps: CAPS_IN.lock().unwrap().clone(),
+        };
+        let out_caps = caps::PadCaps {
+            name: "src",
+            caps: CAPS_OUT.lock().unwrap().clone(),
+        };
+        (vec![in_caps], vec![out_caps])
+    }
+}
+
+impl TorchFilter {
+    fn channel_stats(&self, values: &[f64]) -> Tensor {
+        let values: Vec<f32> = values.iter().map(|value| *value as f32).collect();
+        Tensor::of_slice(&values)
+            .view((1, 3, 1, 1))
+            .to_device(self.device)
+    }
+
+    // Whether frames have to be flipped between RGB and BGR for the model
+    fn flip_channels(&self, video_info: &gst_video::VideoInfo) -> bool {
+        let frame_order = match video_info.format() {
+            gst_video::VideoFormat::Bgr => ChannelOrder::Bgr,
+            _ => ChannelOrder::Rgb,
+        };
+        frame_order != self.channel_order
+    }
+
+    fn resize(&self, input: (i64, i64)) -> resize::Resize {
+        let network = match self.input_size {
+            (0, 0) => input,
+            (0, width) => (input.0, width as i64),
+            (height, 0) => (height as i64, input.1),
+            (height, width) => (height as i64, width as i64),
+        };
+        resize::Resize::new(self.resize_mode, input, network)
+    }
+
+    fn forward(&self, img: Tensor) -> Result<Tensor, cata::ProcessError> {
+        let output = self
+            .model
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .forward_is(&[tch::IValue::Tensor(img)])?;
+        // Models returning several outputs are read from the first one
+        let output = match &output {
+            tch::IValue::Tensor(tensor) => Some(tensor),
+            tch::IValue::Tuple(outputs) => match outputs.first() {
+                Some(tch::IValue::Tensor(tensor)) => Some(tensor),
+                _ => None,
+            },
+            _ => None,
+        };
+        output
+            .map(|tensor| tensor.shallow_clone())
+            .ok_or_else(|| cata::ProcessError::Shape("Model didn't output a tensor".to_string()))
+    }
+
+    // Frames produced by the image, class map and mask outputs
+    fn out_info(&self) -> Option<gst_video::VideoInfo> {
+        let video_info = self.video_info.as_ref()?;
+        let format = match self.output {
+            Output::Image => video_info.format(),
+            Output::ClassMap => gst_video::VideoFormat::Rgb,
+            _ => gst_video::VideoFormat::Gray8,
+        };
+        gst_video::VideoInfo::builder(format, video_info.width(), video_info.height())
+            .fps(video_info.fps())
+            .par(video_info.par())
+            .build()
+            .ok()
+    }
+
+    fn expect_channels(output: &Tensor, channels: Option<i64>) -> Result<(), cata::ProcessError> {
+        let size = output.size();
+        let valid = size.len() == 4 && channels.map_or(true, |channels| size[1] == channels);
+        if valid {
+            Ok(())
+        } else {
+            Err(cata::ProcessError::Shape(format!(
+                "Model output {:?} doesn't match output mode, expected [1, {}, H, W]",
+                size,
+                channels.map_or("C".to_string(), |channels| channels.to_string())
+            )))
+        }
+    }
+
+    // Runs the model on a zero frame to find the shape of raw tensor outputs
+    fn probe_output_shape(&self) -> Result<Vec<i64>, gst::ErrorMessage> {
+        let (height, width) = match self.input_size {
+            (0, _) | (_, 0) => {
+                return Err(gst_error_msg!(
+                    gst::LibraryError::Settings,
+                    ["Tensor output needs input-width and input-height"]
+                ))
+            }
+            (height, width) => (height as i64, width as i64),
+        };
+        let img = Tensor::zeros(&[1, 3, height, width], (tch::Kind::Float, self.device));
+        self.forward(img)
+            .map(|output| output.size())
+            .map_err(|err| {
+                gst_error_msg!(
+                    gst::LibraryError::Failed,
+                    ["Failed to probe model output shape"],
+                    ["{}", err]
+                )
+            })
+    }
+}
+
+impl cata::Process for TorchFilter {
+    fn process(
+        &mut self,
+        inbuf: &Vec<gst::Buffer>,
+        outbuf: &mut Vec<gst::Buffer>,
+    ) -> Result<(), cata::ProcessError> {
+        let video_info = self.video_info.as_ref().unwrap();
+        let in_ref = inbuf[0].as_ref();
+        let in_frame = frame::map_readable(in_ref, video_info)?;
+        let resize = self.resize((in_frame.height() as i64, in_frame.width() as i64));
+        let flip = self.flip_channels(video_info);
+
+        let mean = self.channel_stats(&self.mean);
+        let stddev = self.channel_stats(&self.std);
+
+        let img = frame::to_tensor(&in_frame)
+            .f_to_device(self.device)?
+            .permute(&[2, 0, 1])
+            .unsqueeze(0)
+            .to_kind(tch::Kind::Float);
+        let img = if flip { img.flip(&[1]) } else { img };
+        let img = (img * self.scale).f_sub(&mean)?.f_div(&stddev)?;
+        let output = self.forward(resize.to_network(&img))?;
+
+        if self.output == Output::Tensor {
+            let mut tensor_buf = frame::to_buffer(&output)?;
+            {
+                let tensor_ref = tensor_buf.get_mut().unwrap();
+                tensor_ref.set_pts(in_ref.get_pts());
+                tensor_ref.set_dts(in_ref.get_dts());
+                tensor_ref.set_offset(in_ref.get_offset());
+                tensor_ref.set_duration(in_ref.get_duration());
+            }
+            outbuf[0] = tensor_buf;
+            return Ok(());
+        }
+
+        // Tensor[[H, W, C], Uint8] at input resolution
+        let out_img = match self.output {
+            Output::Image => {
+                Self::expect_channels(&output, Some(3))?;
+                let out_img =
+                    resize.from_network(&output).f_mul(&stddev)?.f_add(&mean)? / self.scale;
+                let out_img = if flip { out_img.flip(&[1]) } else { out_img };
+                out_img.clamp(0.0, 255.0).squeeze_dim(0).permute(&[1, 2, 0])
+            }
+            Output::ClassMap => {
+                Self::expect_channels(&output, None)?;
+                let labels = resize
+                    .from_network(&output)
+                    .f_argmax(1, false)?
+                    .clamp(0, 255)
+                    .flatten(0, -1);
+                self.palette.f_index_select(0, &labels)?
+            }
+            _ => {
+                Self::expect_channels(&output, Some(1))?;
+                let mask = resize.from_network(&output).clamp(0.0, 1.0) * 255.0;
+                mask.squeeze_dim(0).permute(&[1, 2, 0])
+            }
+        };
+
+        let out_info = self.out_info().unwrap();
+        let mut out_buf = gst::Buffer::with_size(out_info.size())
+            .map_err(|_| cata::ProcessError::Mapping("Failed to allocate buffer".to_string()))?;
+        {
+            let out_ref = out_buf.get_mut().unwrap();
+            out_ref.set_pts(in_ref.get_pts());
+            out_ref.set_dts(in_ref.get_dts());
+            out_ref.set_offset(in_ref.get_offset());
+            out_ref.set_duration(in_ref.get_duration());
+            let mut out_frame = frame::map_writable(out_ref, &out_info)?;
+            frame::copy_to_frame(&out_img.to_kind(tch::Kind::Uint8), &mut out_frame)?;
+        }
+        // Tensor output already carries the raw data
+        if self.attach_tensor {
+            meta::TensorMeta::add(out_buf.get_mut().unwrap(), "output", &output)?;
+        }
+        outbuf[0] = out_buf;
+
+        Ok(())
+    }
+
+    fn set_property(&mut self, property: &subclass::Property, value: &glib::Value) {
+        match property {
+            subclass::Property("model", ..) => {
+                self.model_path = value.get().expect("model path");
+            }
+            subclass::Property("device", ..) => {
+                self.device = device::from_value(value, self.device);
+            }
+            subclass::Property("input-width", ..) => {
+                self.input_size.1 = value.get_some().expect("input width");
+            }
+            subclass::Property("input-height", ..) => {
+                self.input_size.0 = value.get_some().expect("input height");
+            }
+            subclass::Property("resize-mode", ..) => {
+                self.resize_mode = value.get_some().expect("resize mode");
+            }
+            subclass::Property("channel-order", ..) => {
+                self.channel_order = value.get_some().expect("channel order");
+            }
+            subclass::Property("mean", ..) => {
+                self.mean = channels_from_value("mean", value, &self.mean);
+            }
+            subclass::Property("std", ..) => {
+                self.std = channels_from_value("std", value, &self.std);
+            }
+            subclass::Property("scale", ..) => {
+                self.scale = value.get_some().expect("scale");
+            }
+            subclass::Property("output", ..) => {
+                self.output = value.get_some().expect("output");
+            }
+            subclass::Property("attach-tensor", ..) => {
+                self.attach_tensor = value.get_some().expect("attach tensor");
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    fn get_property(&self, property: &subclass::Property) -> glib::Value {
+        match property {
+            subclass::Property("model", ..) => self.model_path.to_value(),
+            subclass::Property("device", ..) => device::to_value(self.device),
+            subclass::Property("input-width", ..) => self.input_size.1.to_value(),
+            subclass::Property("input-height", ..) => self.input_size.0.to_value(),
+            subclass::Property("resize-mode", ..) => self.resize_mode.to_value(),
+            subclass::Property("channel-order", ..) => self.channel_order.to_value(),
+            subclass::Property("mean", ..) => channels_to_string(&self.mean).to_value(),
+            subclass::Property("std", ..) => channels_to_string(&self.std).to_value(),
+            subclass::Property("scale", ..) => self.scale.to_value(),
+            subclass::Property("output", ..) => self.output.to_value(),
+            subclass::Property("attach-tensor", ..) => self.attach_tensor.to_value(),
+            _ => unimplemented!(),
+        }
+    }
+
+    fn set_caps(&mut self, _sink: usize, caps: &gst::Caps) -> bool {
+        match gst_video::VideoInfo::from_caps(caps) {
+            Ok(video_info) => {
+                self.video_info = Some(video_info);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    // Images and masks match the input resolution, raw tensors carry their shape
+    fn src_caps(&self, _src: usize) -> Option<gst::Caps> {
+        if self.output == Output::Tensor {
+            return Some(caps::tensor_caps(&self.output_shape));
+        }
+        self.out_info()?.to_caps().ok()
+    }
+
+    fn prepare(&mut self) -> Result<(), gst::ErrorMessage> {
+        let model_path = self.model_path.as_ref().ok_or_else(|| {
+            gst_error_msg!(
+                gst::ResourceError::NotFound,
+                ["No model set, point the model property to a TorchScript file"]
+            )
+        })?;
+        self.model = Some(models::load(Path::new(model_path), self.device)?);
+        self.palette = palette().to_device(self.device);
+        if self.output == Output::Tensor {
+            self.output_shape = self.probe_output_shape()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn parses_channel_values() {
+        assert_eq!(
+            parse_channels("0.485, 0.456,0.406"),
+            Some(vec![0.485, 0.456, 0.406])
+        );
+        assert_eq!(parse_channels("0.5"), Some(vec![0.5; 3]));
+        assert_eq!(parse_channels("1,2"), None);
+        assert_eq!(parse_channels("a,b,c"), None);
+    }
+
+    #[test]
+    fn palette_separates_classes() {
+        let palette = palette();
+        assert_eq!(palette.size(), vec![256, 3]);
+        let colors: Vec<u8> = Vec::from(palette.narrow(0, 0, 3).flatten(0, 1));
+        assert_eq!(colors, vec![0, 0, 0, 128, 0, 0, 0, 128, 0]);
+    }
+}
@@ -0,0 +1,384 @@
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+use std::time::Instant;
+
+use glib;
+use glib::GEnum;
+use gst;
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, GEnum)]
+#[repr(u32)]
+#[genum(type_name = "SimboticLeaky")]
+pub enum Leaky {
+    #[genum(name = "No: block upstream until there is room", nick = "no")]
+    No = 0,
+    #[genum(name = "Upstream: drop the newest frames", nick = "upstream")]
+    Upstream = 1,
+    #[genum(name = "Downstream: drop the oldest frames", nick = "downstream")]
+    Downstream = 2,
+}
+
+pub const DEFAULT_QUEUE_SIZE: u32 = 2;
+
+// Work handed from the streaming thread to the inference worker
+pub enum Item {
+    // Zipped buffers and when they were received
+    Buffers(Vec<gst::Buffer>, Instant),
+    // Serialized event received on a sink pad, never dropped
+    Event(gst::Pad, gst::Event),
+}
+
+struct State {
+    items: VecDeque<Item>,
+    frames: usize,
+    flushing: bool,
+    shutdown: bool,
+    error: Option<gst::FlowError>,
+    dropped: u64,
+}
+
+// Bounded queue between the streaming thread and the inference worker.
+// Only buffers count towards the size and can be dropped.
+pub struct Queue {
+    cat: gst::DebugCategory,
+    state: Mutex<State>,
+    cond: Condvar,
+    pub size: Mutex<usize>,
+    pub leaky: Mutex<Leaky>,
+}
+
+impl Queue {
+    pub fn new(cat: gst::DebugCategory) -> Queue {
+        Queue {
+            cat,
+            state: Mutex::new(State {
+                items: VecDeque::new(),
+                frames: 0,
+                flushing: false,
+                shutdown: false,
+                error: None,
+                dropped: 0,
+            }),
+            cond: Condvar::new(),
+            size: Mutex::new(DEFAULT_QUEUE_SIZE as usize),
+            leaky: Mutex::new(Leaky::Downstream),
+        }
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.state.lock().unwrap().dropped
+    }
+
+    // Queues an item, returns the flow to report upstream
+    pub fn push(&self, item: Item) -> Result<gst::FlowSuccess, gst::FlowError> {
+        let size = (*self.size.lock().unwrap()).max(1);
+        let leaky = *self.leaky.lock().unwrap();
+        let mut state = self.state.lock().unwrap();
+        if let Item::Buffers(..) = item {
+            while state.frames >= size
+                && !state.flushing
+                && !state.shutdown
+                && state.error.is_none()
+            {
+                match leaky {
+                    Leaky::No => state = self.cond.wait(state).unwrap(),
+                    Leaky::Upstream => {
+                        state.dropped += 1;
+                        gst_debug!(self.cat, "Queue full, dropped newest frame");
+                        return Ok(gst::FlowSuccess::Ok);
+                    }
+                    Leaky::Downstream => {
+                        let oldest = state
+                            .items
+                            .iter()
+                            .position(|item| match item {
+                                Item::Buffers(..) => true,
+                                _ => false,
+                            })
+                            .unwrap();
+                        state.items.remove(oldest);
+                        state.frames -= 1;
+                        state.dropped += 1;
+                        gst_debug!(self.cat, "Queue full, dropped oldest frame");
+                    }
+                }
+            }
+        }
+        if state.flushing || state.shutdown {
+            return Err(gst::FlowError::Flushing);
+        }
+        if let Some(error) = state.error {
+            return Err(error);
+        }
+        if let Item::Buffers(..) = item {
+            state.frames += 1;
+        }
+        state.items.push_back(item);
+        self.cond.notify_all();
+        Ok(gst::FlowSuccess::Ok)
+    }
+
+    // Next item for the worker, None once shut down
+    pub fn pop(&self) -> Option<Item> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.shutdown {
+                return None;
+            }
+            if let Some(item) = state.items.pop_front() {
+                if let Item::Buffers(..) = item {
+                    state.frames -= 1;
+                }
+                self.cond.notify_all();
+                return Some(item);
+            }
+            state = self.cond.wait(state).unwrap();
+        }
+    }
+
+    // Flow error of the worker, reported to upstream on the next push
+    pub fn set_error(&self, error: gst::FlowError) {
+        self.state.lock().unwrap().error = Some(error);
+        self.cond.notify_all();
+    }
+
+    pub fn set_flushing(&self, flushing: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.flushing = flushing;
+        state.items.clear();
+        state.frames = 0;
+        state.error = None;
+        self.cond.notify_all();
+    }
+
+    pub fn start(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.shutdown = false;
+        state.flushing = false;
+        state.error = None;
+    }
+
+    pub fn shutdown(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.shutdown = true;
+        state.items.clear();
+        state.frames = 0;
+        self.cond.notify_all();
+    }
+}
+
+struct Timer {
+    deadline: Option<Instant>,
+    shutdown: bool,
+}
+
+// Deadline a flushing thread sleeps until, moved whenever a new partial batch starts
+pub struct Deadline {
+    timer: Mutex<Timer>,
+    cond: Condvar,
+}
+
+impl Deadline {
+    pub fn new() -> Deadline {
+        Deadline {
+            timer: Mutex::new(Timer {
+                deadline: None,
+                shutdown: false,
+            }),
+            cond: Condvar::new(),
+        }
+    }
+
+    pub fn set(&self, deadline: Option<Instant>) {
+        self.timer.lock().unwrap().deadline = deadline;
+        self.cond.notify_all();
+    }
+
+    // Blocks until the deadline passes, false once shut down
+    pub fn wait(&self) -> bool {
+        let mut timer = self.timer.lock().unwrap();
+        loop {
+            if timer.shutdown {
+                return false;
+            }
+            match timer.deadline {
+                Some(deadline) if deadline <= Instant::now() => {
+                    timer.deadline = None;
+                    return true;
+                }
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    timer = self.cond.wait_timeout(timer, timeout).unwrap().0;
+                }
+                None => timer = self.cond.wait(timer).unwrap(),
+            }
+        }
+    }
+
+    pub fn start(&self) {
+        let mut timer = self.timer.lock().unwrap();
+        timer.deadline = None;
+        timer.shutdown = false;
+    }
+
+    pub fn shutdown(&self) {
+        self.timer.lock().unwrap().shutdown = true;
+        self.cond.notify_all();
+    }
+}
+
+// Turns taken while holding one lock, and served in the same order once it is released,
+// so results of batches processed one after another are pushed in that order
+pub struct Turns {
+    // Next turn to hand out and the turn being served
+    turns: Mutex<(u64, u64)>,
+    cond: Condvar,
+}
+
+// A turn, the next one is served once it is dropped
+pub struct Turn<'a> {
+    turns: &'a Turns,
+}
+
+impl Turns {
+    pub fn new() -> Turns {
+        Turns {
+            turns: Mutex::new((0, 0)),
+            cond: Condvar::new(),
+        }
+    }
+
+    // Next turn, taken while holding the lock that orders the work
+    pub fn take(&self) -> u64 {
+        let mut turns = self.turns.lock().unwrap();
+        let turn = turns.0;
+        turns.0 += 1;
+        turn
+    }
+
+    // Blocks until every earlier turn is done
+    pub fn wait(&self, turn: u64) -> Turn {
+        let mut turns = self.turns.lock().unwrap();
+        while turns.1 != turn {
+            turns = self.cond.wait(turns).unwrap();
+        }
+        Turn { turns: self }
+    }
+}
+
+impl<'a> Drop for Turn<'a> {
+    fn drop(&mut self) {
+        self.turns.turns.lock().unwrap().1 += 1;
+        self.turns.cond.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::time::Duration;
+
+    fn queue(leaky: Leaky) -> Queue {
+        gst::init().unwrap();
+        let cat = gst::DebugCategory::new("worker-test", gst::DebugColorFlags::empty(), None);
+        let queue = Queue::new(cat);
+        *queue.leaky.lock().unwrap() = leaky;
+        queue
+    }
+
+    fn frame(ms: u64) -> Item {
+        let mut buffer = gst::Buffer::new();
+        buffer
+            .get_mut()
+            .unwrap()
+            .set_pts(gst::ClockTime::from_mseconds(ms));
+        Item::Buffers(vec![buffer], Instant::now())
+    }
+
+    fn drain(queue: &Queue) -> Vec<u64> {
+        let mut pts = Vec::new();
+        while queue.state.lock().unwrap().frames > 0 {
+            if let Some(Item::Buffers(buffers, _)) = queue.pop() {
+                pts.push(buffers[0].get_pts().mseconds().unwrap());
+            }
+        }
+        pts
+    }
+
+    #[test]
+    fn downstream_drops_oldest() {
+        let queue = queue(Leaky::Downstream);
+        for ms in 0..4 {
+            queue.push(frame(ms * 40)).unwrap();
+        }
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(drain(&queue), vec![80, 120]);
+    }
+
+    #[test]
+    fn upstream_drops_newest() {
+        let queue = queue(Leaky::Upstream);
+        for ms in 0..4 {
+            queue.push(frame(ms * 40)).unwrap();
+        }
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(drain(&queue), vec![0, 40]);
+    }
+
+    #[test]
+    fn deadline_wakes_on_time() {
+        let deadline = Deadline::new();
+        let started = Instant::now();
+        deadline.set(Some(started + Duration::from_millis(20)));
+        assert!(deadline.wait());
+        assert!(started.elapsed() >= Duration::from_millis(20));
+        deadline.shutdown();
+        assert!(!deadline.wait());
+    }
+
+    #[test]
+    fn error_unblocks_full_queue() {
+        let queue = std::sync::Arc::new(queue(Leaky::No));
+        queue.push(frame(0)).unwrap();
+        queue.push(frame(40)).unwrap();
+        let worker = queue.clone();
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            worker.set_error(gst::FlowError::Error);
+        });
+        assert_eq!(queue.push(frame(80)), Err(gst::FlowError::Error));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn turns_are_served_in_order() {
+        let turns = std::sync::Arc::new(Turns::new());
+        let first = turns.take();
+        let second = turns.take();
+        let served = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let (later, log) = (turns.clone(), served.clone());
+        let handle = std::thread::spawn(move || {
+            let _turn = later.wait(second);
+            log.lock().unwrap().push(second);
+        });
+        std::thread::sleep(Duration::from_millis(20));
+        {
+            let _turn = turns.wait(first);
+            served.lock().unwrap().push(first);
+        }
+        handle.join().unwrap();
+        assert_eq!(*served.lock().unwrap(), vec![first, second]);
+    }
+
+    #[test]
+    fn flushing_rejects_items() {
+        let queue = queue(Leaky::No);
+        queue.push(frame(0)).unwrap();
+        queue.set_flushing(true);
+        assert_eq!(queue.push(frame(40)), Err(gst::FlowError::Flushing));
+        queue.set_flushing(false);
+        queue.push(frame(80)).unwrap();
+        assert_eq!(drain(&queue), vec![80]);
+    }
+}
@@ -1,31 +1,412 @@
+use std::collections::VecDeque;
+
+use glib;
+use glib::GEnum;
+use gst;
 use gst::Buffer;
 
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, GEnum)]
+#[repr(u32)]
+#[genum(type_name = "SimboticZipPolicy")]
+pub enum ZipPolicy {
+    #[genum(
+        name = "Latest: newest buffer of every pad, ignoring timestamps",
+        nick = "latest"
+    )]
+    Latest = 0,
+    #[genum(
+        name = "Strict PTS: only buffers within the tolerance",
+        nick = "strict-pts"
+    )]
+    StrictPts = 1,
+    #[genum(
+        name = "Nearest: closest buffer to the first pad's PTS",
+        nick = "nearest"
+    )]
+    Nearest = 2,
+}
+
+pub const DEFAULT_TOLERANCE: u64 = 20_000_000;
+pub const DEFAULT_MAX_QUEUE: u32 = 8;
+
+fn pts(buffer: &Buffer) -> Option<u64> {
+    buffer.get_pts().nseconds()
+}
+
+fn distance(a: u64, b: u64) -> u64 {
+    if a > b {
+        a - b
+    } else {
+        b - a
+    }
+}
+
 pub struct Zipper {
-    buffers: Vec<Vec<Buffer>>,
+    cat: gst::DebugCategory,
+    buffers: Vec<VecDeque<Buffer>>,
+    // Sticky pads aren't matched, their latest buffer joins every zip
+    sticky: Vec<bool>,
+    latest: Vec<Option<Buffer>>,
+    // Sticky pads of non-live sources, their buffers wait until the zips catch up with their PTS
+    paced: Vec<bool>,
+    // Flow returned to paced pads instead of waiting, once the zips can't catch up
+    pub pacing: Result<(), gst::FlowError>,
+    pub policy: ZipPolicy,
+    pub tolerance: u64,
+    pub max_queue: usize,
+    dropped: u64,
 }
 
 impl Zipper {
-    pub fn with_size(size: usize) -> Zipper {
+    pub fn with_size(size: usize, cat: gst::DebugCategory) -> Zipper {
         Zipper {
-            buffers: vec![Vec::new(); size],
+            cat,
+            buffers: vec![VecDeque::new(); size],
+            sticky: vec![false; size],
+            latest: vec![None; size],
+            paced: vec![false; size],
+            pacing: Ok(()),
+            policy: ZipPolicy::Latest,
+            tolerance: DEFAULT_TOLERANCE,
+            max_queue: DEFAULT_MAX_QUEUE as usize,
+            dropped: 0,
+        }
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    // Pad `idx` no longer holds back zips, an empty buffer stands in until its first one
+    pub fn set_sticky(&mut self, idx: usize) {
+        self.sticky[idx] = true;
+    }
+
+    pub fn is_sticky(&self, idx: usize) -> bool {
+        self.sticky[idx]
+    }
+
+    // Sticky pad `idx` only joins zips from the first whose PTS reaches its buffer's
+    pub fn set_paced(&mut self, idx: usize, paced: bool) {
+        self.paced[idx] = self.sticky[idx] && paced;
+    }
+
+    // Whether a paced pad has to wait for the zips before pushing another buffer
+    pub fn is_backlogged(&self, idx: usize) -> bool {
+        self.paced[idx] && self.buffers[idx].len() >= self.max_queue.max(1)
+    }
+
+    pub fn clear(&mut self) {
+        for queue in self.buffers.iter_mut() {
+            queue.clear();
+        }
+        for latest in self.latest.iter_mut() {
+            *latest = None;
+        }
+    }
+
+    fn sticky_buffer(&self, idx: usize) -> Buffer {
+        self.latest[idx].clone().unwrap_or_else(Buffer::new)
+    }
+
+    // Pads matched by the zip policy
+    fn matched(&self) -> Vec<usize> {
+        (0..self.buffers.len())
+            .filter(|&idx| !self.sticky[idx])
+            .collect()
+    }
+
+    fn drop_front(&mut self, idx: usize, reason: &str) {
+        if let Some(buffer) = self.buffers[idx].pop_front() {
+            self.dropped += 1;
+            gst_debug!(
+                self.cat,
+                "Dropped buffer {:?} from pad {} ({}), {} dropped so far",
+                buffer.get_pts(),
+                idx,
+                reason,
+                self.dropped
+            );
         }
     }
 
     pub fn push(&mut self, buffer: Buffer, idx: usize) {
-        self.buffers[idx].push(buffer);
+        if self.sticky[idx] && !self.paced[idx] {
+            self.latest[idx] = Some(buffer);
+            return;
+        }
+        self.buffers[idx].push_back(buffer);
+        while self.buffers[idx].len() > self.max_queue.max(1) {
+            self.drop_front(idx, "queue full");
+        }
     }
 
     pub fn try_zip(&mut self) -> Option<Vec<Buffer>> {
-        let filled = self.buffers.iter().filter(|&buf| buf.len() == 0).count() == 0;
-        if filled {
-            let mut zip = Vec::new();
-            for idx in 0..self.buffers.len() {
-                zip.push(self.buffers[idx].pop().unwrap());
-                self.buffers[idx].clear();
+        let matched = self.matched();
+        let filled = matched.iter().all(|&idx| !self.buffers[idx].is_empty());
+        if !filled {
+            return None;
+        }
+        let mut zip = match self.policy {
+            ZipPolicy::Latest => self.zip_latest(),
+            ZipPolicy::StrictPts => self.zip_strict(),
+            ZipPolicy::Nearest => self.zip_nearest(),
+        }?;
+        // Paced pads join with their latest buffer up to the first matched pad's PTS
+        let reference = matched.first().and_then(|&idx| pts(&zip[idx]));
+        for idx in 0..self.buffers.len() {
+            if self.paced[idx] {
+                self.release(idx, reference);
+                zip[idx] = self.sticky_buffer(idx);
+            }
+        }
+        Some(zip)
+    }
+
+    // Makes the queued buffers of a paced pad up to `reference` its latest one.
+    // Untimestamped buffers are released right away.
+    fn release(&mut self, idx: usize, reference: Option<u64>) {
+        while let Some(buffer) = self.buffers[idx].front() {
+            match (pts(buffer), reference) {
+                (Some(t), Some(reference)) if t > reference => break,
+                _ => self.latest[idx] = self.buffers[idx].pop_front(),
             }
-            Some(zip)
-        } else {
-            None
         }
     }
+
+    // Newest buffer of every pad, older ones are discarded
+    fn zip_latest(&mut self) -> Option<Vec<Buffer>> {
+        let mut zip = Vec::new();
+        for idx in 0..self.buffers.len() {
+            if self.sticky[idx] {
+                zip.push(self.sticky_buffer(idx));
+                continue;
+            }
+            while self.buffers[idx].len() > 1 {
+                self.drop_front(idx, "superseded");
+            }
+            zip.push(self.buffers[idx].pop_front().unwrap());
+        }
+        Some(zip)
+    }
+
+    // Oldest buffers of every pad, once they all fall within the tolerance
+    fn zip_strict(&mut self) -> Option<Vec<Buffer>> {
+        let matched = self.matched();
+        loop {
+            let heads: Option<Vec<u64>> = matched
+                .iter()
+                .map(|&idx| self.buffers[idx].front().and_then(pts))
+                .collect();
+            let heads = match heads {
+                Some(heads) => heads,
+                // Untimestamped buffers can only be matched in arrival order
+                None => return Some(self.pop_fronts()),
+            };
+
+            // Heads older than the newest head by more than the tolerance can't be matched anymore
+            let newest = *heads.iter().max().unwrap();
+            let stale: Vec<usize> = (0..heads.len())
+                .filter(|&i| newest - heads[i] > self.tolerance)
+                .map(|i| matched[i])
+                .collect();
+            if stale.is_empty() {
+                return Some(self.pop_fronts());
+            }
+            for idx in stale {
+                self.drop_front(idx, "no match within tolerance");
+            }
+            if matched.iter().any(|&idx| self.buffers[idx].is_empty()) {
+                return None;
+            }
+        }
+    }
+
+    // Oldest buffer of the first pad with the closest buffer of every other pad.
+    // Matched buffers of the other pads are kept, so slower streams can be reused.
+    fn zip_nearest(&mut self) -> Option<Vec<Buffer>> {
+        let reference = match self.buffers[0].front().and_then(pts) {
+            Some(reference) => reference,
+            None => return self.zip_latest(),
+        };
+        let waiting_full = self.buffers[0].len() >= self.max_queue.max(1);
+
+        let mut nearest = vec![0; self.buffers.len()];
+        for idx in self.matched().into_iter().skip(1) {
+            // A closer buffer may still arrive while this pad lags behind the reference
+            let newest = self.buffers[idx].back().and_then(pts);
+            if !waiting_full && newest.map_or(false, |newest| newest < reference) {
+                return None;
+            }
+            nearest[idx] = self.buffers[idx]
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, buffer)| pts(buffer).map_or(u64::MAX, |t| distance(t, reference)))
+                .map(|(pos, _)| pos)
+                .unwrap();
+        }
+
+        let mut zip = vec![self.buffers[0].pop_front().unwrap()];
+        for idx in 1..self.buffers.len() {
+            if self.sticky[idx] {
+                zip.push(self.sticky_buffer(idx));
+                continue;
+            }
+            for _ in 0..nearest[idx] {
+                self.drop_front(idx, "superseded by a nearer buffer");
+            }
+            zip.push(self.buffers[idx].front().unwrap().clone());
+        }
+        Some(zip)
+    }
+
+    fn pop_fronts(&mut self) -> Vec<Buffer> {
+        (0..self.buffers.len())
+            .map(|idx| {
+                if self.sticky[idx] {
+                    self.sticky_buffer(idx)
+                } else {
+                    self.buffers[idx].pop_front().unwrap()
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn zipper(size: usize, policy: ZipPolicy) -> Zipper {
+        gst::init().unwrap();
+        let cat = gst::DebugCategory::new("zipper-test", gst::DebugColorFlags::empty(), None);
+        let mut zipper = Zipper::with_size(size, cat);
+        zipper.policy = policy;
+        zipper
+    }
+
+    fn buffer(ms: u64) -> Buffer {
+        let mut buffer = Buffer::new();
+        buffer
+            .get_mut()
+            .unwrap()
+            .set_pts(gst::ClockTime::from_mseconds(ms));
+        buffer
+    }
+
+    fn zipped_pts(zip: Vec<Buffer>) -> Vec<u64> {
+        zip.iter()
+            .map(|b| b.get_pts().mseconds().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn latest_keeps_newest() {
+        let mut zipper = zipper(2, ZipPolicy::Latest);
+        zipper.push(buffer(0), 0);
+        zipper.push(buffer(40), 0);
+        assert!(zipper.try_zip().is_none());
+        zipper.push(buffer(0), 1);
+        assert_eq!(zipped_pts(zipper.try_zip().unwrap()), vec![40, 0]);
+        assert_eq!(zipper.dropped(), 1);
+    }
+
+    #[test]
+    fn strict_pts_drops_unmatched() {
+        let mut zipper = zipper(2, ZipPolicy::StrictPts);
+        zipper.push(buffer(0), 0);
+        zipper.push(buffer(40), 0);
+        zipper.push(buffer(45), 1);
+        assert_eq!(zipped_pts(zipper.try_zip().unwrap()), vec![40, 45]);
+        assert_eq!(zipper.dropped(), 1);
+        assert!(zipper.try_zip().is_none());
+    }
+
+    #[test]
+    fn nearest_reuses_slower_pad() {
+        let mut zipper = zipper(2, ZipPolicy::Nearest);
+        zipper.push(buffer(0), 1);
+        zipper.push(buffer(100), 1);
+        zipper.push(buffer(30), 0);
+        zipper.push(buffer(60), 0);
+        assert_eq!(zipped_pts(zipper.try_zip().unwrap()), vec![30, 0]);
+        assert_eq!(zipped_pts(zipper.try_zip().unwrap()), vec![60, 100]);
+        assert_eq!(zipper.dropped(), 1);
+    }
+
+    // Zips everything a live push completes, as the chain function does
+    fn push_live(zipper: &mut Zipper, buffer: Buffer, idx: usize) -> Vec<Vec<u64>> {
+        zipper.push(buffer, idx);
+        let mut zips = Vec::new();
+        while let Some(zip) = zipper.try_zip() {
+            zips.push(zipped_pts(zip));
+        }
+        zips
+    }
+
+    #[test]
+    fn slow_pad_drains_backlog() {
+        let mut zipper = zipper(2, ZipPolicy::Nearest);
+        for ms in &[0, 33, 66] {
+            assert!(push_live(&mut zipper, buffer(*ms), 0).is_empty());
+        }
+        assert_eq!(
+            push_live(&mut zipper, buffer(50), 1),
+            vec![vec![0, 50], vec![33, 50]]
+        );
+        assert_eq!(push_live(&mut zipper, buffer(100), 1), vec![vec![66, 50]]);
+        assert_eq!(push_live(&mut zipper, buffer(100), 0), vec![vec![100, 100]]);
+        assert!(zipper.buffers[0].is_empty());
+        assert_eq!(zipper.dropped(), 1);
+    }
+
+    #[test]
+    fn sticky_pad_joins_every_zip() {
+        for policy in &[ZipPolicy::Latest, ZipPolicy::StrictPts, ZipPolicy::Nearest] {
+            let mut zipper = zipper(2, *policy);
+            zipper.set_sticky(1);
+            zipper.push(buffer(0), 0);
+            let zip = zipper.try_zip().unwrap();
+            assert_eq!(zip[1].get_size(), 0);
+            assert!(zip[1].get_pts().is_none());
+            zipper.push(buffer(500), 1);
+            zipper.push(buffer(40), 0);
+            assert_eq!(zipped_pts(zipper.try_zip().unwrap()), vec![40, 500]);
+            zipper.push(buffer(80), 0);
+            assert_eq!(zipped_pts(zipper.try_zip().unwrap()), vec![80, 500]);
+            assert_eq!(zipper.dropped(), 0);
+        }
+    }
+
+    #[test]
+    fn paced_pad_follows_pts() {
+        let mut zipper = zipper(2, ZipPolicy::Latest);
+        zipper.set_sticky(1);
+        zipper.set_paced(1, true);
+        zipper.max_queue = 2;
+        zipper.push(buffer(0), 1);
+        zipper.push(buffer(100), 1);
+        assert!(zipper.is_backlogged(1));
+        zipper.push(buffer(0), 0);
+        assert_eq!(zipped_pts(zipper.try_zip().unwrap()), vec![0, 0]);
+        assert!(!zipper.is_backlogged(1));
+        zipper.push(buffer(50), 0);
+        assert_eq!(zipped_pts(zipper.try_zip().unwrap()), vec![50, 0]);
+        zipper.push(buffer(100), 0);
+        assert_eq!(zipped_pts(zipper.try_zip().unwrap()), vec![100, 100]);
+        assert_eq!(zipper.dropped(), 0);
+    }
+
+    #[test]
+    fn max_queue_bounds_pads() {
+        let mut zipper = zipper(2, ZipPolicy::StrictPts);
+        zipper.max_queue = 2;
+        for ms in 0..4 {
+            zipper.push(buffer(ms * 40), 0);
+        }
+        assert_eq!(zipper.dropped(), 2);
+        zipper.push(buffer(80), 1);
+        assert_eq!(zipped_pts(zipper.try_zip().unwrap()), vec![80, 80]);
+    }
 }
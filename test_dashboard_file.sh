@@ -12,5 +12,5 @@ export RUST_BACKTRACE=1
     video/x-raw,format=RGB,width=640,height=192 ! \
     tee name=t ! \
     queue2 ! videoconvert ! comp. \
-    t. ! queue2 ! monodepth ! videoconvert ! comp. \
-    t. ! queue2 ! semseg ! videoconvert ! comp. 
+    t. ! queue2 ! monodepth batch-size=4 ! videoconvert ! comp. \
+    t. ! queue2 ! semseg batch-size=4 ! videoconvert ! comp. 
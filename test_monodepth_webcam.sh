@@ -5,7 +5,5 @@ export GST_PLUGIN_PATH=${SIMBOTIC_TORCH}/target/release:${LIBTORCH}/lib
 export RUST_BACKTRACE=1
 
 ./target/release/simbotic-stream \
-    v4l2src ! \
-    aspectratiocrop aspect-ratio=10/3 ! videoscale ! videoconvert ! \
-    video/x-raw,format=RGB,width=640,height=192 ! \
-    monodepth ! videoconvert ! xvimagesink sync=false
+    v4l2src ! videoconvert ! video/x-raw,format=RGB ! \
+    monodepth resize-mode=letterbox async=true ! videoconvert ! xvimagesink sync=false
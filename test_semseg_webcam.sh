@@ -5,8 +5,6 @@ export GST_PLUGIN_PATH=${SIMBOTIC_TORCH}/target/release:${LIBTORCH}/lib
 export RUST_BACKTRACE=1
 
 ./target/release/simbotic-stream \
-    v4l2src ! \
-    aspectratiocrop aspect-ratio=10/3 ! videoscale ! videoconvert ! \
-    video/x-raw,format=RGB,width=640,height=192 ! \
-    semseg ! videoconvert ! fpsdisplaysink sync=false
+    v4l2src ! videoconvert ! video/x-raw,format=RGB ! \
+    semseg resize-mode=letterbox async=true ! videoconvert ! fpsdisplaysink sync=false
 